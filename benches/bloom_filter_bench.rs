@@ -0,0 +1,55 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use onechain::core::hasher::{BlockHasher, Digest, TruncatedSha256Hasher};
+use onechain::storage::blocked_bloom_filter::{BlockedBloomFilter, BlockedBloomFilterOps};
+use onechain::storage::bloom_filter::{BloomFilter, BloomFilterOps};
+
+const KEYS: u32 = 100_000;
+const BITS_PER_KEY: usize = 10;
+
+fn keys(range: std::ops::Range<u32>) -> Vec<Digest> {
+    range.map(|i| TruncatedSha256Hasher.digest(&i.to_be_bytes())).collect()
+}
+
+// both filters get the same bits, large enough not to fit in L1
+fn bench_add(c: &mut Criterion) {
+    let added = keys(0..KEYS);
+    c.bench_function("bloom filter add (7 probes)", |b| {
+        b.iter(|| {
+            let mut bf = BloomFilter::with_capacity(KEYS as usize, BITS_PER_KEY);
+            for key in added.iter() {
+                bf.add(key);
+            }
+            black_box(bf)
+        })
+    });
+    c.bench_function("blocked bloom filter add (one cache line)", |b| {
+        b.iter(|| {
+            let mut bf = BlockedBloomFilter::with_capacity(KEYS as usize, BITS_PER_KEY);
+            for key in added.iter() {
+                bf.add(key);
+            }
+            black_box(bf)
+        })
+    });
+}
+
+// half of the probes were added, half were not
+fn bench_may_contain(c: &mut Criterion) {
+    let added = keys(0..KEYS);
+    let probes = keys(KEYS / 2..KEYS / 2 + KEYS);
+    let mut classic = BloomFilter::with_capacity(KEYS as usize, BITS_PER_KEY);
+    let mut blocked = BlockedBloomFilter::with_capacity(KEYS as usize, BITS_PER_KEY);
+    for key in added.iter() {
+        classic.add(key);
+        blocked.add(key);
+    }
+    c.bench_function("bloom filter may_contain (7 probes)", |b| {
+        b.iter(|| probes.iter().filter(|k| classic.may_contain(black_box(k))).count())
+    });
+    c.bench_function("blocked bloom filter may_contain (one cache line)", |b| {
+        b.iter(|| probes.iter().filter(|k| blocked.may_contain(black_box(k))).count())
+    });
+}
+
+criterion_group!(benches, bench_add, bench_may_contain);
+criterion_main!(benches);
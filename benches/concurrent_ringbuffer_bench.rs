@@ -0,0 +1,85 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use onechain::core::block::Block;
+use onechain::storage::concurrent_ring_buffer::{ConcurrentRingBuffer, ConcurrentRingBufferOps};
+use onechain::storage::ring_buffer::{BlockRingBuffer, BlockRingBufferOps};
+use std::sync::Mutex;
+use std::thread;
+
+const PRODUCERS: usize = 4;
+const BLOCKS_PER_PRODUCER: usize = 10_000;
+
+fn phone(producer: usize, i: usize) -> [u8; 10] {
+    let mut phone = [producer as u8; 10];
+    phone[6..].copy_from_slice(&(i as u32).to_be_bytes());
+    phone
+}
+
+fn bench_single_thread_push_pop(c: &mut Criterion) {
+    let ring_buffer = ConcurrentRingBuffer::new();
+    c.bench_function("concurrent ringbuffer push/pop single thread", |b| {
+        b.iter(|| {
+            for i in 0..100 {
+                ring_buffer.push(Block::new([(i % 256) as u8; 10], false));
+            }
+            while let Some(block) = ring_buffer.pop() {
+                black_box(block);
+            }
+        })
+    });
+}
+
+fn bench_producers_one_consumer(c: &mut Criterion) {
+    c.bench_function("concurrent ringbuffer 4 producers 1 consumer", |b| {
+        b.iter(|| {
+            let ring_buffer = ConcurrentRingBuffer::with_capacity(1024);
+            thread::scope(|scope| {
+                for producer in 0..PRODUCERS {
+                    let ring_buffer = &ring_buffer;
+                    scope.spawn(move || {
+                        for i in 0..BLOCKS_PER_PRODUCER {
+                            ring_buffer.push(Block::new(phone(producer, i), false));
+                        }
+                    });
+                }
+                let mut popped = 0;
+                while popped < PRODUCERS * BLOCKS_PER_PRODUCER {
+                    match ring_buffer.pop() {
+                        Some(block) => {
+                            black_box(block);
+                            popped += 1;
+                        },
+                        None => std::hint::spin_loop(),
+                    }
+                }
+            });
+        })
+    });
+}
+
+// the same workload through the single threaded buffer behind a lock, as a baseline
+fn bench_locked_ringbuffer(c: &mut Criterion) {
+    c.bench_function("locked ringbuffer 4 producers", |b| {
+        b.iter(|| {
+            let ring_buffer = Mutex::new(BlockRingBuffer::new());
+            thread::scope(|scope| {
+                for producer in 0..PRODUCERS {
+                    let ring_buffer = &ring_buffer;
+                    scope.spawn(move || {
+                        for i in 0..BLOCKS_PER_PRODUCER {
+                            ring_buffer.lock().unwrap().add(phone(producer, i));
+                        }
+                    });
+                }
+            });
+            black_box(ring_buffer.into_inner().unwrap().length());
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_single_thread_push_pop,
+    bench_producers_one_consumer,
+    bench_locked_ringbuffer
+);
+criterion_main!(benches);
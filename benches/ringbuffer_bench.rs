@@ -47,6 +47,18 @@ fn bench_cache_alignment(c: &mut Criterion) {
     });
 }
 
+fn bench_add(c: &mut Criterion) {
+    let mut ring_buffer = BlockRingBuffer::new();
+    let mut i = 0u64;
+
+    c.bench_function("ringbuffer add", |b| {
+        b.iter(|| {
+            i += 1;
+            ring_buffer.add(black_box([(i % 256) as u8; 10]));
+        })
+    });
+}
+
 // Define the benchmark group
-criterion_group!(benches, bench_ringbuffer_l1_cache_access, bench_cache_alignment);
+criterion_group!(benches, bench_ringbuffer_l1_cache_access, bench_cache_alignment, bench_add);
 criterion_main!(benches);
\ No newline at end of file
@@ -0,0 +1,160 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::block::Block;
+use crate::core::hasher::Digest;
+
+/// 16 bit lanes in a `MultisetHash`.
+pub const LANES: usize = 1024;
+/// lanes are packed four to a word and added without carries crossing lane borders
+const WORDS: usize = LANES / 4;
+const HIGH_BITS: u64 = 0x8000_8000_8000_8000;
+
+const DOMAIN: &str = "onechain 2025 multiset hash of blocks";
+
+/// Incremental hash of a multiset of blocks (a lattice hash, as in LtHash).
+/// Every element is expanded with BLAKE3 into `LANES` 16 bit words and added lane wise,
+/// so the result ignores order, an element added twice counts twice and removing one is
+/// the exact inverse of adding it. Layers holding the same blocks hold equal hashes,
+/// however the blocks were batched on the way.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MultisetHash {
+    words: Vec<u64>,
+}
+
+impl MultisetHash {
+    pub fn new() -> Self {
+        MultisetHash { words: vec![0; WORDS] }
+    }
+
+    pub fn insert(&mut self, block: &Block) {
+        let lanes = expand(block);
+        for (word, add) in self.words.iter_mut().zip(lanes) {
+            *word = add_lanes(*word, add);
+        }
+    }
+
+    pub fn remove(&mut self, block: &Block) {
+        let lanes = expand(block);
+        for (word, sub) in self.words.iter_mut().zip(lanes) {
+            *word = add_lanes(*word, negate_lanes(sub));
+        }
+    }
+
+    /// Adds every element of `other`.
+    pub fn combine(&mut self, other: &MultisetHash) {
+        for (word, add) in self.words.iter_mut().zip(&other.words) {
+            *word = add_lanes(*word, *add);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|w| *w == 0)
+    }
+
+    /// 32 byte commitment to the multiset, for headers and logs.
+    pub fn digest(&self) -> Digest {
+        let bytes: Vec<u8> = self.words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        Digest::new(blake3::hash(&bytes).as_bytes())
+    }
+}
+
+/// Lane wise wrapping add of four 16 bit lanes.
+fn add_lanes(a: u64, b: u64) -> u64 {
+    ((a & !HIGH_BITS) + (b & !HIGH_BITS)) ^ ((a ^ b) & HIGH_BITS)
+}
+
+/// Lane wise two's complement, `add_lanes(a, negate_lanes(a)) == 0`.
+fn negate_lanes(a: u64) -> u64 {
+    add_lanes(!a, 0x0001_0001_0001_0001)
+}
+
+impl Default for MultisetHash {
+    fn default() -> Self {
+        MultisetHash::new()
+    }
+}
+
+impl fmt::Debug for MultisetHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MultisetHash({:?})", self.digest())
+    }
+}
+
+impl FromIterator<Block> for MultisetHash {
+    fn from_iter<I: IntoIterator<Item = Block>>(blocks: I) -> Self {
+        let mut hash = MultisetHash::new();
+        for block in blocks {
+            hash.insert(&block);
+        }
+        hash
+    }
+}
+
+/// Lanes of one block. Commits to everything that survives a flush: key digest, sequence
+/// number, tombstone and payload. The sequence number keeps two writes of the same record apart.
+fn expand(block: &Block) -> [u64; WORDS] {
+    let mut hasher = blake3::Hasher::new_derive_key(DOMAIN);
+    hasher.update(&[block.data.len() as u8]);
+    hasher.update(block.data.as_bytes());
+    hasher.update(&block.seq.to_le_bytes());
+    hasher.update(&[block.disabled as u8]);
+    if let Some(payload) = block.payload.as_ref() {
+        hasher.update(&(payload.key.len() as u64).to_le_bytes());
+        hasher.update(&payload.key);
+        hasher.update(&payload.value);
+    }
+    let mut bytes = [0u8; LANES * 2];
+    hasher.finalize_xof().fill(&mut bytes);
+    let mut words = [0u64; WORDS];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(8)) {
+        *word = u64::from_le_bytes(chunk.try_into().unwrap());
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(i: u8, seq: u64) -> Block {
+        let mut block = Block::new([i; 10], false);
+        block.seq = seq;
+        block
+    }
+
+    #[test]
+    fn test_lanes_wrap_independently() {
+        let a = 0xFFFF_0001_8000_7FFF;
+        let b = 0x0001_FFFF_8000_0001;
+        assert_eq!(add_lanes(a, b), 0x0000_0000_0000_8000);
+        assert_eq!(add_lanes(a, negate_lanes(a)), 0);
+        assert_eq!(negate_lanes(0), 0);
+    }
+
+    #[test]
+    fn test_multiset_hash_is_order_free_but_counts_duplicates() {
+        let forward: MultisetHash = (0..50u8).map(|i| block(i, i as u64)).collect();
+        let backward: MultisetHash = (0..50u8).rev().map(|i| block(i, i as u64)).collect();
+        assert_eq!(forward, backward);
+
+        // unlike xor, adding an element twice does not cancel it out
+        let mut twice = MultisetHash::new();
+        twice.insert(&block(1, 1));
+        twice.insert(&block(1, 1));
+        assert!(!twice.is_empty());
+        assert_ne!(twice, MultisetHash::new());
+        twice.remove(&block(1, 1));
+        twice.remove(&block(1, 1));
+        assert!(twice.is_empty());
+
+        // combining batches equals hashing them together
+        let mut halves: MultisetHash = (0..25u8).map(|i| block(i, i as u64)).collect();
+        halves.combine(&(25..50u8).map(|i| block(i, i as u64)).collect());
+        assert_eq!(halves, forward);
+        // a rewrite of the same key is a different element
+        assert_ne!(MultisetHash::from_iter([block(1, 1)]), MultisetHash::from_iter([block(1, 2)]));
+        assert_ne!(MultisetHash::from_iter([block(1, 1)]).digest(), forward.digest());
+    }
+}
@@ -1,29 +1,69 @@
-use sha2::{Digest, Sha256};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use crate::core::hasher::{BlockHasher, Digest, TruncatedSha256Hasher};
 
 /// Default hasher for the linked list.
-pub fn sha_hash(data: &[u8; 10]) -> [u8; 16] {
+pub fn sha_hash(data: &[u8]) -> [u8; 16] {
     let mut sha = Sha256::new();
     sha.update(data);
     let full_hash = sha.finalize();
     full_hash[..16].try_into().expect("Failed to convert hash to fixed size array")
 }
-#[derive(Debug, Clone, Copy)]
+/// Original key and value of a record, `Block::data` only holds the digest of the key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Payload {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[repr(align(64))] // align to 64 bytes for cache line alignment
 pub struct Block {
-    pub data: [u8; 16],
+    /// digest of the key, blocks are ordered and looked up by it
+    pub data: Digest,
     pub timestamp: i64,
+    /// global write order, assigned once the block reaches the ring buffer
+    pub seq: u64,
     pub disabled: bool,
     pub next: Option<usize>,
+    /// boxed so key and value bytes of any length stay out of the block
+    pub payload: Option<Box<Payload>>,
 }
 
+// a `MAX_DIGEST_SIZE` digest alone takes half a cache line, a block spans two of them
+const _: () = assert!(std::mem::size_of::<Block>() <= 2 * 64);
+
 impl Block {
+    /// Phone number record, the number is the key and there is no value.
     pub fn new(phone_number: [u8; 10], is_disabled: bool) -> Block {
-        let hash: [u8; 16] = sha_hash(&phone_number);
+        Block::with_payload(phone_number.to_vec(), Vec::new(), is_disabled)
+    }
+
+    /// Arbitrary record keyed by the digest of `key`, a tombstone keeps the key only.
+    pub fn with_payload(key: Vec<u8>, value: Vec<u8>, is_disabled: bool) -> Block {
+        Block::with_hasher(&TruncatedSha256Hasher, key, value, is_disabled)
+    }
+
+    /// Record keyed by the digest `hasher` computes for `key`.
+    pub fn with_hasher(
+        hasher: &dyn BlockHasher, key: Vec<u8>, value: Vec<u8>, is_disabled: bool,
+    ) -> Block {
         Block {
-            data: hash,
+            data: hasher.digest(&key),
             next: None,
             timestamp: chrono::Utc::now().timestamp_millis(),
+            seq: 0,
             disabled: is_disabled,
+            payload: Some(Box::new(Payload { key, value })),
         }
     }
+
+    pub fn key(&self) -> Option<&[u8]> {
+        self.payload.as_ref().map(|p| p.key.as_slice())
+    }
+
+    pub fn value(&self) -> Option<&[u8]> {
+        self.payload.as_ref().map(|p| p.value.as_slice())
+    }
 }
@@ -0,0 +1,293 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::core::block::Block;
+use crate::core::hasher::{BlockHasher, Digest};
+use crate::core::merkle::{leaf_hash, merkle_root, MerkleProof, MerkleTree, MerkleTreeOps};
+
+/// The hashed part of a chain block, linking it to its parent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    /// genesis is at 0
+    pub height: u64,
+    /// hash of the parent header, all zeros for genesis
+    pub prev_hash: Digest,
+    /// millis, never before the parent's
+    pub timestamp: i64,
+    /// merkle root over the leaf hashes of the records in the body
+    pub merkle_root: Digest,
+}
+
+impl BlockHeader {
+    pub fn hash(&self, hasher: &dyn BlockHasher) -> Digest {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.height.to_le_bytes());
+        bytes.extend_from_slice(self.prev_hash.as_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes.extend_from_slice(self.merkle_root.as_bytes());
+        hasher.digest(&bytes)
+    }
+}
+
+/// Header plus body, the records committed to by `header.merkle_root`.
+/// Records are storage `Block`s, `Block::next` and `Block::seq` are local and not committed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainBlock {
+    pub header: BlockHeader,
+    pub records: Vec<Block>,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ChainError {
+    #[error("block at height {height} does not extend the tip at height {tip_height}")]
+    Fork { height: u64, tip_height: u64 },
+    #[error("block at height {height} no longer hashes to the link recorded for it")]
+    BrokenLink { height: u64 },
+    #[error("block at height {height} has a merkle root that does not match its records")]
+    MerkleRootMismatch { height: u64 },
+    #[error("block at height {height} is older than its parent")]
+    TimestampRegression { height: u64 },
+    #[error("genesis block does not match the chain's hasher")]
+    BadGenesis,
+}
+
+/// Append only, hash linked sequence of `ChainBlock`s starting at a fixed genesis block.
+/// Every digest, from record leaves to header hashes, comes from the chain's `BlockHasher`.
+pub struct Chain {
+    hasher: Arc<dyn BlockHasher>,
+    blocks: Vec<ChainBlock>,
+    /// hash of every header, index is the height
+    hashes: Vec<Digest>,
+}
+
+pub trait ChainOps: Sized {
+    /// Chain holding only the genesis block, identical for every chain using the same hasher.
+    fn new(hasher: Arc<dyn BlockHasher>) -> Self;
+    fn genesis(hasher: &dyn BlockHasher) -> ChainBlock;
+    fn tip(&self) -> &ChainBlock;
+    fn tip_hash(&self) -> Digest;
+    fn height(&self) -> u64;
+    fn get(&self, height: u64) -> Option<&ChainBlock>;
+    /// Builds the block that would extend the tip with `records`.
+    fn next_block(&self, records: Vec<Block>, timestamp: i64) -> ChainBlock;
+    /// Appends `block` if it extends the tip, returns its hash.
+    /// Blocks at a height other than tip + 1, or pointing at another parent, are forks and rejected.
+    fn append(&mut self, block: ChainBlock) -> std::result::Result<Digest, ChainError>;
+    /// Inclusion proof for record `index` of the block at `height` against that block's merkle root,
+    /// enough for a client holding only the header to check the record was committed.
+    fn prove(&self, height: u64, index: usize) -> Option<MerkleProof>;
+    /// Re-checks every link and merkle root from genesis to tip.
+    fn validate(&self) -> std::result::Result<(), ChainError>;
+}
+
+impl ChainOps for Chain {
+    fn new(hasher: Arc<dyn BlockHasher>) -> Chain {
+        let genesis = Chain::genesis(&*hasher);
+        let hash = genesis.header.hash(&*hasher);
+        Chain {
+            hasher,
+            blocks: vec![genesis],
+            hashes: vec![hash],
+        }
+    }
+
+    fn genesis(hasher: &dyn BlockHasher) -> ChainBlock {
+        ChainBlock {
+            header: BlockHeader {
+                height: 0,
+                prev_hash: Digest::new(&vec![0; hasher.width()]),
+                timestamp: 0,
+                merkle_root: merkle_root(hasher, &[]),
+            },
+            records: Vec::new(),
+        }
+    }
+
+    fn tip(&self) -> &ChainBlock {
+        self.blocks.last().unwrap()
+    }
+
+    fn tip_hash(&self) -> Digest {
+        *self.hashes.last().unwrap()
+    }
+
+    fn height(&self) -> u64 {
+        self.tip().header.height
+    }
+
+    fn get(&self, height: u64) -> Option<&ChainBlock> {
+        self.blocks.get(height as usize)
+    }
+
+    fn next_block(&self, records: Vec<Block>, timestamp: i64) -> ChainBlock {
+        ChainBlock {
+            header: BlockHeader {
+                height: self.height() + 1,
+                prev_hash: self.tip_hash(),
+                timestamp: timestamp.max(self.tip().header.timestamp),
+                merkle_root: self.records_root(&records),
+            },
+            records,
+        }
+    }
+
+    fn append(&mut self, block: ChainBlock) -> std::result::Result<Digest, ChainError> {
+        self.check(self.tip(), &self.tip_hash(), &block)?;
+        let hash = block.header.hash(&*self.hasher);
+        self.blocks.push(block);
+        self.hashes.push(hash);
+        Ok(hash)
+    }
+
+    fn prove(&self, height: u64, index: usize) -> Option<MerkleProof> {
+        let block = self.get(height)?;
+        MerkleTree::from_blocks(&*self.hasher, &block.records).prove(index)
+    }
+
+    fn validate(&self) -> std::result::Result<(), ChainError> {
+        let genesis = Chain::genesis(&*self.hasher);
+        if self.blocks[0].header != genesis.header || !self.blocks[0].records.is_empty() {
+            return Err(ChainError::BadGenesis);
+        }
+        for (height, block) in self.blocks.iter().enumerate() {
+            if block.header.hash(&*self.hasher) != self.hashes[height] {
+                return Err(ChainError::BrokenLink { height: height as u64 });
+            }
+            if height > 0 {
+                self.check(&self.blocks[height - 1], &self.hashes[height - 1], block)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Chain {
+    pub fn hasher(&self) -> &dyn BlockHasher {
+        &*self.hasher
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ChainBlock> {
+        self.blocks.iter()
+    }
+
+    fn records_root(&self, records: &[Block]) -> Digest {
+        let leaves: Vec<Digest> = records.iter().map(|r| leaf_hash(&*self.hasher, r)).collect();
+        merkle_root(&*self.hasher, &leaves)
+    }
+
+    /// Does `block` validly extend `parent`, whose header hashes to `parent_hash`.
+    fn check(
+        &self, parent: &ChainBlock, parent_hash: &Digest, block: &ChainBlock,
+    ) -> std::result::Result<(), ChainError> {
+        let header = &block.header;
+        if header.height != parent.header.height + 1 {
+            return Err(ChainError::Fork {
+                height: header.height,
+                tip_height: parent.header.height,
+            });
+        }
+        if header.prev_hash != *parent_hash {
+            // same height as the next block but built on another parent
+            return Err(ChainError::Fork {
+                height: header.height,
+                tip_height: parent.header.height,
+            });
+        }
+        if header.timestamp < parent.header.timestamp {
+            return Err(ChainError::TimestampRegression { height: header.height });
+        }
+        if header.merkle_root != self.records_root(&block.records) {
+            return Err(ChainError::MerkleRootMismatch { height: header.height });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::hasher::{Blake3Hasher, TruncatedSha256Hasher};
+
+    fn records(from: u8, count: u8) -> Vec<Block> {
+        (from..from + count).map(|i| Block::new([i; 10], false)).collect()
+    }
+
+    fn chain_of(length: u8) -> Chain {
+        let mut chain = Chain::new(Arc::new(TruncatedSha256Hasher));
+        for i in 0..length {
+            let block = chain.next_block(records(i * 3, 3), i as i64 * 1000);
+            chain.append(block).unwrap();
+        }
+        chain
+    }
+
+    #[test]
+    fn test_append_links_blocks() {
+        let chain = chain_of(5);
+        assert_eq!(chain.height(), 5);
+        for height in 1..=5 {
+            let block = chain.get(height).unwrap();
+            let parent = chain.get(height - 1).unwrap();
+            assert_eq!(block.header.prev_hash, parent.header.hash(chain.hasher()));
+            assert_eq!(block.records.len(), 3);
+        }
+        assert_eq!(chain.tip_hash(), chain.tip().header.hash(chain.hasher()));
+        assert_eq!(chain.validate(), Ok(()));
+        // a record proves against its header alone
+        let header = &chain.get(4).unwrap().header;
+        let record = &chain.get(4).unwrap().records[2];
+        let proof = chain.prove(4, 2).unwrap();
+        assert!(proof.verify(
+            chain.hasher(),
+            &leaf_hash(chain.hasher(), record),
+            &header.merkle_root
+        ));
+        assert!(chain.prove(4, 3).is_none());
+        // genesis is shared by every chain with the same hasher
+        assert_eq!(chain.get(0).unwrap().header, chain_of(0).tip().header);
+        let blake3 = Chain::new(Arc::new(Blake3Hasher));
+        assert_eq!(blake3.tip().header.prev_hash.len(), 32);
+        assert_ne!(blake3.tip_hash(), chain.get(0).unwrap().header.hash(chain.hasher()));
+    }
+
+    #[test]
+    fn test_rejects_blocks_not_extending_tip() {
+        let mut chain = chain_of(3);
+        // a sibling of the tip
+        let mut sibling = chain.get(3).unwrap().clone();
+        sibling.records = records(100, 1);
+        sibling.header.merkle_root = chain.records_root(&sibling.records);
+        assert_eq!(chain.append(sibling), Err(ChainError::Fork { height: 3, tip_height: 3 }));
+
+        // right height, built on an older block
+        let mut stale = chain.next_block(records(50, 2), 10_000);
+        stale.header.prev_hash = chain.get(2).unwrap().header.hash(chain.hasher());
+        assert_eq!(chain.append(stale), Err(ChainError::Fork { height: 4, tip_height: 3 }));
+
+        let mut tampered = chain.next_block(records(50, 2), 10_000);
+        tampered.records.pop();
+        assert_eq!(chain.append(tampered), Err(ChainError::MerkleRootMismatch { height: 4 }));
+
+        let mut early = chain.next_block(records(50, 2), 10_000);
+        early.header.timestamp = 0;
+        assert_eq!(chain.append(early), Err(ChainError::TimestampRegression { height: 4 }));
+
+        assert_eq!(chain.height(), 3);
+        let next = chain.next_block(records(50, 2), 10_000);
+        assert!(chain.append(next).is_ok());
+    }
+
+    #[test]
+    fn test_validate_detects_tampering() {
+        let mut chain = chain_of(4);
+        chain.blocks[2].records[0] = Block::new([0xEE; 10], false);
+        assert_eq!(chain.validate(), Err(ChainError::MerkleRootMismatch { height: 2 }));
+
+        let mut chain = chain_of(4);
+        chain.blocks[2].header.timestamp += 1;
+        // the stored hash no longer matches the rewritten header
+        assert_eq!(chain.validate(), Err(ChainError::BrokenLink { height: 2 }));
+    }
+}
@@ -0,0 +1,229 @@
+use serde::{Deserialize, Serialize};
+
+use crate::core::block::Block;
+use crate::core::hasher::{BlockHasher, Digest};
+use crate::core::merkle::{leaf_hash, MerkleProof, MerkleTree, MerkleTreeOps};
+
+/// Merkle tree over the latest record of every key, sorted by key digest.
+/// Tombstones stay in as leaves, so a revoked key proves as revoked rather than unknown,
+/// and the sort order lets two neighbouring leaves prove that nothing lies between them.
+pub struct SortedMerkleTree {
+    records: Vec<Block>,
+    tree: MerkleTree,
+}
+
+/// A leaf of a `SortedMerkleTree` together with its inclusion proof.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProvenRecord {
+    pub record: Block,
+    pub proof: MerkleProof,
+}
+
+/// Shows that a key has no live record under a `SortedMerkleTree` root.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ExclusionProof {
+    /// the key's latest record is a tombstone
+    Tombstoned(ProvenRecord),
+    /// the key sorts between two adjacent leaves, either one is None at the edges of the tree
+    Absent {
+        leaf_count: u64,
+        left: Option<ProvenRecord>,
+        right: Option<ProvenRecord>,
+    },
+}
+
+pub trait SortedMerkleTreeOps: Sized {
+    /// Tree over `records` in write order, a later record of a key replaces an earlier one.
+    fn new(hasher: &dyn BlockHasher, records: impl IntoIterator<Item = Block>) -> Self;
+    fn root(&self) -> Digest;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool;
+    /// Latest record committed for `key`, tombstones included.
+    fn get(&self, key: &Digest) -> Option<&Block>;
+    /// Proof that `key` is absent or tombstoned, None when it has a live record.
+    fn prove_exclusion(&self, key: &Digest) -> Option<ExclusionProof>;
+}
+
+impl SortedMerkleTreeOps for SortedMerkleTree {
+    fn new(hasher: &dyn BlockHasher, records: impl IntoIterator<Item = Block>) -> Self {
+        let mut records: Vec<Block> = records.into_iter().collect();
+        // stable, so the latest record of a key ends up last in its run
+        records.sort_by_key(|r| r.data);
+        let mut latest: Vec<Block> = Vec::with_capacity(records.len());
+        for record in records {
+            match latest.last_mut() {
+                Some(last) if last.data == record.data => *last = record,
+                _ => latest.push(record),
+            }
+        }
+        let tree = MerkleTree::from_blocks(hasher, &latest);
+        SortedMerkleTree { records: latest, tree }
+    }
+
+    fn root(&self) -> Digest {
+        self.tree.root()
+    }
+
+    fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    fn get(&self, key: &Digest) -> Option<&Block> {
+        let index = self.records.binary_search_by_key(key, |r| r.data).ok()?;
+        Some(&self.records[index])
+    }
+
+    fn prove_exclusion(&self, key: &Digest) -> Option<ExclusionProof> {
+        match self.records.binary_search_by_key(key, |r| r.data) {
+            Ok(index) if self.records[index].disabled => {
+                Some(ExclusionProof::Tombstoned(self.proven(index)))
+            },
+            Ok(_) => None,
+            Err(index) => Some(ExclusionProof::Absent {
+                leaf_count: self.len() as u64,
+                left: index.checked_sub(1).map(|i| self.proven(i)),
+                right: (index < self.len()).then(|| self.proven(index)),
+            }),
+        }
+    }
+}
+
+impl SortedMerkleTree {
+    pub fn records(&self) -> &[Block] {
+        &self.records
+    }
+
+    fn proven(&self, index: usize) -> ProvenRecord {
+        ProvenRecord {
+            record: self.records[index].clone(),
+            proof: self.tree.prove(index).unwrap(),
+        }
+    }
+}
+
+impl ProvenRecord {
+    fn verify(&self, hasher: &dyn BlockHasher, root: &Digest) -> bool {
+        self.proof.verify(hasher, &leaf_hash(hasher, &self.record), root)
+    }
+}
+
+impl ExclusionProof {
+    /// Checks that `key` has no live record in the tree with `root`.
+    pub fn verify(&self, hasher: &dyn BlockHasher, root: &Digest, key: &Digest) -> bool {
+        match self {
+            ExclusionProof::Tombstoned(proven) => {
+                proven.record.data == *key && proven.record.disabled && proven.verify(hasher, root)
+            },
+            ExclusionProof::Absent { leaf_count, left, right } => {
+                let count = *leaf_count;
+                let valid = |proven: &ProvenRecord| {
+                    proven.proof.leaf_count == count && proven.verify(hasher, root)
+                };
+                match (left, right) {
+                    (None, None) => {
+                        count == 0 && *root == MerkleTree::new(hasher, Vec::new()).root()
+                    },
+                    (Some(left), None) => {
+                        valid(left) && left.proof.index + 1 == count && left.record.data < *key
+                    },
+                    (None, Some(right)) => {
+                        valid(right) && right.proof.index == 0 && *key < right.record.data
+                    },
+                    (Some(left), Some(right)) => {
+                        valid(left)
+                            && valid(right)
+                            && left.proof.index + 1 == right.proof.index
+                            && left.record.data < *key
+                            && *key < right.record.data
+                    },
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::hasher::TruncatedSha256Hasher;
+
+    fn records() -> Vec<Block> {
+        let mut records: Vec<Block> = (0..20u8).map(|i| Block::new([i; 10], false)).collect();
+        // revoke two of them later on
+        records.push(Block::new([3; 10], true));
+        records.push(Block::new([11; 10], true));
+        records
+    }
+
+    fn key(i: u8) -> Digest {
+        TruncatedSha256Hasher.digest(&[i; 10])
+    }
+
+    #[test]
+    fn test_exclusion_of_absent_and_tombstoned_keys() {
+        let hasher = TruncatedSha256Hasher;
+        let tree = SortedMerkleTree::new(&hasher, records());
+        let root = tree.root();
+        assert_eq!(tree.len(), 20);
+        assert!(tree.get(&key(3)).unwrap().disabled);
+
+        for i in [3, 11] {
+            let proof = tree.prove_exclusion(&key(i)).unwrap();
+            assert!(matches!(proof, ExclusionProof::Tombstoned(_)));
+            assert!(proof.verify(&hasher, &root, &key(i)));
+            // a tombstone only speaks for its own key
+            assert!(!proof.verify(&hasher, &root, &key(4)));
+        }
+        assert!(tree.prove_exclusion(&key(4)).is_none());
+
+        // absent keys, including ones sorting before and after every leaf
+        let absent = (20..60u8).map(key).chain([Digest::from([0; 16]), Digest::from([0xFF; 16])]);
+        for missing in absent {
+            let proof = tree.prove_exclusion(&missing).unwrap();
+            assert!(proof.verify(&hasher, &root, &missing));
+            assert!(!proof.verify(&hasher, &root, &key(5)));
+        }
+
+        let empty = SortedMerkleTree::new(&hasher, Vec::new());
+        let proof = empty.prove_exclusion(&key(1)).unwrap();
+        assert!(proof.verify(&hasher, &empty.root(), &key(1)));
+        assert!(!proof.verify(&hasher, &root, &key(1)));
+    }
+
+    #[test]
+    fn test_exclusion_proof_cannot_hide_a_live_key() {
+        let hasher = TruncatedSha256Hasher;
+        let tree = SortedMerkleTree::new(&hasher, records());
+        let root = tree.root();
+        let live = tree.records().iter().position(|r| r.data == key(7)).unwrap();
+        let ExclusionProof::Absent { left, right, .. } =
+            tree.prove_exclusion(&Digest::from([0xFF; 16])).unwrap()
+        else {
+            panic!("no tombstone at the end");
+        };
+        assert!(left.is_some() && right.is_none());
+
+        // neighbours that are not adjacent skip over the live key
+        let skipping = ExclusionProof::Absent {
+            leaf_count: tree.len() as u64,
+            left: Some(tree.proven(live - 1)),
+            right: Some(tree.proven(live + 1)),
+        };
+        assert!(!skipping.verify(&hasher, &root, &key(7)));
+        // a live record passed off as a tombstone
+        let mut revoked = tree.proven(live);
+        revoked.record.disabled = true;
+        assert!(!ExclusionProof::Tombstoned(revoked).verify(&hasher, &root, &key(7)));
+        // claiming an edge the tree does not end at
+        let early_end = ExclusionProof::Absent {
+            leaf_count: tree.len() as u64,
+            left: Some(tree.proven(live)),
+            right: None,
+        };
+        assert!(!early_end.verify(&hasher, &root, &Digest::from([0xFF; 16])));
+    }
+}
@@ -0,0 +1,199 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use sha2::Digest as _;
+
+use crate::core::block::sha_hash;
+
+/// Widest digest any `BlockHasher` may produce.
+pub const MAX_DIGEST_SIZE: usize = 32;
+
+/// Digest of a block key, up to `MAX_DIGEST_SIZE` bytes wide.
+/// Stored inline and encoded at a fixed size so blocks and footers keep a fixed layout
+/// whatever hasher produced them. Digests order and compare by their bytes.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Digest {
+    bytes: [u8; MAX_DIGEST_SIZE],
+    len: u8,
+}
+
+impl Digest {
+    /// Panics when `bytes` is wider than `MAX_DIGEST_SIZE`.
+    pub fn new(bytes: &[u8]) -> Digest {
+        assert!(bytes.len() <= MAX_DIGEST_SIZE, "digest wider than {} bytes", MAX_DIGEST_SIZE);
+        let mut digest = Digest {
+            bytes: [0; MAX_DIGEST_SIZE],
+            len: bytes.len() as u8,
+        };
+        digest.bytes[..bytes.len()].copy_from_slice(bytes);
+        digest
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Byte wise xor, as wide as the wider of both. Used for cumulative hashes.
+    pub fn xor(&self, other: &Digest) -> Digest {
+        let mut out = Digest {
+            bytes: [0; MAX_DIGEST_SIZE],
+            len: self.len.max(other.len),
+        };
+        for (i, byte) in out.bytes.iter_mut().enumerate() {
+            *byte = self.bytes[i] ^ other.bytes[i];
+        }
+        out
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for Digest {
+    fn from(bytes: [u8; N]) -> Digest {
+        Digest::new(&bytes)
+    }
+}
+
+impl PartialEq for Digest {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for Digest {}
+
+impl<const N: usize> PartialEq<[u8; N]> for Digest {
+    fn eq(&self, other: &[u8; N]) -> bool {
+        self.as_bytes() == other
+    }
+}
+
+impl PartialOrd for Digest {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Digest {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl std::hash::Hash for Digest {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state)
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.as_bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Turns block keys into the digests blocks are ordered and looked up by.
+/// A chain picks its hasher once at construction, every layer then carries digests of its width.
+pub trait BlockHasher: Send + Sync {
+    /// Stable identifier recorded with the data, a store only reopens with the same hasher.
+    fn id(&self) -> u8;
+    /// Bytes in every digest this hasher produces, at most `MAX_DIGEST_SIZE`.
+    fn width(&self) -> usize;
+    fn digest(&self, data: &[u8]) -> Digest;
+}
+
+/// SHA-256 truncated to 16 bytes, the original digest of the chain and the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TruncatedSha256Hasher;
+
+/// Full 32 byte SHA-256.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha256Hasher;
+
+/// 32 byte BLAKE3.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blake3Hasher;
+
+impl BlockHasher for TruncatedSha256Hasher {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn width(&self) -> usize {
+        16
+    }
+
+    fn digest(&self, data: &[u8]) -> Digest {
+        Digest::from(sha_hash(data))
+    }
+}
+
+impl BlockHasher for Sha256Hasher {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn width(&self) -> usize {
+        32
+    }
+
+    fn digest(&self, data: &[u8]) -> Digest {
+        Digest::new(&sha2::Sha256::digest(data))
+    }
+}
+
+impl BlockHasher for Blake3Hasher {
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn width(&self) -> usize {
+        32
+    }
+
+    fn digest(&self, data: &[u8]) -> Digest {
+        Digest::new(blake3::hash(data).as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashers_produce_their_width() {
+        let hashers: [&dyn BlockHasher; 3] = [&TruncatedSha256Hasher, &Sha256Hasher, &Blake3Hasher];
+        for hasher in hashers {
+            let digest = hasher.digest(b"5551234567");
+            assert_eq!(digest.len(), hasher.width());
+            assert_eq!(digest, hasher.digest(b"5551234567"));
+            assert_ne!(digest, hasher.digest(b"5551234568"));
+        }
+        // the truncated variant is a prefix of the full one
+        let full = Sha256Hasher.digest(b"key");
+        assert_eq!(TruncatedSha256Hasher.digest(b"key").as_bytes(), &full.as_bytes()[..16]);
+        assert_ne!(full, Blake3Hasher.digest(b"key"));
+    }
+
+    #[test]
+    fn test_digest_orders_by_bytes() {
+        let short = Digest::from([1u8; 16]);
+        assert!(short < Digest::from([1u8; 32]));
+        assert!(Digest::from([2u8; 16]) > Digest::from([1u8; 32]));
+        assert_eq!(short, [1u8; 16]);
+        assert_eq!(short.xor(&short), Digest::from([0u8; 16]));
+        let encoded = bincode::serialize(&short).unwrap();
+        assert_eq!(encoded.len(), MAX_DIGEST_SIZE + 1);
+        assert_eq!(bincode::deserialize::<Digest>(&encoded).unwrap(), short);
+    }
+}
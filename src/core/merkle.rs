@@ -1,7 +1,227 @@
+use serde::{Deserialize, Serialize};
+
 use crate::core::block::Block;
+use crate::core::hasher::{BlockHasher, Digest};
+
+/// prefixes keep a leaf from ever hashing like an inner node
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+/// Binary Merkle tree over leaf digests, every level is kept so proofs can be cut from it.
 pub struct MerkleTree {
-    pub root: Block,
-    pub nodes: Vec<Block>,
-    pub size: usize,
-    pub capacity: usize,
+    /// `levels[0]` holds the leaves, the last level holds only the root
+    levels: Vec<Vec<Digest>>,
+    /// root of an empty tree, the zero digest of the hasher's width
+    empty_root: Digest,
+}
+
+/// Sibling digests from a leaf up to the root. Levels where the node was promoted
+/// without a sibling are skipped, `leaf_count` tells the verifier which ones those are.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub index: u64,
+    pub leaf_count: u64,
+    pub siblings: Vec<Digest>,
+}
+
+pub trait MerkleTreeOps: Sized {
+    fn new(hasher: &dyn BlockHasher, leaves: Vec<Digest>) -> Self;
+    /// Tree over the `leaf_hash` of every block, in order.
+    fn from_blocks(hasher: &dyn BlockHasher, blocks: &[Block]) -> Self;
+    fn root(&self) -> Digest;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool;
+    fn leaf(&self, index: usize) -> Option<Digest>;
+    /// Inclusion proof for the leaf at `index`, None when out of range.
+    fn prove(&self, index: usize) -> Option<MerkleProof>;
+}
+
+/// Leaf digest of a record, commits to its key digest, tombstone and payload
+/// but not to where it happens to sit in local storage.
+pub fn leaf_hash(hasher: &dyn BlockHasher, record: &Block) -> Digest {
+    let mut bytes = vec![LEAF_PREFIX];
+    bytes.extend_from_slice(record.data.as_bytes());
+    bytes.push(record.disabled as u8);
+    if let Some(payload) = record.payload.as_ref() {
+        bytes.extend_from_slice(&(payload.key.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&payload.key);
+        bytes.extend_from_slice(&payload.value);
+    }
+    hasher.digest(&bytes)
+}
+
+pub fn node_hash(hasher: &dyn BlockHasher, left: &Digest, right: &Digest) -> Digest {
+    let mut bytes = Vec::with_capacity(1 + left.len() + right.len());
+    bytes.push(NODE_PREFIX);
+    bytes.extend_from_slice(left.as_bytes());
+    bytes.extend_from_slice(right.as_bytes());
+    hasher.digest(&bytes)
+}
+
+/// Root over `leaves` in order. An odd node at the end of a level is promoted as is,
+/// zero leaves give the all zero digest of the hasher's width.
+pub fn merkle_root(hasher: &dyn BlockHasher, leaves: &[Digest]) -> Digest {
+    if leaves.is_empty() {
+        return Digest::new(&vec![0; hasher.width()]);
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(hasher, &level);
+    }
+    level[0]
+}
+
+fn next_level(hasher: &dyn BlockHasher, level: &[Digest]) -> Vec<Digest> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node_hash(hasher, left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+impl MerkleTreeOps for MerkleTree {
+    fn new(hasher: &dyn BlockHasher, leaves: Vec<Digest>) -> MerkleTree {
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let next = next_level(hasher, levels.last().unwrap());
+            levels.push(next);
+        }
+        MerkleTree {
+            levels,
+            empty_root: Digest::new(&vec![0; hasher.width()]),
+        }
+    }
+
+    fn from_blocks(hasher: &dyn BlockHasher, blocks: &[Block]) -> MerkleTree {
+        MerkleTree::new(hasher, blocks.iter().map(|b| leaf_hash(hasher, b)).collect())
+    }
+
+    fn root(&self) -> Digest {
+        self.levels.last().unwrap().first().copied().unwrap_or(self.empty_root)
+    }
+
+    fn len(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.levels[0].is_empty()
+    }
+
+    fn leaf(&self, index: usize) -> Option<Digest> {
+        self.levels[0].get(index).copied()
+    }
+
+    fn prove(&self, index: usize) -> Option<MerkleProof> {
+        if index >= self.len() {
+            return None;
+        }
+        let mut siblings = Vec::new();
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(position ^ 1) {
+                siblings.push(*sibling);
+            }
+            position /= 2;
+        }
+        Some(MerkleProof {
+            index: index as u64,
+            leaf_count: self.len() as u64,
+            siblings,
+        })
+    }
+}
+
+impl MerkleProof {
+    /// Recomputes the root from `leaf` and the siblings and compares it with `root`.
+    pub fn verify(&self, hasher: &dyn BlockHasher, leaf: &Digest, root: &Digest) -> bool {
+        if self.index >= self.leaf_count {
+            return false;
+        }
+        let mut siblings = self.siblings.iter();
+        let mut current = *leaf;
+        let mut position = self.index;
+        let mut width = self.leaf_count;
+        while width > 1 {
+            if position % 2 == 1 {
+                let Some(left) = siblings.next() else { return false };
+                current = node_hash(hasher, left, &current);
+            } else if position + 1 < width {
+                let Some(right) = siblings.next() else { return false };
+                current = node_hash(hasher, &current, right);
+            }
+            position /= 2;
+            width = width.div_ceil(2);
+        }
+        siblings.next().is_none() && current == *root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::hasher::{Blake3Hasher, TruncatedSha256Hasher};
+
+    fn leaves(hasher: &dyn BlockHasher, count: u8) -> Vec<Digest> {
+        (0..count).map(|i| hasher.digest(&[i; 10])).collect()
+    }
+
+    #[test]
+    fn test_every_leaf_proves_against_root() {
+        let hasher = TruncatedSha256Hasher;
+        for count in 1..=17 {
+            let leaves = leaves(&hasher, count);
+            let tree = MerkleTree::new(&hasher, leaves.clone());
+            assert_eq!(tree.root(), merkle_root(&hasher, &leaves));
+            for (i, leaf) in leaves.iter().enumerate() {
+                let proof = tree.prove(i).unwrap();
+                assert!(proof.siblings.len() <= 5);
+                assert!(proof.verify(&hasher, leaf, &tree.root()), "{} of {}", i, count);
+            }
+            assert!(tree.prove(count as usize).is_none());
+        }
+        let empty = MerkleTree::new(&hasher, Vec::new());
+        assert!(empty.is_empty());
+        assert_eq!(empty.root(), merkle_root(&hasher, &[]));
+        assert!(empty.prove(0).is_none());
+    }
+
+    #[test]
+    fn test_proof_rejects_wrong_leaf_position_or_root() {
+        let hasher = Blake3Hasher;
+        let blocks: Vec<Block> = (0..7).map(|i| Block::new([i; 10], i == 3)).collect();
+        let tree = MerkleTree::from_blocks(&hasher, &blocks);
+        let leaf = leaf_hash(&hasher, &blocks[5]);
+        let proof = tree.prove(5).unwrap();
+        assert!(proof.verify(&hasher, &leaf, &tree.root()));
+
+        // a leaf that was never recorded, or a recorded one under the wrong index
+        assert!(!proof.verify(
+            &hasher,
+            &leaf_hash(&hasher, &Block::new([9; 10], false)),
+            &tree.root()
+        ));
+        assert!(!proof.verify(&hasher, &tree.leaf(4).unwrap(), &tree.root()));
+        let mut moved = proof.clone();
+        moved.index = 4;
+        assert!(!moved.verify(&hasher, &leaf, &tree.root()));
+
+        // the root of another tree, a truncated or padded proof
+        let other = MerkleTree::from_blocks(&hasher, &blocks[..6]);
+        assert!(!proof.verify(&hasher, &leaf, &other.root()));
+        let mut short = proof.clone();
+        short.siblings.pop();
+        assert!(!short.verify(&hasher, &leaf, &tree.root()));
+        let mut long = proof.clone();
+        long.siblings.push(leaf);
+        assert!(!long.verify(&hasher, &leaf, &tree.root()));
+        // an inner node passed off as a leaf
+        let inner = node_hash(&hasher, &tree.leaf(4).unwrap(), &leaf);
+        let mut lifted = proof;
+        lifted.siblings.remove(0);
+        assert!(!lifted.verify(&hasher, &inner, &tree.root()));
+    }
 }
@@ -1,8 +1,14 @@
 // Module: core
+pub mod accumulator;
 pub mod block;
+pub mod chain;
+pub mod exclusion;
+pub mod hasher;
 pub mod merkle;
 pub mod mpt;
 pub mod skip_list;
+pub mod smt;
+pub mod trie;
 use crate::storage::ring_buffer::BlockRingBuffer;
 use block::Block;
 
@@ -22,11 +28,11 @@ pub fn quick_sort(ringbuffer_flush_candidate: &mut [Option<block::Block>]) {
 
 fn partition(ringbuffer_flush_candidate: &mut [Option<block::Block>], pivot_index: usize) -> usize {
     // move pivot to end
-    let pivot_value = ringbuffer_flush_candidate[pivot_index].unwrap().data;
+    let pivot_value = ringbuffer_flush_candidate[pivot_index].as_ref().unwrap().data;
     ringbuffer_flush_candidate.swap(pivot_index, ringbuffer_flush_candidate.len() - 1);
     let mut j = 0;
     for i in 0..ringbuffer_flush_candidate.len() - 1 {
-        if ringbuffer_flush_candidate[i].unwrap().data < pivot_value {
+        if ringbuffer_flush_candidate[i].as_ref().unwrap().data < pivot_value {
             ringbuffer_flush_candidate.swap(i, j);
             j += 1; // increment partition index
         }
@@ -38,7 +44,11 @@ fn partition(ringbuffer_flush_candidate: &mut [Option<block::Block>], pivot_inde
 
 /// selects
 fn pivot_selector(arr: &mut [Option<block::Block>], a: usize, b: usize, c: usize) -> usize {
-    let (val_a, val_b, val_c) = (arr[a].unwrap().data, arr[b].unwrap().data, arr[c].unwrap().data);
+    let (val_a, val_b, val_c) = (
+        arr[a].as_ref().unwrap().data,
+        arr[b].as_ref().unwrap().data,
+        arr[c].as_ref().unwrap().data,
+    );
     if (val_a > val_b) ^ (val_a > val_c) {
         return a;
     } else if (val_b > val_a) ^ (val_b > val_c) {
@@ -61,10 +71,12 @@ mod tests {
         for j in 0..100 {
             let data = data_values[index % data_values.len()]; // Repeat in order
             buffer.blocks[j] = Some(Block {
-                data: *data, // Copy the provided value
+                data: (*data).into(), // Copy the provided value
                 timestamp: 0,
+                seq: 0,
                 disabled: false,
                 next: None,
+                payload: None,
             });
 
             index += 1; // Move sequentially through the input values
@@ -74,8 +86,8 @@ mod tests {
     }
 
     /// Helper function to extract `data` values from the ring buffer after sorting
-    fn extract_data(buffer: &BlockRingBuffer) -> Vec<[u8; 16]> {
-        buffer.blocks.iter().filter_map(|b| b.map(|block| block.data)).collect()
+    fn extract_data(buffer: &BlockRingBuffer) -> Vec<hasher::Digest> {
+        buffer.blocks.iter().filter_map(|b| b.as_ref().map(|block| block.data)).collect()
     }
 
     /// Test sorting a reverse lexicographically sorted ring buffer
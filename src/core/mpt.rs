@@ -1 +1,517 @@
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
 
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::core::block::Block;
+use crate::core::hasher::{BlockHasher, Digest};
+use crate::core::trie::{common_prefix, to_nibbles, NodeStore, TrieNode};
+
+/// Authenticated key value state. Nodes are content addressed in a `NodeStore` and never
+/// rewritten, an update stores the new nodes along its path and moves the root.
+/// The shape depends only on the stored keys, so equal state always has an equal root.
+pub struct MerklePatriciaTrie<S: NodeStore> {
+    hasher: Arc<dyn BlockHasher>,
+    store: S,
+    root: Option<Digest>,
+}
+
+/// Encoded nodes from the root down the path of a key, as far as the path exists.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrieProof {
+    pub nodes: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TrieProofError {
+    #[error("proof node at depth {depth} does not hash to the digest its parent holds")]
+    HashMismatch { depth: usize },
+    #[error("proof node at depth {depth} is not a trie node")]
+    Undecodable { depth: usize },
+    #[error("proof ends before the path of the key does")]
+    Incomplete,
+    #[error("proof carries nodes past the end of the key's path")]
+    TrailingNodes,
+}
+
+pub trait MerklePatriciaTrieOps<S: NodeStore>: Sized {
+    /// Empty trie over `store`.
+    fn new(store: S, hasher: Arc<dyn BlockHasher>) -> Self;
+    /// Trie at a root produced earlier over the same store, `empty_root` opens an empty trie.
+    fn at(store: S, hasher: Arc<dyn BlockHasher>, root: Digest) -> Self;
+    /// Root committing to the whole state, the zero digest of the hasher's width when empty.
+    fn root(&self) -> Digest;
+    fn get(&self, key: &[u8]) -> std::result::Result<Option<Vec<u8>>, Error>;
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> std::result::Result<(), Error>;
+    /// Returns false when the key was not there.
+    fn remove(&mut self, key: &[u8]) -> std::result::Result<bool, Error>;
+    /// Applies the records of a chain block in order, tombstones remove their key.
+    /// Returns the state root after the block.
+    fn apply(&mut self, records: &[Block]) -> std::result::Result<Digest, Error>;
+    /// Proof of the value under `key`, or of its absence.
+    fn prove(&self, key: &[u8]) -> std::result::Result<TrieProof, Error>;
+}
+
+impl<S: NodeStore> MerklePatriciaTrieOps<S> for MerklePatriciaTrie<S> {
+    fn new(store: S, hasher: Arc<dyn BlockHasher>) -> Self {
+        MerklePatriciaTrie { hasher, store, root: None }
+    }
+
+    fn at(store: S, hasher: Arc<dyn BlockHasher>, root: Digest) -> Self {
+        let root = (root != empty_root(&*hasher)).then_some(root);
+        MerklePatriciaTrie { hasher, store, root }
+    }
+
+    fn root(&self) -> Digest {
+        self.root.unwrap_or_else(|| empty_root(&*self.hasher))
+    }
+
+    fn get(&self, key: &[u8]) -> std::result::Result<Option<Vec<u8>>, Error> {
+        let path = to_nibbles(key);
+        let mut path = path.as_slice();
+        let mut next = self.root;
+        while let Some(hash) = next {
+            match self.load(&hash)? {
+                TrieNode::Leaf { path: rest, value } => {
+                    return Ok((rest == path).then_some(value));
+                },
+                TrieNode::Extension { path: shared, child } => {
+                    if !path.starts_with(&shared) {
+                        return Ok(None);
+                    }
+                    path = &path[shared.len()..];
+                    next = Some(child);
+                },
+                TrieNode::Branch { children, value } => match path.split_first() {
+                    None => return Ok(value),
+                    Some((nibble, rest)) => {
+                        path = rest;
+                        next = children[*nibble as usize];
+                    },
+                },
+            }
+        }
+        Ok(None)
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> std::result::Result<(), Error> {
+        let root = self.insert_at(self.root, &to_nibbles(key), value.to_vec())?;
+        self.root = Some(root);
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &[u8]) -> std::result::Result<bool, Error> {
+        match self.remove_at(self.root, &to_nibbles(key))? {
+            None => Ok(false),
+            Some(root) => {
+                self.root = root;
+                Ok(true)
+            },
+        }
+    }
+
+    fn apply(&mut self, records: &[Block]) -> std::result::Result<Digest, Error> {
+        for record in records {
+            let payload = record.payload.as_ref().ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, "record without a key can not update state")
+            })?;
+            if record.disabled {
+                self.remove(&payload.key)?;
+            } else {
+                self.insert(&payload.key, &payload.value)?;
+            }
+        }
+        Ok(self.root())
+    }
+
+    fn prove(&self, key: &[u8]) -> std::result::Result<TrieProof, Error> {
+        let path = to_nibbles(key);
+        let mut path = path.as_slice();
+        let mut nodes = Vec::new();
+        let mut next = self.root;
+        while let Some(hash) = next {
+            let encoded = self.load_encoded(&hash)?;
+            next = match TrieNode::decode(&encoded)? {
+                TrieNode::Leaf { .. } => None,
+                TrieNode::Extension { path: shared, child } => {
+                    let follows = path.starts_with(&shared);
+                    path = &path[shared.len().min(path.len())..];
+                    follows.then_some(child)
+                },
+                TrieNode::Branch { children, .. } => match path.split_first() {
+                    None => None,
+                    Some((nibble, rest)) => {
+                        path = rest;
+                        children[*nibble as usize]
+                    },
+                },
+            };
+            nodes.push(encoded);
+        }
+        Ok(TrieProof { nodes })
+    }
+}
+
+impl<S: NodeStore> MerklePatriciaTrie<S> {
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    fn load_encoded(&self, hash: &Digest) -> std::result::Result<Vec<u8>, Error> {
+        self.store.load(hash)?.ok_or_else(|| {
+            Error::new(ErrorKind::NotFound, format!("trie node {:?} is missing", hash))
+        })
+    }
+
+    fn load(&self, hash: &Digest) -> std::result::Result<TrieNode, Error> {
+        TrieNode::decode(&self.load_encoded(hash)?)
+    }
+
+    fn save(&mut self, node: TrieNode) -> std::result::Result<Digest, Error> {
+        let encoded = node.encode();
+        let hash = self.hasher.digest(&encoded);
+        self.store.store(hash, encoded)?;
+        Ok(hash)
+    }
+
+    /// Stores `value` under `path` below `node`, returns the digest of the replacement node.
+    fn insert_at(
+        &mut self, node: Option<Digest>, path: &[u8], value: Vec<u8>,
+    ) -> std::result::Result<Digest, Error> {
+        let Some(hash) = node else {
+            return self.save(TrieNode::Leaf { path: path.to_vec(), value });
+        };
+        match self.load(&hash)? {
+            TrieNode::Leaf { path: existing, value: existing_value } => {
+                if existing == path {
+                    return self.save(TrieNode::Leaf { path: existing, value });
+                }
+                let common = common_prefix(&existing, path);
+                let mut children: Box<[Option<Digest>; 16]> = Default::default();
+                let mut branch_value = None;
+                for (rest, value) in [(&existing[..], existing_value), (path, value)] {
+                    match rest[common..].split_first() {
+                        None => branch_value = Some(value),
+                        Some((nibble, rest)) => {
+                            let leaf = TrieNode::Leaf { path: rest.to_vec(), value };
+                            children[*nibble as usize] = Some(self.save(leaf)?);
+                        },
+                    }
+                }
+                let branch = self.save(TrieNode::Branch { children, value: branch_value })?;
+                self.extend(&path[..common], branch)
+            },
+            TrieNode::Extension { path: shared, child } => {
+                let common = common_prefix(&shared, path);
+                if common == shared.len() {
+                    let child = self.insert_at(Some(child), &path[common..], value)?;
+                    return self.save(TrieNode::Extension { path: shared, child });
+                }
+                // the new key leaves the shared path, split it at a branch
+                let mut children: Box<[Option<Digest>; 16]> = Default::default();
+                children[shared[common] as usize] =
+                    Some(self.extend(&shared[common + 1..], child)?);
+                let mut branch_value = None;
+                match path[common..].split_first() {
+                    None => branch_value = Some(value),
+                    Some((nibble, rest)) => {
+                        let leaf = TrieNode::Leaf { path: rest.to_vec(), value };
+                        children[*nibble as usize] = Some(self.save(leaf)?);
+                    },
+                }
+                let branch = self.save(TrieNode::Branch { children, value: branch_value })?;
+                self.extend(&path[..common], branch)
+            },
+            TrieNode::Branch { mut children, value: branch_value } => match path.split_first() {
+                None => self.save(TrieNode::Branch { children, value: Some(value) }),
+                Some((nibble, rest)) => {
+                    let slot = &mut children[*nibble as usize];
+                    *slot = Some(self.insert_at(*slot, rest, value)?);
+                    self.save(TrieNode::Branch { children, value: branch_value })
+                },
+            },
+        }
+    }
+
+    /// Removes `path` below `node`. None when the key is absent, otherwise the replacement
+    /// node, itself None once the subtree is empty.
+    fn remove_at(
+        &mut self, node: Option<Digest>, path: &[u8],
+    ) -> std::result::Result<Option<Option<Digest>>, Error> {
+        let Some(hash) = node else {
+            return Ok(None);
+        };
+        match self.load(&hash)? {
+            TrieNode::Leaf { path: existing, .. } => Ok((existing == path).then_some(None)),
+            TrieNode::Extension { path: shared, child } => {
+                if !path.starts_with(&shared) {
+                    return Ok(None);
+                }
+                match self.remove_at(Some(child), &path[shared.len()..])? {
+                    None => Ok(None),
+                    Some(None) => Ok(Some(None)),
+                    Some(Some(child)) => Ok(Some(Some(self.join(&shared, child)?))),
+                }
+            },
+            TrieNode::Branch { mut children, mut value } => {
+                match path.split_first() {
+                    None if value.is_none() => return Ok(None),
+                    None => value = None,
+                    Some((nibble, rest)) => {
+                        let slot = &mut children[*nibble as usize];
+                        match self.remove_at(*slot, rest)? {
+                            None => return Ok(None),
+                            Some(child) => *slot = child,
+                        }
+                    },
+                }
+                Ok(Some(self.collapse(children, value)?))
+            },
+        }
+    }
+
+    /// Branch left after a removal, folded into a leaf or extension once it has a single entry.
+    fn collapse(
+        &mut self, children: Box<[Option<Digest>; 16]>, value: Option<Vec<u8>>,
+    ) -> std::result::Result<Option<Digest>, Error> {
+        let mut occupied = children.iter().enumerate().filter_map(|(i, c)| c.map(|c| (i, c)));
+        match (occupied.next(), occupied.next(), value) {
+            (None, _, None) => Ok(None),
+            (None, _, Some(value)) => {
+                Ok(Some(self.save(TrieNode::Leaf { path: Vec::new(), value })?))
+            },
+            (Some((nibble, child)), None, None) => Ok(Some(self.join(&[nibble as u8], child)?)),
+            (_, _, value) => Ok(Some(self.save(TrieNode::Branch { children, value })?)),
+        }
+    }
+
+    /// `child` reached through `prefix`, merged into the child when it is a leaf or extension.
+    fn join(&mut self, prefix: &[u8], child: Digest) -> std::result::Result<Digest, Error> {
+        match self.load(&child)? {
+            TrieNode::Leaf { path, value } => {
+                self.save(TrieNode::Leaf { path: [prefix, &path].concat(), value })
+            },
+            TrieNode::Extension { path, child } => {
+                self.save(TrieNode::Extension { path: [prefix, &path].concat(), child })
+            },
+            TrieNode::Branch { .. } => self.extend(prefix, child),
+        }
+    }
+
+    /// `child` behind an extension for `prefix`, or `child` itself when the prefix is empty.
+    fn extend(&mut self, prefix: &[u8], child: Digest) -> std::result::Result<Digest, Error> {
+        if prefix.is_empty() {
+            return Ok(child);
+        }
+        self.save(TrieNode::Extension { path: prefix.to_vec(), child })
+    }
+}
+
+/// Root of the empty trie for `hasher`.
+pub fn empty_root(hasher: &dyn BlockHasher) -> Digest {
+    Digest::new(&vec![0; hasher.width()])
+}
+
+impl TrieProof {
+    /// Walks the proof from `root` along `key`. Returns the value proven to be stored
+    /// under `key`, or None when the proof shows there is none.
+    pub fn verify(
+        &self, hasher: &dyn BlockHasher, root: &Digest, key: &[u8],
+    ) -> std::result::Result<Option<Vec<u8>>, TrieProofError> {
+        let path = to_nibbles(key);
+        let mut path = path.as_slice();
+        let mut expected = (*root != empty_root(hasher)).then_some(*root);
+        let mut nodes = self.nodes.iter().enumerate();
+        let mut found = None;
+        while let Some(hash) = expected {
+            let (depth, encoded) = nodes.next().ok_or(TrieProofError::Incomplete)?;
+            if hasher.digest(encoded) != hash {
+                return Err(TrieProofError::HashMismatch { depth });
+            }
+            let node =
+                TrieNode::decode(encoded).map_err(|_| TrieProofError::Undecodable { depth })?;
+            expected = match node {
+                TrieNode::Leaf { path: rest, value } => {
+                    found = (rest == path).then_some(value);
+                    None
+                },
+                TrieNode::Extension { path: shared, child } => {
+                    let follows = path.starts_with(&shared);
+                    path = &path[shared.len().min(path.len())..];
+                    follows.then_some(child)
+                },
+                TrieNode::Branch { children, value } => match path.split_first() {
+                    None => {
+                        found = value;
+                        None
+                    },
+                    Some((nibble, rest)) => {
+                        path = rest;
+                        children[*nibble as usize]
+                    },
+                },
+            };
+        }
+        if nodes.next().is_some() {
+            return Err(TrieProofError::TrailingNodes);
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    use super::*;
+    use crate::core::hasher::{Blake3Hasher, TruncatedSha256Hasher};
+    use crate::core::trie::MemoryNodeStore;
+    use crate::storage::engine::{Engine, EngineOps};
+    use crate::storage::test_dir;
+    use proptest::prelude::*;
+
+    fn trie() -> MerklePatriciaTrie<MemoryNodeStore> {
+        MerklePatriciaTrie::new(MemoryNodeStore::default(), Arc::new(TruncatedSha256Hasher))
+    }
+
+    #[test]
+    fn test_root_depends_only_on_contents() {
+        let keys: [&[u8]; 7] = [b"do", b"dog", b"doge", b"horse", b"", b"d", b"dogs"];
+        let mut forward = trie();
+        let mut backward = trie();
+        let empty = forward.root();
+        for key in keys {
+            forward.insert(key, key).unwrap();
+        }
+        for key in keys.iter().rev() {
+            backward.insert(key, b"stale").unwrap();
+            backward.insert(key, key).unwrap();
+        }
+        assert_eq!(forward.root(), backward.root());
+        for key in keys {
+            assert_eq!(forward.get(key).unwrap(), Some(key.to_vec()));
+        }
+        assert_eq!(forward.get(b"doggo").unwrap(), None);
+        assert_eq!(forward.get(b"h").unwrap(), None);
+
+        // removing a key gives the root of never having it
+        let mut without = trie();
+        for key in keys.iter().filter(|k| **k != b"dog") {
+            without.insert(key, key).unwrap();
+        }
+        assert!(forward.remove(b"dog").unwrap());
+        assert!(!forward.remove(b"dog").unwrap());
+        assert_eq!(forward.root(), without.root());
+        for key in keys {
+            forward.remove(key).unwrap();
+        }
+        assert_eq!(forward.root(), empty);
+    }
+
+    #[test]
+    fn test_proofs_of_inclusion_and_absence() {
+        let hasher = Blake3Hasher;
+        let mut trie = MerklePatriciaTrie::new(MemoryNodeStore::default(), Arc::new(hasher));
+        let empty_proof = trie.prove(b"dog").unwrap();
+        assert_eq!(empty_proof.verify(&hasher, &trie.root(), b"dog"), Ok(None));
+        for key in [&b"do"[..], b"dog", b"doge", b"horse"] {
+            trie.insert(key, &[key, b"!"].concat()).unwrap();
+        }
+        let root = trie.root();
+        for key in [&b"do"[..], b"dog", b"doge", b"horse"] {
+            let proof = trie.prove(key).unwrap();
+            assert_eq!(proof.verify(&hasher, &root, key), Ok(Some([key, b"!"].concat())));
+        }
+        for absent in [&b"d"[..], b"dogs", b"cat", b"horsey", b""] {
+            let proof = trie.prove(absent).unwrap();
+            assert_eq!(proof.verify(&hasher, &root, absent), Ok(None), "{:?}", absent);
+        }
+
+        let proof = trie.prove(b"doge").unwrap();
+        // a proof only speaks for its own key and root
+        assert_eq!(proof.verify(&hasher, &root, b"dog"), Err(TrieProofError::TrailingNodes));
+        assert_eq!(
+            proof.verify(&hasher, &empty_root(&hasher), b"doge"),
+            Err(TrieProofError::TrailingNodes)
+        );
+        let mut forged = proof.clone();
+        let last = forged.nodes.len() - 1;
+        forged.nodes[last] = TrieNode::Leaf { path: vec![], value: b"forged".to_vec() }.encode();
+        assert_eq!(
+            forged.verify(&hasher, &root, b"doge"),
+            Err(TrieProofError::HashMismatch { depth: last })
+        );
+        let mut short = proof;
+        short.nodes.pop();
+        assert_eq!(short.verify(&hasher, &root, b"doge"), Err(TrieProofError::Incomplete));
+    }
+
+    #[test]
+    fn test_state_persists_in_engine() {
+        let dir = test_dir("mpt-engine");
+        let hasher: Arc<dyn BlockHasher> = Arc::new(TruncatedSha256Hasher);
+        let mut roots = Vec::new();
+        {
+            let engine = Engine::open(&dir).unwrap();
+            let mut state = MerklePatriciaTrie::new(engine, hasher.clone());
+            for height in 0..30u16 {
+                let records: Vec<Block> = (0..50u16)
+                    .map(|i| {
+                        // every seventh record revokes a key of the previous block
+                        let revoke = i % 7 == 0 && height > 0;
+                        let phone = if revoke { height - 1 } else { height } * 50 + i;
+                        Block::with_payload(
+                            phone.to_be_bytes().to_vec(),
+                            vec![height as u8],
+                            revoke,
+                        )
+                    })
+                    .collect();
+                roots.push(state.apply(&records).unwrap());
+            }
+            state.into_store().flush().unwrap();
+        }
+        let engine = Engine::open(&dir).unwrap();
+        let state = MerklePatriciaTrie::at(engine, hasher.clone(), *roots.last().unwrap());
+        assert_eq!(state.get(&7u16.to_be_bytes()).unwrap(), None);
+        assert_eq!(state.get(&8u16.to_be_bytes()).unwrap(), Some(vec![0]));
+        assert_eq!(state.get(&1498u16.to_be_bytes()).unwrap(), Some(vec![29]));
+        // earlier roots stay readable
+        let earlier = MerklePatriciaTrie::at(state.into_store(), hasher.clone(), roots[0]);
+        assert_eq!(earlier.get(&1498u16.to_be_bytes()).unwrap(), None);
+        let proof = earlier.prove(&49u16.to_be_bytes()).unwrap();
+        assert_eq!(proof.verify(&*hasher, &roots[0], &49u16.to_be_bytes()), Ok(Some(vec![0])));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    proptest! {
+        #[test]
+        fn prop_trie_matches_btree_map(ops in prop::collection::vec((prop::collection::vec(0u8..4, 0..4), any::<bool>()), 1..200)) {
+            let mut state = trie();
+            let mut model = BTreeMap::new();
+            for (i, (key, insert)) in ops.iter().enumerate() {
+                if *insert {
+                    state.insert(key, &[i as u8]).unwrap();
+                    model.insert(key.clone(), vec![i as u8]);
+                } else {
+                    prop_assert_eq!(state.remove(key).unwrap(), model.remove(key).is_some());
+                }
+            }
+            let mut rebuilt = trie();
+            for (key, value) in &model {
+                rebuilt.insert(key, value).unwrap();
+            }
+            prop_assert_eq!(state.root(), rebuilt.root());
+            for (key, _) in &ops {
+                let proof = state.prove(key).unwrap();
+                prop_assert_eq!(proof.verify(&TruncatedSha256Hasher, &state.root(), key).unwrap(), model.get(key).cloned());
+            }
+        }
+    }
+}
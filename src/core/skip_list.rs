@@ -1,59 +1,27 @@
 use std::cmp::Ordering;
 
-use crate::core::block::Block;
-use crate::datasource::DataSource;
+use crate::core::block::{Block, Payload};
+use crate::core::hasher::Digest;
 use crate::sys::blocks_ptr;
-use crate::sys::pin_memory;
+use crate::sys::{pin_memory, unpin_memory};
 use serde::{Deserialize, Serialize};
 
-// 1K blocks logn = 10
-const MAX_LEVEL: usize = 8;
-const CAPACITY: usize = 1000;
-const HOT_DATA_PROBABILITY_THRESHOLD: f64 = 0.75;
-const MEDIUM_PROBABILITY_THRESHOLD: f64 = 0.50;
-const COLD_DATA_PROBABILITY_THRESHOLD: f64 = 0.25;
-
-/// Randomly assign a level to the data provided
-/// param data: data to be assigned a level
-/// param source: source of data
-/// return: level assigned to the data
-/// 1. If the data is from ring buffer, bump level with 75% probability
-/// 2. If the data is from memtable, bump level with 50% probability
-/// 3. If the data is from sstable, bump level with 25% probability
-pub fn random_level(data: &[u8; 16], source: DataSource) -> usize {
-    let mut level = 1;
-    while rand::random::<bool>() && level < MAX_LEVEL {
-        match source {
-            DataSource::RingBuffer => {
-                if rand::random::<f64>() > HOT_DATA_PROBABILITY_THRESHOLD {
-                    level += 1;
-                }
-            },
-            DataSource::MemTable => {
-                if rand::random::<f64>() > MEDIUM_PROBABILITY_THRESHOLD {
-                    level += 1;
-                }
-            },
+/// default number of layers, enough for 4^8 = 65536 blocks
+pub const MAX_LEVEL: usize = 8;
+/// every layer links every `LEVEL_FANOUT`th node of the layer below
+const LEVEL_FANOUT: usize = 4;
+/// default number of blocks, see `Options::mem_table_capacity`
+pub const CAPACITY: usize = 1000;
 
-            DataSource::SSTable => {
-                if rand::random::<f64>() > COLD_DATA_PROBABILITY_THRESHOLD {
-                    level += 1;
-                }
-            },
-        }
-    }
-    level
-}
 #[repr(C)]
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SkipNode {
-    /// bitmap that represents layers this node is present.
-    /// 0000 , 0000 (first 4 bits for layer, next 4 bits for offset index on layer 0)
-    /// Node is present in layer 0 and offset 1[ 0000, 0001]
-    /// Node is present in layer 0 and offset 1 & Node is present in layer 1 and offset 2[ (0000, 0001) , (0001, 0010)]
-    pub layer_next: [u8; MAX_LEVEL], // 10 layers
     pub tombstone: bool,
-    pub data: [u8; 16], // hash of phone number
+    pub data: Digest, // hash of the key
+    /// sequence number of the write, see `Block::seq`
+    pub seq: u64,
+    /// original key and value, see `Block::payload`
+    pub payload: Option<Box<Payload>>,
 }
 impl PartialEq for SkipNode {
     fn eq(&self, other: &Self) -> bool {
@@ -72,108 +40,220 @@ impl Ord for SkipNode {
         self.data.cmp(&other.data)
     }
 }
+impl From<&SkipNode> for Block {
+    /// skip nodes do not keep the write time, timestamp is left at 0
+    fn from(node: &SkipNode) -> Block {
+        Block {
+            data: node.data,
+            timestamp: 0,
+            seq: node.seq,
+            disabled: node.tombstone,
+            next: None,
+            payload: node.payload.clone(),
+        }
+    }
+}
+
+impl From<Block> for SkipNode {
+    fn from(block: Block) -> SkipNode {
+        SkipNode {
+            payload: block.payload,
+            ..SkipNode::new(block.data, block.seq, block.disabled)
+        }
+    }
+}
+
 impl SkipNode {
-    fn new(data: [u8; 16], tombstone_marker: bool, source: DataSource) -> Self {
+    fn new(data: Digest, seq: u64, tombstone_marker: bool) -> Self {
         SkipNode {
-            layer_next: [0; 8],
             tombstone: tombstone_marker,
             data,
+            seq,
+            payload: None,
         }
     }
 }
 
-/// A bitmap based skip_list
-/// Each node maintains a bitmap (array of fixed size) to represent layers it is present in (using high nibble in a byte) and
-/// total steps it takes from head to reach this node (using low nibble in a byte).
+/// A skip list laid out as a sorted array.
+/// Layers are implicit in the array positions: layer 0 holds every node, layer l every
+/// `LEVEL_FANOUT^l`th one, so the nodes of a layer are a fixed stride apart and an insert,
+/// which shifts the tail by one slot, never has to relink anything.
+/// A search walks the top layer as far as it can, then drops a layer, at most
+/// `LEVEL_FANOUT - 1` steps per layer.
 /// Each node maintains a tombstone marker to indicate if the node is deleted.
 /// Each node maintains a data field to store the hash of phone number (in future any other data like posts or something related to social network)
-/// This makes skip_list a sorted array with fixed pre-allocated blocks of memory.
-/// And is pinned in memory during initialization.
-/// No dynamic memory allocation is done mostly, when full flush to `SSTable` is done.
+/// The blocks are a fixed array allocated during initialization and pinned in memory
+/// where the memlock limit allows it.
+/// Nothing is allocated on insert or search, when full the list is flushed to an `SSTable`.
 #[repr(C)]
 pub struct SkipList {
-    /// represents a sorted array of
-    // heads at each level of skip list
-    // levels are fixed
-    pub heads: [usize; MAX_LEVEL],
-    // pre-allocate fixed length array of blocks
-    pub blocks: [Option<SkipNode>; CAPACITY],
+    // pre-allocated blocks, sized once by `with_capacity`
+    pub blocks: Box<[Option<SkipNode>]>,
+    // number of occupied blocks, `blocks[..count]` is kept sorted
+    pub count: usize,
+    // layers a search walks down through
+    max_level: usize,
+    // whether `blocks` are locked in memory and have to be unlocked on drop
+    pinned: bool,
 }
 
 pub trait SkipListOps {
-    fn add(&mut self, data: &[u8; 16], tombstone_marker: bool) -> bool;
-    /// Reader flushes blocks from skip list and writes to SSTable
+    /// Inserts `data` in sorted position, returns false when the skip list is full
+    /// and has to be flushed to an `SSTableSegment` first.
+    /// Equal keys are kept ordered by `seq`, oldest first, whatever order the writes arrive in.
+    fn add(&mut self, data: Digest, seq: u64, tombstone_marker: bool) -> bool;
+    /// Inserts a node carrying its payload, see `add`.
+    fn insert(&mut self, node: SkipNode) -> bool;
+    /// Replaces every version of the node's key with the node, inserting it when the key is new.
+    /// Returns false when the key is new and the skip list is full.
+    fn upsert(&mut self, node: SkipNode) -> bool;
+    /// Writes a tombstone version for `key`, older versions stay readable at their `seq`.
+    fn delete(&mut self, key: Digest, seq: u64) -> bool;
+    /// Resets the skip list once its blocks have been written to an `SSTableSegment`.
     fn flush(&mut self) -> bool;
     fn size(&self) -> usize;
-    fn search(&self, key: [u8; 16]) -> Option<Block>;
+    /// Latest entry written for `key`, tombstones included.
+    /// Borrowed from the list, callers clone it into a `Block` when they hand it out.
+    fn search(&self, key: Digest) -> Option<&SkipNode>;
+    /// Latest entry for `key` written at or before `seq`.
+    fn search_at(&self, key: Digest, seq: u64) -> Option<&SkipNode>;
     fn merge(&mut self, other: [u8; 100]) -> bool;
 }
 
 impl SkipList {
     pub fn init() -> SkipList {
-        let skip_list = SkipList::_new();
+        SkipList::with_capacity(CAPACITY, MAX_LEVEL)
+    }
+
+    /// Skip list of `capacity` blocks searched through `max_level` layers.
+    /// The blocks stay pageable when pinning fails, e.g. once `RLIMIT_MEMLOCK` is used up.
+    pub fn with_capacity(capacity: usize, max_level: usize) -> SkipList {
+        let mut skip_list = SkipList::_new(capacity, max_level);
         let blocks_ptr = blocks_ptr(&skip_list);
-        // force failure if the memory is not pinned
-        pin_memory(blocks_ptr, std::mem::size_of_val(&skip_list.blocks)).unwrap();
+        skip_list.pinned =
+            pin_memory(blocks_ptr, std::mem::size_of_val(&*skip_list.blocks)).is_ok();
         skip_list
     }
-    fn _new() -> Self {
+
+    /// Like `with_capacity` without pinning the blocks, for lists that may `grow`.
+    pub fn unpinned(capacity: usize, max_level: usize) -> SkipList {
+        SkipList::_new(capacity, max_level)
+    }
+
+    fn _new(capacity: usize, max_level: usize) -> Self {
         SkipList {
-            heads: [usize::MAX; 8],
-            blocks: [None; 1000],
+            blocks: (0..capacity).map(|_| None).collect(),
+            count: 0,
+            max_level: max_level.max(1),
+            pinned: false,
+        }
+    }
+
+    /// Blocks the list holds before it has to be flushed.
+    pub fn capacity(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Reallocates the blocks to hold `capacity`, the nodes keep their positions.
+    /// A pinned list is unpinned first, the new blocks are never pinned.
+    pub fn grow(&mut self, capacity: usize) {
+        if self.pinned {
+            let _ = unpin_memory(blocks_ptr(self), std::mem::size_of_val(&*self.blocks));
+            self.pinned = false;
+        }
+        let mut blocks = std::mem::take(&mut self.blocks).into_vec();
+        blocks.resize_with(capacity.max(self.count), || None);
+        self.blocks = blocks.into_boxed_slice();
+    }
+
+    /// Occupied nodes in key order.
+    pub fn iter(&self) -> impl Iterator<Item = &SkipNode> {
+        self.blocks[..self.count].iter().flatten()
+    }
+
+    /// Number of leading nodes satisfying `before`, which has to hold for a prefix
+    /// of the nodes. Walks the layers top down.
+    fn seek(&self, before: impl Fn(&SkipNode) -> bool) -> usize {
+        let mut index = 0;
+        for level in (0..self.max_level).rev() {
+            let step = LEVEL_FANOUT.pow(level as u32);
+            while index + step <= self.count && before(self.node(index + step - 1)) {
+                index += step;
+            }
         }
+        index
+    }
+
+    /// Index range of every version of `key`, oldest first.
+    fn versions(&self, key: &Digest) -> std::ops::Range<usize> {
+        self.seek(|n| n.data < *key)..self.seek(|n| n.data <= *key)
+    }
+
+    fn node(&self, index: usize) -> &SkipNode {
+        self.blocks[index].as_ref().unwrap()
     }
 }
 
 impl SkipListOps for SkipList {
-    fn add(&mut self, data: &[u8; 16], tombstone_marker: bool) -> bool {
-        let mut inserted = false;
-        if self.size() == CAPACITY {
-            // flush the skip list to SSTable
-            inserted = self.flush();
-        } else {
-            let max_level = random_level(data, DataSource::RingBuffer);
-            let mut new_node = SkipNode::new(*data, tombstone_marker, DataSource::RingBuffer);
-            // find right place to insert node
-            for i in (0..max_level).rev() {
-                let mut pos = self.heads[i];
-                while pos < self.blocks.len()
-                    && self.blocks[pos].is_some()
-                    && self.blocks[pos].unwrap().data < new_node.data
-                {
-                    pos += 1;
-                }
-                if pos < self.blocks.len()
-                    && self.blocks[pos].is_some()
-                    && (self.blocks[pos].unwrap().data >= new_node.data)
-                {
-                    // insert at this level
-                    let byte_offset = i / 8;
-                    let bit_offset = i % 4;
-                    let step_offset = (pos - self.heads[i]) % 4;
-                    let higher_nibble =
-                        (new_node.layer_next[byte_offset] & 0xF0) | 1 << (bit_offset + 4);
-                    let lower_nibble =
-                        (new_node.layer_next[byte_offset] & 0x0F) | (1 << step_offset);
-                    new_node.layer_next[byte_offset] = higher_nibble | lower_nibble;
-                    self.blocks[pos] = Some(new_node);
-                    inserted = true;
-                }
-            }
+    fn add(&mut self, data: Digest, seq: u64, tombstone_marker: bool) -> bool {
+        self.insert(SkipNode::new(data, seq, tombstone_marker))
+    }
+
+    fn insert(&mut self, new_node: SkipNode) -> bool {
+        if self.size() == self.capacity() {
+            // caller has to flush the skip list to SSTable first
+            return false;
         }
-        return inserted;
+        // equal keys are ordered by seq, equal seqs by arrival
+        let pos = self
+            .seek(|n| n.data < new_node.data || (n.data == new_node.data && n.seq <= new_node.seq));
+        // shift the tail right by one to make room for the new node
+        self.blocks[pos..=self.count].rotate_right(1);
+        self.blocks[pos] = Some(new_node);
+        self.count += 1;
+        true
+    }
+
+    fn upsert(&mut self, node: SkipNode) -> bool {
+        let versions = self.versions(&node.data);
+        if versions.is_empty() {
+            return self.insert(node);
+        }
+        // the first version takes the node, the tail moves left over the others
+        let stale = versions.len() - 1;
+        self.blocks[versions.start] = Some(node);
+        self.blocks[versions.start + 1..self.count].rotate_left(stale);
+        self.blocks[self.count - stale..self.count].fill(None);
+        self.count -= stale;
+        true
+    }
+
+    fn delete(&mut self, key: Digest, seq: u64) -> bool {
+        self.add(key, seq, true)
     }
 
     fn flush(&mut self) -> bool {
-        todo!()
+        if self.count == 0 {
+            return false;
+        }
+        // reset in place, the blocks stay pinned
+        self.blocks.fill(None);
+        self.count = 0;
+        true
     }
 
     fn size(&self) -> usize {
-        todo!()
+        self.count
+    }
+
+    fn search(&self, key: Digest) -> Option<&SkipNode> {
+        self.search_at(key, u64::MAX)
     }
 
-    fn search(&self, key: [u8; 16]) -> Option<Block> {
-        todo!()
+    fn search_at(&self, key: Digest, seq: u64) -> Option<&SkipNode> {
+        // equal keys are stored oldest first, the last visible one wins
+        let versions = self.versions(&key);
+        self.blocks[versions].iter().flatten().rev().find(|n| n.seq <= seq)
     }
 
     fn merge(&mut self, other: [u8; 100]) -> bool {
@@ -182,15 +262,121 @@ impl SkipListOps for SkipList {
     }
 }
 
+impl Drop for SkipList {
+    fn drop(&mut self) {
+        if self.pinned {
+            // nothing to report, the pages are freed right after
+            let _ = unpin_memory(blocks_ptr(self), std::mem::size_of_val(&*self.blocks));
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use proptest::prelude::*;
+
     #[test]
-    fn test_bitmap_code() {
-        // init 10 elements with 0 (nibble 1= layer, nibble 2 = next index)
-        // assume max_level in question is 5 for an element to be inserted
-        let byte_index = 10 / 8;
-        let bit_offset = 10 % 4;
-        let a = [0b0000_0000; 10];
-        println!("{:08b}", (a[byte_index] | (1 << (bit_offset + 4)) & 0xF0));
+    fn test_search_returns_latest_entry() {
+        let mut skip_list = SkipList::_new(CAPACITY, MAX_LEVEL);
+        skip_list.add([2; 16].into(), 1, false);
+        skip_list.add([1; 16].into(), 2, false);
+        skip_list.add([2; 16].into(), 3, true);
+        assert!(!skip_list.search([1; 16].into()).unwrap().tombstone);
+        assert!(skip_list.search([2; 16].into()).unwrap().tombstone);
+        assert!(skip_list.search([3; 16].into()).is_none());
+        // older versions stay readable at their sequence number
+        assert!(!skip_list.search_at([2; 16].into(), 2).unwrap().tombstone);
+        assert!(skip_list.search_at([1; 16].into(), 1).is_none());
+    }
+
+    #[test]
+    fn test_upsert_replaces_every_version() {
+        let mut skip_list = SkipList::_new(CAPACITY, MAX_LEVEL);
+        for seq in 0..5 {
+            skip_list.add([(seq % 3) as u8; 16].into(), seq, false);
+        }
+        assert!(skip_list.delete([1; 16].into(), 5));
+        assert_eq!(skip_list.size(), 6);
+        assert!(skip_list.upsert(SkipNode::new([1; 16].into(), 6, false)));
+        assert_eq!(skip_list.size(), 4);
+        let keys: Vec<(u8, u64)> =
+            skip_list.iter().map(|n| (n.data.as_bytes()[0], n.seq)).collect();
+        assert_eq!(keys, vec![(0, 0), (0, 3), (1, 6), (2, 2)]);
+        assert!(skip_list.blocks[4..].iter().all(|b| b.is_none()));
+        assert!(skip_list.upsert(SkipNode::new([9; 16].into(), 7, false)));
+        assert_eq!(skip_list.search([9; 16].into()).unwrap().seq, 7);
+    }
+
+    #[test]
+    fn test_insert_stops_at_capacity() {
+        let mut skip_list = SkipList::_new(CAPACITY, MAX_LEVEL);
+        for i in 0..CAPACITY {
+            assert!(skip_list.add(Digest::from((i as u16).to_be_bytes()), i as u64, false));
+        }
+        assert!(!skip_list.add([0; 2].into(), 0, false));
+        assert!(!skip_list.upsert(SkipNode::new([0xFF; 2].into(), 0, false)));
+        // an existing key is replaced in place even when full
+        assert!(skip_list.upsert(SkipNode::new([0; 2].into(), 1000, true)));
+        assert!(skip_list.search([0; 2].into()).unwrap().tombstone);
+        assert_eq!(skip_list.search([3, 0xE7].into()).unwrap().seq, 999);
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Add(u8),
+        Upsert(u8),
+        Delete(u8),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (0u8..40).prop_map(Op::Add),
+            (0u8..40).prop_map(Op::Upsert),
+            (0u8..40).prop_map(Op::Delete),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_matches_btree_map_model(ops in prop::collection::vec(op(), 0..400), probe in 0u64..400) {
+            let mut skip_list = SkipList::_new(CAPACITY, MAX_LEVEL);
+            // every version of a key, oldest first
+            let mut model: BTreeMap<Digest, Vec<(u64, bool)>> = BTreeMap::new();
+            for (seq, op) in ops.into_iter().enumerate() {
+                let seq = seq as u64;
+                match op {
+                    Op::Add(k) => {
+                        prop_assert!(skip_list.add([k; 16].into(), seq, false));
+                        model.entry([k; 16].into()).or_default().push((seq, false));
+                    },
+                    Op::Upsert(k) => {
+                        prop_assert!(skip_list.upsert(SkipNode::new([k; 16].into(), seq, false)));
+                        model.insert([k; 16].into(), vec![(seq, false)]);
+                    },
+                    Op::Delete(k) => {
+                        prop_assert!(skip_list.delete([k; 16].into(), seq));
+                        model.entry([k; 16].into()).or_default().push((seq, true));
+                    },
+                }
+            }
+            let expected: Vec<(Digest, u64, bool)> = model
+                .iter()
+                .flat_map(|(k, versions)| versions.iter().map(move |(s, t)| (*k, *s, *t)))
+                .collect();
+            let actual: Vec<(Digest, u64, bool)> = skip_list.iter().map(|n| (n.data, n.seq, n.tombstone)).collect();
+            prop_assert_eq!(skip_list.size(), expected.len());
+            prop_assert_eq!(actual, expected);
+            for k in 0u8..40 {
+                let key = Digest::from([k; 16]);
+                let versions = model.get(&key).map(|v| v.as_slice()).unwrap_or(&[]);
+                let latest = skip_list.search(key).map(|b| (b.seq, b.tombstone));
+                prop_assert_eq!(latest, versions.last().copied());
+                let visible = skip_list.search_at(key, probe).map(|b| (b.seq, b.tombstone));
+                prop_assert_eq!(visible, versions.iter().rev().find(|(s, _)| *s <= probe).copied());
+            }
+        }
     }
 }
@@ -0,0 +1,284 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::block::Block;
+use crate::core::hasher::{BlockHasher, Digest};
+use crate::core::merkle::{leaf_hash, node_hash};
+use crate::storage::mem_table::{MemTable, MemTableOps};
+
+/// Sparse Merkle tree with one leaf slot per possible key digest, 128 levels deep for the
+/// default 16 byte digests. Only live records occupy a slot, every empty subtree hashes to
+/// the precomputed default of its height and is never stored.
+pub struct SparseMerkleTree {
+    /// bits in a key, the height of the root
+    depth: usize,
+    /// `defaults[h]` is the root of an empty subtree of height h
+    defaults: Vec<Digest>,
+    /// non default nodes by height and the key prefix above them, low bits zeroed
+    nodes: HashMap<(usize, Digest), Digest>,
+    /// live record of every occupied slot
+    records: BTreeMap<Digest, Block>,
+}
+
+/// Siblings from the leaf up to the root. Siblings equal to their height's default are left
+/// out and flagged in `present`, so a proof in a sparse tree carries a handful of digests.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SparseMerkleProof {
+    /// bit h set when the sibling at height h is carried in `siblings`
+    pub present: Vec<u8>,
+    /// non default siblings, lowest height first
+    pub siblings: Vec<Digest>,
+}
+
+pub trait SparseMerkleTreeOps: Sized {
+    /// Empty tree over the digests of `hasher`.
+    fn new(hasher: &dyn BlockHasher) -> Self;
+    fn root(&self) -> Digest;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool;
+    fn get(&self, key: &Digest) -> Option<&Block>;
+    /// Applies `records` in order, tombstones empty their slot.
+    /// Shared ancestors of the batch are rehashed once.
+    fn update(&mut self, hasher: &dyn BlockHasher, records: impl IntoIterator<Item = Block>);
+    /// Applies everything a memtable is about to flush.
+    fn update_from_mem_table(&mut self, hasher: &dyn BlockHasher, mem_table: &MemTable);
+    /// Proof for the slot of `key`, showing either its record or that it is empty.
+    fn prove(&self, key: &Digest) -> SparseMerkleProof;
+}
+
+impl SparseMerkleTreeOps for SparseMerkleTree {
+    fn new(hasher: &dyn BlockHasher) -> Self {
+        let depth = hasher.width() * 8;
+        SparseMerkleTree {
+            depth,
+            defaults: default_hashes(hasher, depth),
+            nodes: HashMap::new(),
+            records: BTreeMap::new(),
+        }
+    }
+
+    fn root(&self) -> Digest {
+        self.node(self.depth, &Digest::new(&vec![0; self.depth / 8]))
+    }
+
+    fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    fn get(&self, key: &Digest) -> Option<&Block> {
+        self.records.get(key)
+    }
+
+    fn update(&mut self, hasher: &dyn BlockHasher, records: impl IntoIterator<Item = Block>) {
+        let mut dirty = Vec::new();
+        for record in records {
+            assert_eq!(record.data.len() * 8, self.depth, "key digest of another width");
+            let key = record.data;
+            if record.disabled {
+                self.records.remove(&key);
+                self.nodes.remove(&(0, key));
+            } else {
+                self.nodes.insert((0, key), leaf_hash(hasher, &record));
+                self.records.insert(key, record);
+            }
+            dirty.push(key);
+        }
+        dirty.sort();
+        dirty.dedup();
+        for height in 0..self.depth {
+            // parents stay sorted, siblings end up next to each other
+            dirty = dirty.iter().map(|key| prefix(key, height + 1)).collect();
+            dirty.dedup();
+            for parent in &dirty {
+                let left = self.node(height, parent);
+                let right = self.node(height, &with_bit(parent, self.depth - 1 - height));
+                if left == self.defaults[height] && right == self.defaults[height] {
+                    self.nodes.remove(&(height + 1, *parent));
+                } else {
+                    self.nodes.insert((height + 1, *parent), node_hash(hasher, &left, &right));
+                }
+            }
+        }
+    }
+
+    fn update_from_mem_table(&mut self, hasher: &dyn BlockHasher, mem_table: &MemTable) {
+        // equal keys sit oldest first, the last one wins
+        let records = mem_table.blocks.blocks[..mem_table.size()].iter().flatten();
+        self.update(hasher, records.map(Block::from));
+    }
+
+    fn prove(&self, key: &Digest) -> SparseMerkleProof {
+        assert_eq!(key.len() * 8, self.depth, "key digest of another width");
+        let mut proof = SparseMerkleProof {
+            present: vec![0; self.depth.div_ceil(8)],
+            siblings: Vec::new(),
+        };
+        for height in 0..self.depth {
+            let sibling =
+                self.node(height, &prefix(&flip_bit(key, self.depth - 1 - height), height));
+            if sibling != self.defaults[height] {
+                proof.present[height / 8] |= 1 << (height % 8);
+                proof.siblings.push(sibling);
+            }
+        }
+        proof
+    }
+}
+
+impl SparseMerkleTree {
+    pub fn records(&self) -> impl Iterator<Item = &Block> {
+        self.records.values()
+    }
+
+    fn node(&self, height: usize, prefix: &Digest) -> Digest {
+        self.nodes.get(&(height, *prefix)).copied().unwrap_or(self.defaults[height])
+    }
+}
+
+impl SparseMerkleProof {
+    /// Checks the slot of `key` under `root` holds `record`, or is empty when `record` is None.
+    pub fn verify(
+        &self, hasher: &dyn BlockHasher, root: &Digest, key: &Digest, record: Option<&Block>,
+    ) -> bool {
+        let depth = key.len() * 8;
+        if self.present.len() != depth.div_ceil(8) {
+            return false;
+        }
+        let defaults = default_hashes(hasher, depth);
+        let mut current = match record {
+            Some(record) if record.data != *key || record.disabled => return false,
+            Some(record) => leaf_hash(hasher, record),
+            None => defaults[0],
+        };
+        let mut siblings = self.siblings.iter();
+        for (height, default) in defaults[..depth].iter().enumerate() {
+            let sibling = if self.present[height / 8] & (1 << (height % 8)) != 0 {
+                match siblings.next() {
+                    Some(sibling) => sibling,
+                    None => return false,
+                }
+            } else {
+                default
+            };
+            current = if bit(key, depth - 1 - height) {
+                node_hash(hasher, sibling, &current)
+            } else {
+                node_hash(hasher, &current, sibling)
+            };
+        }
+        siblings.next().is_none() && current == *root
+    }
+}
+
+/// Roots of empty subtrees for every height up to `depth`, the empty leaf is all zeros.
+pub fn default_hashes(hasher: &dyn BlockHasher, depth: usize) -> Vec<Digest> {
+    let mut defaults = vec![Digest::new(&vec![0; hasher.width()])];
+    for height in 0..depth {
+        let below = defaults[height];
+        defaults.push(node_hash(hasher, &below, &below));
+    }
+    defaults
+}
+
+/// Bit `index` of `key`, counted from the most significant bit of the first byte.
+fn bit(key: &Digest, index: usize) -> bool {
+    key.as_bytes()[index / 8] & (0x80 >> (index % 8)) != 0
+}
+
+fn with_bit(key: &Digest, index: usize) -> Digest {
+    let mut bytes = key.as_bytes().to_vec();
+    bytes[index / 8] |= 0x80 >> (index % 8);
+    Digest::new(&bytes)
+}
+
+fn flip_bit(key: &Digest, index: usize) -> Digest {
+    let mut bytes = key.as_bytes().to_vec();
+    bytes[index / 8] ^= 0x80 >> (index % 8);
+    Digest::new(&bytes)
+}
+
+/// `key` with its lowest `height` bits cleared, naming the subtree of that height above it.
+fn prefix(key: &Digest, height: usize) -> Digest {
+    let mut bytes = key.as_bytes().to_vec();
+    let depth = bytes.len() * 8;
+    for index in depth - height..depth {
+        bytes[index / 8] &= !(0x80 >> (index % 8));
+    }
+    Digest::new(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::hasher::{Blake3Hasher, TruncatedSha256Hasher};
+
+    #[test]
+    fn test_root_commits_to_the_live_set() {
+        let hasher = TruncatedSha256Hasher;
+        let mut one_by_one = SparseMerkleTree::new(&hasher);
+        let empty = one_by_one.root();
+        let records: Vec<Block> = (0..200u8).map(|i| Block::new([i; 10], false)).collect();
+        for record in records.iter().rev() {
+            one_by_one.update(&hasher, [record.clone()]);
+        }
+        let mut batched = SparseMerkleTree::new(&hasher);
+        batched.update(&hasher, records.clone());
+        assert_eq!(one_by_one.root(), batched.root());
+        assert_ne!(batched.root(), empty);
+        assert_eq!(batched.len(), 200);
+
+        // revoking every record empties the tree down to its default root
+        batched.update(&hasher, (0..200u8).map(|i| Block::new([i; 10], true)));
+        assert!(batched.is_empty());
+        assert_eq!(batched.root(), empty);
+        assert!(batched.nodes.is_empty());
+    }
+
+    #[test]
+    fn test_proofs_for_occupied_and_empty_slots() {
+        let hasher = Blake3Hasher;
+        let mut tree = SparseMerkleTree::new(&hasher);
+        let records: Vec<Block> =
+            (0..50u8).map(|i| Block::with_hasher(&hasher, vec![i; 10], vec![i], false)).collect();
+        tree.update(&hasher, records.clone());
+        tree.update(&hasher, [Block::with_hasher(&hasher, vec![7; 10], Vec::new(), true)]);
+        let root = tree.root();
+
+        let record = &records[9];
+        let proof = tree.prove(&record.data);
+        // a sparse tree of 49 leaves leaves most of the 256 siblings at their default
+        assert!(proof.siblings.len() < 16);
+        assert!(proof.verify(&hasher, &root, &record.data, Some(record)));
+        assert!(!proof.verify(&hasher, &root, &record.data, None));
+        assert!(!proof.verify(&hasher, &root, &record.data, Some(&records[10])));
+
+        let revoked = records[7].data;
+        let proof = tree.prove(&revoked);
+        assert!(proof.verify(&hasher, &root, &revoked, None));
+        assert!(!proof.verify(&hasher, &root, &revoked, Some(&records[7])));
+        let unknown = hasher.digest(b"never written");
+        assert!(tree.prove(&unknown).verify(&hasher, &root, &unknown, None));
+        assert!(!tree.prove(&unknown).verify(&hasher, &root, &revoked, None));
+    }
+
+    #[test]
+    fn test_update_from_mem_table() {
+        let hasher = TruncatedSha256Hasher;
+        let mut mem_table = MemTable::new();
+        for i in 0..100u8 {
+            mem_table.insert(Block::new([i % 40; 10], i >= 90));
+        }
+        let mut tree = SparseMerkleTree::new(&hasher);
+        tree.update_from_mem_table(&hasher, &mem_table);
+        // 10..=19 were revoked by the last writes
+        let mut expected = SparseMerkleTree::new(&hasher);
+        expected.update(&hasher, (0..10u8).chain(20..40).map(|i| Block::new([i; 10], false)));
+        assert_eq!(tree.len(), 30);
+        assert_eq!(tree.root(), expected.root());
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::hasher::{BlockHasher, Digest};
+use crate::storage::engine::{Engine, EngineOps};
+
+/// Engine keys of trie nodes start with this, keeping them apart from records.
+pub const NODE_KEY_PREFIX: &[u8] = b"mpt/";
+
+/// Node of a Merkle Patricia Trie. Paths are nibbles, one per byte, values 0..16.
+/// Children are referenced by the digest of their encoding, never inlined.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TrieNode {
+    /// rest of the key below this point and the value stored under it
+    Leaf { path: Vec<u8>, value: Vec<u8> },
+    /// shared path of every key below `child`, never empty
+    Extension { path: Vec<u8>, child: Digest },
+    /// one child per next nibble, `value` is for the key ending here.
+    /// Children are boxed to keep the other variants small.
+    Branch {
+        children: Box<[Option<Digest>; 16]>,
+        value: Option<Vec<u8>>,
+    },
+}
+
+impl TrieNode {
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("trie nodes always serialize")
+    }
+
+    pub fn decode(bytes: &[u8]) -> std::result::Result<TrieNode, Error> {
+        bincode::deserialize(bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Digest a parent stores for this node.
+    pub fn hash(&self, hasher: &dyn BlockHasher) -> Digest {
+        hasher.digest(&self.encode())
+    }
+}
+
+/// Splits every byte of `key` into its high and low nibble.
+pub fn to_nibbles(key: &[u8]) -> Vec<u8> {
+    key.iter().flat_map(|b| [b >> 4, b & 0x0F]).collect()
+}
+
+pub fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+/// Content addressed storage for encoded trie nodes. Nodes are immutable once stored,
+/// so every root ever produced stays readable.
+pub trait NodeStore {
+    fn load(&self, hash: &Digest) -> std::result::Result<Option<Vec<u8>>, Error>;
+    fn store(&mut self, hash: Digest, encoded: Vec<u8>) -> std::result::Result<(), Error>;
+}
+
+/// Nodes held in memory, for tries that do not need to outlive the process.
+#[derive(Debug, Default)]
+pub struct MemoryNodeStore {
+    pub nodes: HashMap<Digest, Vec<u8>>,
+}
+
+impl NodeStore for MemoryNodeStore {
+    fn load(&self, hash: &Digest) -> std::result::Result<Option<Vec<u8>>, Error> {
+        Ok(self.nodes.get(hash).cloned())
+    }
+
+    fn store(&mut self, hash: Digest, encoded: Vec<u8>) -> std::result::Result<(), Error> {
+        self.nodes.insert(hash, encoded);
+        Ok(())
+    }
+}
+
+/// Nodes written through the engine, keyed by `NODE_KEY_PREFIX` and the node digest.
+impl NodeStore for Engine {
+    fn load(&self, hash: &Digest) -> std::result::Result<Option<Vec<u8>>, Error> {
+        self.get_value(&node_key(hash))
+    }
+
+    fn store(&mut self, hash: Digest, encoded: Vec<u8>) -> std::result::Result<(), Error> {
+        self.put(&node_key(&hash), &encoded).map(|_| ())
+    }
+}
+
+fn node_key(hash: &Digest) -> Vec<u8> {
+    [NODE_KEY_PREFIX, hash.as_bytes()].concat()
+}
@@ -1,5 +1,6 @@
 /// Datasource is used to
 /// to present layers of data storage options
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DataSource {
     RingBuffer,
     MemTable,
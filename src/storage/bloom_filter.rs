@@ -1,58 +1,92 @@
+use std::io::{Error, ErrorKind};
+
 use serde::{Deserialize, Serialize};
 use twox_hash::XxHash64;
 
-pub const BLOOM_FILTER_SIZE: usize = 19;
+use crate::core::hasher::Digest;
+use crate::core::skip_list::CAPACITY;
+
+/// default filter density, see `FilterKind::Bloom`
+pub const BLOOM_BITS_PER_KEY: usize = 10;
+/// default target false positive rate
+pub const BLOOM_FALSE_POSITIVE_RATE: f64 = 0.01;
+pub const BLOOM_SEED: u64 = 1234;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BloomFilter {
-    ///    Bloom filter size depends on:
-    ///	n = 1000 (number of elements in SSTable segment)
-    /// 	p = False positive probability (e.g., 1%)
-    /// 	m = Number of bits needed
-    /// 	k = Number of hash functions
+    /// Bloom filter size depends on:
+    /// n = number of elements in the SSTable segment
+    /// p = False positive probability (e.g., 1%)
+    /// m = Number of bits needed
+    /// k = Number of hash functions
     /// m = -(n * ln(p)) / (ln(2))²
     /// k = (m / n) * ln(2)
     /// For n = 1000 and p = 1% (0.01):
     /// m ≈ -(1000 * ln(0.01)) / (ln(2))²
     ///   ≈ (1000 * 4.6) / 0.48
-    ///   ≈ 9580 bits (≈ 1197 bytes)
-    /// k ≈ (9580 / 1000) * ln(2)
+    ///   ≈ 9586 bits (≈ 1199 bytes)
+    /// k ≈ (9586 / 1000) * ln(2)
     /// ≈ 6.64  (round to **7 hash functions**)
-    pub bits: [u8; BLOOM_FILTER_SIZE], // 150 bits
+    pub bits: Vec<u8>,
+    /// k, bit positions set per key
+    pub hash_count: u32,
+    /// seed of the base hash, filters only combine when their seeds match
+    pub seed: u64,
 }
 
 pub trait BloomFilterOps {
     fn set_bit(&mut self, index: usize);
     fn check_bit(&self, index: usize) -> bool;
-    fn add(&mut self, hashed: &[u8; 16]);
+    fn add(&mut self, hashed: &Digest);
+    /// false means `hashed` was never added, true means it probably was
+    fn may_contain(&self, hashed: &Digest) -> bool;
+    /// Adds every key of `other`, e.g. to build the filter of a merged segment without rehashing.
+    /// Both filters need the same size, hash count and seed.
+    fn union(&mut self, other: &BloomFilter) -> std::result::Result<(), Error>;
 }
 impl BloomFilter {
+    /// Filter for a full memtable at the default false positive rate.
     pub fn new() -> Self {
-        BloomFilter { bits: [0; BLOOM_FILTER_SIZE] }
-    }
-
-    /// 7 Hash functions used for Bloom Filter:
-    /// We use one base hash functions and derive 7 hash functions from it.
-    /// 1. xxHash-derived
-    /// 2. CityHash-derived
-    /// 3. FNV-1a variation
-    /// 4. SipHash-derived
-    /// 5. WyHash-derived
-    /// 6. MetroHash-derived
-    /// 7. xxHash-derived
-    fn hash(&self, hashed: &[u8; 16], filter_size: usize) -> [usize; 7] {
-        // FIXME: Seed is hardcoded - needs to be configurable
-        let base_hash = XxHash64::oneshot(1234, hashed);
-        return [
-            (base_hash >> 32) as usize % filter_size,
-            (base_hash & 0xFFFFFFFF) as usize % filter_size, // xxHash-derived
-            ((base_hash >> 16) & 0xFFFFFFFF) as usize % filter_size, // CityHash-derived
-            ((base_hash.wrapping_mul(0x5bd1e995) >> 24) & 0xFFFFFFFF) as usize % filter_size, // FNV-1a variation
-            ((base_hash.wrapping_add(0x9e3779b97f4a7c15)) & 0xFFFFFFFF) as usize % filter_size, // SipHash-derived
-            ((base_hash.wrapping_mul(0xc6a4a7935bd1e995) >> 17) & 0xFFFFFFFF) as usize
-                % filter_size, // WyHash-derived
-            ((base_hash.wrapping_add(0xdaba0b6eb09322e3)) & 0xFFFFFFFF) as usize % filter_size, // MetroHash-derived
-        ];
+        BloomFilter::with_rate(CAPACITY, BLOOM_FALSE_POSITIVE_RATE)
+    }
+
+    /// Smallest filter keeping the false positive rate at `false_positive_rate`
+    /// once `keys` keys were added.
+    pub fn with_rate(keys: usize, false_positive_rate: f64) -> Self {
+        let p = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let m = (-(keys.max(1) as f64) * p.ln() / (2f64.ln() * 2f64.ln())).ceil() as usize;
+        BloomFilter::with_bits(m, keys)
+    }
+
+    /// Filter for `keys` keys with `bits_per_key` bits each.
+    pub fn with_capacity(keys: usize, bits_per_key: usize) -> Self {
+        BloomFilter::with_bits(keys.max(1) * bits_per_key, keys)
+    }
+
+    /// Same filter hashing with `seed`, only useful before anything was added.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// `m` bits with the k that minimizes false positives for `keys` keys.
+    fn with_bits(m: usize, keys: usize) -> Self {
+        let m = m.max(8);
+        let k = (m as f64 / keys.max(1) as f64 * 2f64.ln()).round().clamp(1.0, 30.0);
+        BloomFilter {
+            bits: vec![0; m.div_ceil(8)],
+            hash_count: k as u32,
+            seed: BLOOM_SEED,
+        }
+    }
+
+    /// Bit positions of `hashed`, derived from one 64 bit hash by double hashing:
+    /// position i = h1 + i * h2 (mod m), with h1 and h2 its two halves.
+    fn hash(&self, hashed: &Digest) -> impl Iterator<Item = usize> {
+        let filter_size = (self.bits.len() * 8) as u64;
+        let base_hash = XxHash64::oneshot(self.seed, hashed.as_bytes());
+        let (h1, h2) = (base_hash & 0xFFFFFFFF, (base_hash >> 32) | 1);
+        (0..self.hash_count as u64).map(move |i| (h1.wrapping_add(i * h2) % filter_size) as usize)
     }
 }
 impl BloomFilterOps for BloomFilter {
@@ -68,13 +102,32 @@ impl BloomFilterOps for BloomFilter {
         return self.bits[byte_offset] & (1 << bit_offset) != 0; // check bit
     }
 
-    // Uses 7 hash functions to set bits in the Bloom Filter
-    fn add(&mut self, hashed: &[u8; 16]) {
-        let hashed_indices = self.hash(hashed, BLOOM_FILTER_SIZE);
-        for index in hashed_indices {
+    // Sets `hash_count` bits in the Bloom Filter
+    fn add(&mut self, hashed: &Digest) {
+        for index in self.hash(hashed) {
             self.set_bit(index);
         }
     }
+
+    fn may_contain(&self, hashed: &Digest) -> bool {
+        self.hash(hashed).all(|index| self.check_bit(index))
+    }
+
+    fn union(&mut self, other: &BloomFilter) -> std::result::Result<(), Error> {
+        if self.bits.len() != other.bits.len()
+            || self.hash_count != other.hash_count
+            || self.seed != other.seed
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "bloom filters differ in size, hash count or seed",
+            ));
+        }
+        for (bits, other) in self.bits.iter_mut().zip(&other.bits) {
+            *bits |= other;
+        }
+        Ok(())
+    }
 }
 
 impl Serialize for BloomFilter {
@@ -82,7 +135,8 @@ impl Serialize for BloomFilter {
     where
         S: serde::Serializer,
     {
-        self.bits.serialize(serializer)
+        // bits as a length prefixed sequence, matching the `Vec<u8>` read back in `deserialize`
+        (self.bits.as_slice(), self.hash_count, self.seed).serialize(serializer)
     }
 }
 
@@ -91,15 +145,86 @@ impl<'de> Deserialize<'de> for BloomFilter {
     where
         D: serde::Deserializer<'de>,
     {
-        let bytes: Vec<u8> = Deserialize::deserialize(deserializer)?;
-        if bytes.len() != BLOOM_FILTER_SIZE {
+        let (bits, hash_count, seed): (Vec<u8>, u32, u64) = Deserialize::deserialize(deserializer)?;
+        if bits.is_empty() || hash_count == 0 {
             return Err(serde::de::Error::custom(format!(
-                "Invalid BloomFilter size: {}",
-                bytes.len()
+                "Invalid BloomFilter: {} bytes, {} hashes",
+                bits.len(),
+                hash_count
             )));
         }
-        let mut bits = [0u8; BLOOM_FILTER_SIZE];
-        bits.copy_from_slice(&bytes);
-        Ok(BloomFilter { bits })
+        Ok(BloomFilter { bits, hash_count, seed })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::hasher::{BlockHasher, TruncatedSha256Hasher};
+
+    fn key(i: u32) -> Digest {
+        TruncatedSha256Hasher.digest(&i.to_be_bytes())
+    }
+
+    #[test]
+    fn test_may_contain_added_keys() {
+        let mut bf = BloomFilter::new();
+        for i in 0..20u8 {
+            bf.add(&Digest::from([i; 16]));
+        }
+        // no false negatives
+        assert!((0..20u8).all(|i| bf.may_contain(&Digest::from([i; 16]))));
+        assert!(!BloomFilter::new().may_contain(&Digest::from([1; 16])));
+    }
+
+    #[test]
+    fn test_sized_from_entry_count_and_rate() {
+        let bf = BloomFilter::with_rate(1000, 0.01);
+        assert_eq!(bf.bits.len(), 1199);
+        assert_eq!(bf.hash_count, 7);
+        let bf = BloomFilter::with_rate(1000, 0.001);
+        assert_eq!(bf.hash_count, 10);
+        assert_eq!(BloomFilter::with_capacity(1000, 10).bits.len(), 1250);
+    }
+
+    #[test]
+    fn test_empirical_false_positive_rate() {
+        for rate in [0.01, 0.05] {
+            let mut bf = BloomFilter::with_rate(1000, rate);
+            for i in 0..1000 {
+                bf.add(&key(i));
+            }
+            let probes = 100_000;
+            let false_positives =
+                (1000..1000 + probes).filter(|i| bf.may_contain(&key(*i))).count();
+            let measured = false_positives as f64 / probes as f64;
+            assert!(measured < rate * 1.5, "{} false positives at target {}", measured, rate);
+        }
+    }
+
+    #[test]
+    fn test_seed_and_union() {
+        let mut left = BloomFilter::with_rate(100, 0.01);
+        let mut right = BloomFilter::with_rate(100, 0.01);
+        let mut reseeded = BloomFilter::with_rate(100, 0.01).with_seed(99);
+        for i in 0..50 {
+            left.add(&key(i));
+            right.add(&key(i + 50));
+            reseeded.add(&key(i));
+        }
+        assert_ne!(left.bits, reseeded.bits);
+        assert!((0..50).all(|i| reseeded.may_contain(&key(i))));
+        assert!(left.union(&reseeded).is_err());
+        assert!(left.union(&BloomFilter::with_rate(1000, 0.01)).is_err());
+
+        left.union(&right).unwrap();
+        assert!((0..100).all(|i| left.may_contain(&key(i))));
+        let mut both = BloomFilter::with_rate(100, 0.01);
+        (0..100).for_each(|i| both.add(&key(i)));
+        assert_eq!(left, both);
+
+        let bytes = bincode::serialize(&reseeded).unwrap();
+        let decoded: BloomFilter = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded, reseeded);
     }
 }
@@ -0,0 +1,496 @@
+use std::collections::BTreeMap;
+use std::io::Error;
+
+use crate::core::hasher::Digest;
+use crate::core::skip_list::{SkipNode, CAPACITY};
+use crate::storage::membership_filter::MembershipFilter;
+use crate::storage::ss_table_reader::{SSTableReader, SSTableReaderOps};
+
+/// How segments are grouped for merging, chosen when the engine is opened.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionStrategy {
+    /// Merges runs of adjacent segments whose entry counts are within
+    /// `bucket_low..=bucket_high` times the run average, once `min_threshold` of them piled up.
+    SizeTiered {
+        min_threshold: usize,
+        max_threshold: usize,
+        bucket_low: f64,
+        bucket_high: f64,
+    },
+    /// Level 0 collects flushed segments which may overlap. Once it holds `level0_limit`
+    /// segments they are merged into level 1. Every deeper level holds non overlapping segments
+    /// of `target_segment_entries` and may grow to `base_level_entries * fanout^(level - 1)`.
+    Leveled {
+        level0_limit: usize,
+        fanout: usize,
+        base_level_entries: usize,
+        target_segment_entries: usize,
+    },
+}
+
+impl CompactionStrategy {
+    pub fn size_tiered() -> Self {
+        CompactionStrategy::SizeTiered {
+            min_threshold: 4,
+            max_threshold: 32,
+            bucket_low: 0.5,
+            bucket_high: 1.5,
+        }
+    }
+
+    pub fn leveled() -> Self {
+        CompactionStrategy::Leveled {
+            level0_limit: 4,
+            fanout: 10,
+            base_level_entries: 10 * CAPACITY,
+            target_segment_entries: CAPACITY,
+        }
+    }
+
+    /// Rejects parameters the pickers can not make progress with,
+    /// a single segment run or an empty level 0 would be picked over and over.
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            CompactionStrategy::SizeTiered { min_threshold, max_threshold, .. } => {
+                if min_threshold < 2 {
+                    return Err("min_threshold must be at least 2".to_string());
+                }
+                if max_threshold < min_threshold {
+                    return Err("max_threshold must not be below min_threshold".to_string());
+                }
+            },
+            CompactionStrategy::Leveled {
+                level0_limit, fanout, base_level_entries, ..
+            } => {
+                if level0_limit < 1 {
+                    return Err("level0_limit must be at least 1".to_string());
+                }
+                if fanout < 2 {
+                    return Err("fanout must be at least 2".to_string());
+                }
+                if base_level_entries < 1 {
+                    return Err("base_level_entries must be at least 1".to_string());
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CompactionOptions {
+    pub strategy: CompactionStrategy,
+    /// Tombstones at least this many millis old are dropped, 0 drops them on the first merge,
+    /// as long as no older segment may still hold the key they delete.
+    pub tombstone_horizon_millis: i64,
+}
+
+impl Default for CompactionOptions {
+    fn default() -> Self {
+        CompactionOptions {
+            strategy: CompactionStrategy::size_tiered(),
+            tombstone_horizon_millis: 24 * 60 * 60 * 1000,
+        }
+    }
+}
+
+/// Segments picked for one merge.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionTask {
+    /// indices into the engine segments, which are kept in read order oldest first
+    pub inputs: Vec<usize>,
+    pub output_level: u8,
+}
+
+pub trait CompactionOps {
+    /// Next merge worth doing, if any. `segments` are ordered oldest first:
+    /// deeper levels before shallower ones, then by `created_at`.
+    fn pick(&self, segments: &[SSTableReader]) -> Option<CompactionTask>;
+    /// Merges `inputs` (oldest first) keeping the newest entry per key, plus the newest entry
+    /// visible to each of the live `snapshots` sequence numbers (ascending).
+    /// `older` are the segments read after the inputs, a tombstone past the horizon is only
+    /// dropped when it is the sole entry left for its key and none of them may contain the key.
+    /// Decodes every entry of the inputs, the only reader besides the accumulator check to do so.
+    fn merge(
+        &self, inputs: &[&SSTableReader], older: &[&SSTableReader], snapshots: &[u64], now: i64,
+    ) -> std::result::Result<Vec<SkipNode>, Error>;
+    /// Splits merged entries into the segments to write.
+    fn split(&self, entries: Vec<SkipNode>) -> Vec<Vec<SkipNode>>;
+    /// Where the merged segments go once the inputs were removed,
+    /// `first_input` is the smallest input index.
+    fn insert_position(
+        &self, remaining: &[SSTableReader], first_input: usize, output_level: u8,
+    ) -> usize;
+}
+
+impl CompactionOps for CompactionOptions {
+    fn pick(&self, segments: &[SSTableReader]) -> Option<CompactionTask> {
+        match self.strategy {
+            CompactionStrategy::SizeTiered {
+                min_threshold,
+                max_threshold,
+                bucket_low,
+                bucket_high,
+            } => pick_size_tiered(segments, min_threshold, max_threshold, bucket_low, bucket_high),
+            CompactionStrategy::Leveled {
+                level0_limit, fanout, base_level_entries, ..
+            } => pick_leveled(segments, level0_limit, fanout, base_level_entries),
+        }
+    }
+
+    fn merge(
+        &self, inputs: &[&SSTableReader], older: &[&SSTableReader], snapshots: &[u64], now: i64,
+    ) -> std::result::Result<Vec<SkipNode>, Error> {
+        let mut versions: BTreeMap<Digest, Vec<(SkipNode, i64)>> = BTreeMap::new();
+        for segment in inputs {
+            for entry in segment.entries()? {
+                versions
+                    .entry(entry.data)
+                    .or_default()
+                    .push((entry, segment.meta_block.created_at));
+            }
+        }
+        let mut merged = Vec::new();
+        for (key, mut versions) in versions {
+            // stable, inputs are oldest first for entries written before sequence numbers
+            versions.sort_by_key(|(entry, _)| entry.seq);
+            // a version survives if it is the newest one or a snapshot sees it
+            let kept: Vec<(SkipNode, i64)> = (0..versions.len())
+                .filter(|&i| match versions.get(i + 1) {
+                    None => true,
+                    Some((next, _)) => {
+                        snapshots.iter().any(|&s| versions[i].0.seq <= s && s < next.seq)
+                    },
+                })
+                .map(|i| versions[i].clone())
+                .collect();
+            if let [(entry, created_at)] = &kept[..] {
+                if entry.tombstone
+                    && now - created_at >= self.tombstone_horizon_millis
+                    && !older.iter().any(|s| may_contain(s, &key))
+                {
+                    continue;
+                }
+            }
+            merged.extend(kept.into_iter().map(|(entry, _)| entry));
+        }
+        Ok(merged)
+    }
+
+    fn split(&self, entries: Vec<SkipNode>) -> Vec<Vec<SkipNode>> {
+        match self.strategy {
+            CompactionStrategy::SizeTiered { .. } => vec![entries],
+            CompactionStrategy::Leveled { target_segment_entries, .. } => {
+                let mut chunks = Vec::new();
+                let mut chunk: Vec<SkipNode> = Vec::new();
+                for entry in entries {
+                    // versions of one key stay in the same segment
+                    if chunk.len() >= target_segment_entries
+                        && chunk.last().is_some_and(|last| last.data != entry.data)
+                    {
+                        chunks.push(std::mem::take(&mut chunk));
+                    }
+                    chunk.push(entry);
+                }
+                chunks.push(chunk);
+                chunks
+            },
+        }
+    }
+
+    fn insert_position(
+        &self, remaining: &[SSTableReader], first_input: usize, output_level: u8,
+    ) -> usize {
+        match self.strategy {
+            // inputs were adjacent, the merged segment takes their place
+            CompactionStrategy::SizeTiered { .. } => first_input,
+            // in front of the first segment of the same or a shallower level
+            CompactionStrategy::Leveled { .. } => remaining
+                .iter()
+                .position(|s| s.meta_block.level <= output_level)
+                .unwrap_or(remaining.len()),
+        }
+    }
+}
+
+fn may_contain(segment: &SSTableReader, key: &Digest) -> bool {
+    *key >= segment.footer.min_key
+        && *key <= segment.footer.max_key
+        && segment.filter.may_contain(key)
+}
+
+fn overlaps(a: &SSTableReader, b: &SSTableReader) -> bool {
+    a.footer.min_key <= b.footer.max_key && b.footer.min_key <= a.footer.max_key
+}
+
+fn pick_size_tiered(
+    segments: &[SSTableReader], min_threshold: usize, max_threshold: usize, bucket_low: f64,
+    bucket_high: f64,
+) -> Option<CompactionTask> {
+    // only adjacent segments are merged so the result can take their place in read order
+    let mut start = 0;
+    while start < segments.len() {
+        let mut end = start + 1;
+        let mut total = segments[start].meta_block.entry_count as usize;
+        while end < segments.len() && end - start < max_threshold {
+            let size = segments[end].meta_block.entry_count as f64;
+            let average = total as f64 / (end - start) as f64;
+            if size < average * bucket_low || size > average * bucket_high {
+                break;
+            }
+            total += size as usize;
+            end += 1;
+        }
+        if end - start >= min_threshold {
+            return Some(CompactionTask {
+                inputs: (start..end).collect(),
+                output_level: 0,
+            });
+        }
+        start += 1;
+    }
+    None
+}
+
+fn pick_leveled(
+    segments: &[SSTableReader], level0_limit: usize, fanout: usize, base_level_entries: usize,
+) -> Option<CompactionTask> {
+    let level0: Vec<usize> =
+        (0..segments.len()).filter(|&i| segments[i].meta_block.level == 0).collect();
+    if level0.len() >= level0_limit {
+        // level 0 segments overlap each other, their merge spans the whole union
+        let min_key = level0.iter().map(|&i| segments[i].footer.min_key).min().unwrap();
+        let max_key = level0.iter().map(|&i| segments[i].footer.max_key).max().unwrap();
+        let mut inputs: Vec<usize> = (0..segments.len())
+            .filter(|&i| {
+                segments[i].meta_block.level == 1
+                    && segments[i].footer.min_key <= max_key
+                    && min_key <= segments[i].footer.max_key
+            })
+            .collect();
+        inputs.extend(level0);
+        return Some(CompactionTask { inputs, output_level: 1 });
+    }
+    let deepest = segments.iter().map(|s| s.meta_block.level).max().unwrap_or(0);
+    let mut budget = base_level_entries;
+    for level in 1..=deepest {
+        let members: Vec<usize> =
+            (0..segments.len()).filter(|&i| segments[i].meta_block.level == level).collect();
+        let entries: usize =
+            members.iter().map(|&i| segments[i].meta_block.entry_count as usize).sum();
+        if entries > budget {
+            // push the oldest segment of the level one level down
+            let victim =
+                *members.iter().min_by_key(|&&i| segments[i].meta_block.created_at).unwrap();
+            let mut inputs: Vec<usize> = (0..segments.len())
+                .filter(|&i| {
+                    segments[i].meta_block.level == level + 1
+                        && overlaps(&segments[i], &segments[victim])
+                })
+                .collect();
+            inputs.push(victim);
+            return Some(CompactionTask { inputs, output_level: level + 1 });
+        }
+        budget = budget.saturating_mul(fanout);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::core::block::sha_hash;
+    use crate::storage::engine::{Engine, EngineOps};
+    use crate::storage::membership_filter::FilterKind;
+    use crate::storage::ss_table::{SSTableSegment, SSTableSegmentOps};
+    use crate::storage::test_dir;
+
+    fn phone(i: u16) -> [u8; 10] {
+        let mut phone = [0u8; 10];
+        phone[..2].copy_from_slice(&i.to_be_bytes());
+        phone
+    }
+
+    fn size_tiered(min_threshold: usize, horizon: i64) -> CompactionOptions {
+        CompactionOptions {
+            strategy: CompactionStrategy::SizeTiered {
+                min_threshold,
+                max_threshold: 32,
+                bucket_low: 0.5,
+                bucket_high: 1.5,
+            },
+            tombstone_horizon_millis: horizon,
+        }
+    }
+
+    #[test]
+    fn test_size_tiered_merges_similar_segments() {
+        let dir = test_dir("compaction-size-tiered");
+        let mut engine = Engine::open_with(&dir, size_tiered(2, 0)).unwrap();
+        for i in 0..2000 {
+            engine.add(phone(i)).unwrap();
+        }
+        assert_eq!(engine.segments.len(), 1);
+        assert_eq!(engine.segments[0].meta_block.entry_count, 2000);
+        assert!((0..2000).all(|i| engine.get(sha_hash(&phone(i))).unwrap().is_some()));
+        // inputs are gone from disk
+        let files = fs::read_dir(&dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension().is_some_and(|x| x == "segment"));
+        assert_eq!(files.count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_shadowed_entries_and_expired_tombstones_are_dropped() {
+        let dir = test_dir("compaction-tombstones");
+        let mut engine = Engine::open_with(&dir, size_tiered(2, 0)).unwrap();
+        for i in 0..10 {
+            engine.add(phone(i)).unwrap();
+        }
+        engine.flush().unwrap();
+        for i in 0..10 {
+            engine.delete(phone(i)).unwrap();
+        }
+        engine.flush().unwrap();
+
+        // both segments merged, every key was deleted and nothing older remains
+        assert!(engine.segments.is_empty());
+        assert!(engine.get(sha_hash(&phone(3))).unwrap().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_tombstones_within_horizon_are_kept() {
+        let dir = test_dir("compaction-tombstone-horizon");
+        let mut engine = Engine::open_with(&dir, size_tiered(2, 60_000)).unwrap();
+        for i in 0..10 {
+            engine.add(phone(i)).unwrap();
+        }
+        engine.flush().unwrap();
+        engine.delete(phone(3)).unwrap();
+        for i in 10..19 {
+            engine.add(phone(i)).unwrap();
+        }
+        engine.flush().unwrap();
+
+        assert_eq!(engine.segments.len(), 1);
+        let entries = engine.segments[0].entries().unwrap();
+        // one entry per key, the tombstone shadows the older live entry
+        assert_eq!(entries.len(), 19);
+        let hashed = sha_hash(&phone(3));
+        assert!(entries.iter().find(|e| e.data == hashed).unwrap().tombstone);
+        assert!(engine.get(hashed).unwrap().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_tombstone_kept_while_older_segment_holds_key() {
+        let dir = test_dir("compaction-tombstone-older");
+        let entry = |i: u16, tombstone: bool, seq: u64| SkipNode {
+            tombstone,
+            data: sha_hash(&phone(i)).into(),
+            seq,
+            payload: None,
+        };
+        let mut large: Vec<SkipNode> = (0..100).map(|i| entry(i, false, i as u64)).collect();
+        large.sort_by_key(|e| e.data);
+        // explicit created_at keeps the segments in write order
+        let create = |entries, created_at| {
+            let segment = SSTableSegment::create_from_entries(
+                &dir,
+                entries,
+                0,
+                created_at,
+                FilterKind::default(),
+            )
+            .unwrap();
+            SSTableReader::open(&segment.path).unwrap()
+        };
+        let segments = [
+            create(large, 1),
+            create(vec![entry(3, true, 100)], 2),
+            create(vec![entry(500, false, 101)], 3),
+        ];
+
+        // the two small segments are picked, the large one is too big for their bucket
+        let options = size_tiered(2, 0);
+        let task = options.pick(&segments).unwrap();
+        assert_eq!(task.inputs, vec![1, 2]);
+        let inputs: Vec<&SSTableReader> = task.inputs.iter().map(|&i| &segments[i]).collect();
+        let merged = options.merge(&inputs, &[&segments[0]], &[], 10).unwrap();
+        assert_eq!(merged.len(), 2);
+        assert!(merged.iter().find(|e| e.data == sha_hash(&phone(3))).unwrap().tombstone);
+
+        // with nothing older left the tombstone is dropped
+        assert_eq!(options.merge(&inputs, &[], &[], 10).unwrap().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_degenerate_parameters_are_rejected() {
+        let dir = test_dir("compaction-validate");
+        let leveled = |level0_limit, fanout, base_level_entries| CompactionStrategy::Leveled {
+            level0_limit,
+            fanout,
+            base_level_entries,
+            target_segment_entries: 500,
+        };
+        assert!(size_tiered(1, 0).strategy.validate().is_err());
+        assert!(size_tiered(33, 0).strategy.validate().is_err());
+        assert!(leveled(0, 10, 1500).validate().is_err());
+        assert!(leveled(4, 1, 1500).validate().is_err());
+        assert!(leveled(4, 10, 0).validate().is_err());
+        assert!(size_tiered(2, 0).strategy.validate().is_ok());
+        assert!(CompactionStrategy::leveled().validate().is_ok());
+
+        let error = Engine::open_with(&dir, size_tiered(1, 0)).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_leveled_keeps_levels_non_overlapping() {
+        let dir = test_dir("compaction-leveled");
+        let options = CompactionOptions {
+            strategy: CompactionStrategy::Leveled {
+                level0_limit: 2,
+                fanout: 2,
+                base_level_entries: 1500,
+                target_segment_entries: 500,
+            },
+            tombstone_horizon_millis: 0,
+        };
+        let mut engine = Engine::open_with(&dir, options).unwrap();
+        for i in 0..6000 {
+            engine.add(phone(i)).unwrap();
+        }
+        engine.delete(phone(42)).unwrap();
+        engine.flush().unwrap();
+
+        let check = |engine: &Engine| {
+            let deepest = engine.segments.iter().map(|s| s.meta_block.level).max().unwrap();
+            assert!(deepest >= 2);
+            for level in 1..=deepest {
+                let members: Vec<_> =
+                    engine.segments.iter().filter(|s| s.meta_block.level == level).collect();
+                for (i, a) in members.iter().enumerate() {
+                    assert!(members[i + 1..].iter().all(|b| !overlaps(a, b)));
+                }
+            }
+            // read order keeps deeper levels first
+            let levels: Vec<_> = engine.segments.iter().map(|s| s.meta_block.level).collect();
+            assert!(levels.windows(2).all(|w| w[0] >= w[1]));
+            assert!((0..6000)
+                .filter(|&i| i != 42)
+                .all(|i| engine.get(sha_hash(&phone(i))).unwrap().is_some()));
+            assert!(engine.get(sha_hash(&phone(42))).unwrap().is_none());
+        };
+        check(&engine);
+        drop(engine);
+        check(&Engine::open_with(&dir, options).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
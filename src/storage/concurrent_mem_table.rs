@@ -0,0 +1,364 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::core::accumulator::MultisetHash;
+use crate::core::block::Block;
+use crate::core::hasher::Digest;
+use crate::core::skip_list::{SkipList, SkipListOps, SkipNode};
+use crate::storage::options::Options;
+use crate::storage::ss_table::{SSTableSegment, SSTableSegmentOps};
+
+/// Independent skip lists in a memtable, a key always lands in the same one.
+pub const SHARDS: usize = 16;
+
+/// One generation of memtable data, split over `SHARDS` skip lists by the first key byte.
+/// Writers to different shards never wait on each other, readers only wait for a writer
+/// of their own shard.
+pub struct Shards {
+    shards: Vec<RwLock<Shard>>,
+    /// entries across all shards, reserved before an insert so the total stays within `capacity`
+    len: AtomicUsize,
+    capacity: usize,
+}
+
+struct Shard {
+    skip_list: SkipList,
+    accumulator: MultisetHash,
+}
+
+/// Memtable shared between threads, every method takes `&self`.
+/// Inserts go to the active generation. `freeze` swaps in an empty one and keeps the old
+/// one readable as an immutable memtable until it has been flushed, so writing a segment
+/// never holds up inserts.
+pub struct ConcurrentMemTable {
+    /// writers hold the read side while inserting, `freeze` takes the write side to swap
+    active: RwLock<Arc<Shards>>,
+    /// frozen generations waiting for their flush, oldest first
+    immutable: RwLock<Vec<Arc<Shards>>>,
+    options: Options,
+}
+
+pub trait ConcurrentMemTableOps: Sized {
+    fn new() -> Self;
+    /// Inserts into the active memtable, returns false once it holds `mem_table_capacity`
+    /// entries and has to be frozen first.
+    fn insert(&self, block: Block) -> bool;
+    /// Entries in the active memtable.
+    fn size(&self) -> usize;
+    fn search(&self, key: Digest) -> Option<Block>;
+    /// Latest entry for `key` written at or before `seq`, active memtable first,
+    /// then the immutable ones newest to oldest.
+    fn search_at(&self, key: Digest, seq: u64) -> Option<Block>;
+    /// Hands the active memtable over for flushing and starts an empty one.
+    /// Returns None when there was nothing to freeze.
+    fn freeze(&self) -> Option<Arc<Shards>>;
+    /// Immutable memtables waiting for their flush, oldest first.
+    fn immutable(&self) -> Vec<Arc<Shards>>;
+    /// Persists a frozen memtable as a level 0 segment in `dir` and stops serving reads from it.
+    fn flush(&self, frozen: &Arc<Shards>, dir: &Path)
+        -> std::result::Result<SSTableSegment, Error>;
+}
+
+impl Shards {
+    fn new(options: &Options) -> Self {
+        let capacity = options.mem_table_capacity;
+        // an even share each, a shard grows when the keys are spread unevenly,
+        // nothing is pinned as several generations may wait for their flush at once
+        let share = capacity.div_ceil(SHARDS);
+        Shards {
+            shards: (0..SHARDS)
+                .map(|_| {
+                    RwLock::new(Shard {
+                        skip_list: SkipList::unpinned(share, options.max_level),
+                        accumulator: MultisetHash::new(),
+                    })
+                })
+                .collect(),
+            len: AtomicUsize::new(0),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, key: &Digest) -> &RwLock<Shard> {
+        let first = key.as_bytes().first().copied().unwrap_or(0);
+        &self.shards[first as usize % SHARDS]
+    }
+
+    fn insert(&self, block: Block) -> bool {
+        let reserved = self.len.fetch_update(Ordering::AcqRel, Ordering::Acquire, |len| {
+            (len < self.capacity).then_some(len + 1)
+        });
+        if reserved.is_err() {
+            return false;
+        }
+        let mut shard = self.shard(&block.data).write().unwrap();
+        if shard.skip_list.size() == shard.skip_list.capacity() {
+            // the reservation keeps every shard below `capacity`, so growing always makes room
+            let grown = (shard.skip_list.capacity() * 2).min(self.capacity);
+            shard.skip_list.grow(grown);
+        }
+        shard.accumulator.insert(&block);
+        shard.skip_list.insert(SkipNode::from(block))
+    }
+
+    fn search_at(&self, key: Digest, seq: u64) -> Option<Block> {
+        self.shard(&key).read().unwrap().skip_list.search_at(key, seq).map(Block::from)
+    }
+
+    /// Every entry in key order, versions of a key oldest first.
+    pub fn entries(&self) -> Vec<SkipNode> {
+        let mut entries = Vec::with_capacity(self.len());
+        for shard in &self.shards {
+            entries.extend(shard.read().unwrap().skip_list.iter().cloned());
+        }
+        // every shard is sorted already, the sort only merges the runs
+        entries.sort_by(|a, b| a.data.cmp(&b.data).then(a.seq.cmp(&b.seq)));
+        entries
+    }
+
+    /// Multiset hash of every entry, see `MemTable::accumulator`.
+    pub fn accumulator(&self) -> MultisetHash {
+        let mut accumulator = MultisetHash::new();
+        for shard in &self.shards {
+            accumulator.combine(&shard.read().unwrap().accumulator);
+        }
+        accumulator
+    }
+}
+
+impl ConcurrentMemTableOps for ConcurrentMemTable {
+    fn new() -> Self {
+        ConcurrentMemTable::with_options(Options::default())
+    }
+
+    fn insert(&self, block: Block) -> bool {
+        self.active.read().unwrap().insert(block)
+    }
+
+    fn size(&self) -> usize {
+        self.active.read().unwrap().len()
+    }
+
+    fn search(&self, key: Digest) -> Option<Block> {
+        self.search_at(key, u64::MAX)
+    }
+
+    fn search_at(&self, key: Digest, seq: u64) -> Option<Block> {
+        let active = self.active.read().unwrap().clone();
+        if let Some(block) = active.search_at(key, seq) {
+            return Some(block);
+        }
+        let immutable = self.immutable.read().unwrap().clone();
+        immutable.iter().rev().find_map(|frozen| frozen.search_at(key, seq))
+    }
+
+    fn freeze(&self) -> Option<Arc<Shards>> {
+        // built before taking the lock, inserts only wait for the swap itself
+        let fresh = Arc::new(Shards::new(&self.options));
+        let mut active = self.active.write().unwrap();
+        if active.is_empty() {
+            return None;
+        }
+        let frozen = std::mem::replace(&mut *active, fresh);
+        // still under the write lock, a reader never misses the frozen entries
+        self.immutable.write().unwrap().push(frozen.clone());
+        Some(frozen)
+    }
+
+    fn immutable(&self) -> Vec<Arc<Shards>> {
+        self.immutable.read().unwrap().clone()
+    }
+
+    fn flush(
+        &self, frozen: &Arc<Shards>, dir: &Path,
+    ) -> std::result::Result<SSTableSegment, Error> {
+        let entries = frozen.entries();
+        let segment = SSTableSegment::create_from_entries(
+            dir,
+            entries,
+            0,
+            chrono::Utc::now().timestamp_millis(),
+            self.options.filter,
+        )?;
+        if segment.meta_block.accumulator != frozen.accumulator() {
+            // not in the manifest yet, a file left behind is removed as an orphan on open
+            let _ = fs::remove_file(&segment.path);
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("segment {:?} does not hold what the memtable accumulated", segment.path),
+            ));
+        }
+        self.immutable.write().unwrap().retain(|f| !Arc::ptr_eq(f, frozen));
+        Ok(segment)
+    }
+}
+
+impl ConcurrentMemTable {
+    /// Memtable whose generations are sized by `options`.
+    pub fn with_options(options: Options) -> Self {
+        ConcurrentMemTable {
+            active: RwLock::new(Arc::new(Shards::new(&options))),
+            immutable: RwLock::new(Vec::new()),
+            options,
+        }
+    }
+}
+
+impl Default for ConcurrentMemTable {
+    fn default() -> Self {
+        ConcurrentMemTable::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU64;
+    use std::thread;
+
+    use super::*;
+    use crate::core::hasher::{BlockHasher, TruncatedSha256Hasher};
+    use crate::core::skip_list::CAPACITY;
+    use crate::storage::test_dir;
+
+    fn block(i: u64, seq: u64) -> Block {
+        let mut block =
+            Block::with_payload(i.to_be_bytes().to_vec(), seq.to_be_bytes().to_vec(), false);
+        block.seq = seq;
+        block
+    }
+
+    fn key(i: u64) -> Digest {
+        TruncatedSha256Hasher.digest(&i.to_be_bytes())
+    }
+
+    #[test]
+    fn test_concurrent_writers_and_readers() {
+        let mem_table = ConcurrentMemTable::new();
+        let sequence = AtomicU64::new(0);
+        thread::scope(|scope| {
+            for writer in 0..8u64 {
+                let (mem_table, sequence) = (&mem_table, &sequence);
+                scope.spawn(move || {
+                    // every writer rewrites the same 25 keys four times
+                    for i in 0..100u64 {
+                        let seq = sequence.fetch_add(1, Ordering::SeqCst) + 1;
+                        assert!(mem_table.insert(block(writer * 25 + i % 25, seq)));
+                    }
+                });
+            }
+            for _ in 0..4 {
+                let mem_table = &mem_table;
+                scope.spawn(move || {
+                    for i in 0..2000u64 {
+                        if let Some(found) = mem_table.search(key(i % 200)) {
+                            assert_eq!(found.key(), Some(&(i % 200).to_be_bytes()[..]));
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(mem_table.size(), 800);
+        // the highest seq of every key wins, whichever thread got the shard lock first
+        let entries = mem_table.active.read().unwrap().entries();
+        assert!(entries.windows(2).all(|w| (w[0].data, w[0].seq) < (w[1].data, w[1].seq)));
+        for i in 0..200u64 {
+            let latest = mem_table.search(key(i)).unwrap();
+            let newest = entries.iter().filter(|e| e.data == key(i)).map(|e| e.seq).max();
+            assert_eq!(Some(latest.seq), newest);
+        }
+    }
+
+    #[test]
+    fn test_frozen_memtable_stays_readable_until_flushed() {
+        let dir = test_dir("concurrent-memtable");
+        let mem_table = ConcurrentMemTable::new();
+        for i in 0..CAPACITY as u64 {
+            assert!(mem_table.insert(block(i, i + 1)));
+        }
+        assert!(!mem_table.insert(block(5000, 5000)));
+        let frozen = mem_table.freeze().unwrap();
+        assert_eq!(mem_table.size(), 0);
+        assert!(mem_table.freeze().is_none());
+
+        thread::scope(|scope| {
+            // inserts keep going while the frozen memtable is written out
+            let writer = scope.spawn(|| {
+                for i in 0..500u64 {
+                    assert!(mem_table.insert(block(i, 2000 + i)));
+                }
+            });
+            let segment = mem_table.flush(&frozen, &dir).unwrap();
+            assert_eq!(segment.data_block.entries.len(), CAPACITY);
+            assert!(segment.verify_accumulator());
+            writer.join().unwrap();
+        });
+        assert!(mem_table.immutable().is_empty());
+        assert_eq!(mem_table.search(key(7)).unwrap().seq, 2007);
+        // older versions were in the frozen memtable only, now they are in the segment
+        assert!(mem_table.search_at(key(7), 1000).is_none());
+        assert!(mem_table.search(key(700)).is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_shards_grow_when_keys_are_skewed() {
+        let shards = Shards::new(&Options::default());
+        let slots = |shards: &Shards| {
+            shards.shards.iter().map(|s| s.read().unwrap().skip_list.capacity()).sum::<usize>()
+        };
+        assert_eq!(slots(&shards), CAPACITY.div_ceil(SHARDS) * SHARDS);
+        // every key starts with a zero byte and lands in the first shard
+        for i in 0..CAPACITY as u64 {
+            let mut key = [0u8; 16];
+            key[8..].copy_from_slice(&i.to_be_bytes());
+            let mut block = block(i, i + 1);
+            block.data = key.into();
+            assert!(shards.insert(block));
+        }
+        assert!(!shards.insert(block(0, 0)));
+        assert_eq!(shards.shards[0].read().unwrap().skip_list.size(), CAPACITY);
+        assert_eq!(shards.entries().len(), CAPACITY);
+    }
+
+    #[test]
+    fn test_mismatched_accumulator_leaves_no_segment() {
+        let dir = test_dir("concurrent-memtable-accumulator");
+        let mem_table = ConcurrentMemTable::new();
+        mem_table.insert(block(1, 1));
+        let frozen = mem_table.freeze().unwrap();
+        // a block the memtable never stored
+        frozen.shards[0].write().unwrap().accumulator.insert(&block(2, 2));
+
+        let error = mem_table.flush(&frozen, &dir).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        assert_eq!(mem_table.immutable().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reads_see_immutable_memtables_newest_first() {
+        let mem_table = ConcurrentMemTable::new();
+        mem_table.insert(block(1, 1));
+        let older = mem_table.freeze().unwrap();
+        mem_table.insert(block(1, 2));
+        let newer = mem_table.freeze().unwrap();
+        mem_table.insert(block(2, 3));
+        assert_eq!(mem_table.immutable().len(), 2);
+        assert!(Arc::ptr_eq(&mem_table.immutable()[0], &older));
+        assert_eq!(mem_table.search(key(1)).unwrap().seq, 2);
+        assert_eq!(mem_table.search_at(key(1), 1).unwrap().seq, 1);
+        assert_eq!(newer.len(), 1);
+    }
+}
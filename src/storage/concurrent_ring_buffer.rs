@@ -0,0 +1,284 @@
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::core::block::Block;
+use crate::storage::mem_table::{MemTable, MemTableOps};
+
+/// Position shared between threads, alone on its cache line so producers bumping the tail
+/// do not invalidate the line consumers read the head from.
+#[repr(align(64))] // align to 64 bytes for cache line alignment
+pub struct AtomicPosition {
+    pub data: AtomicUsize,
+}
+
+/// Slot of a `ConcurrentRingBuffer`. `stamp` says whose turn it is:
+/// equal to a ticket, the producer holding that ticket may write,
+/// one past it, the consumer holding that ticket may read.
+struct Slot {
+    stamp: AtomicUsize,
+    block: UnsafeCell<Option<Block>>,
+}
+
+/// Bounded ring buffer many threads can push to and pop from at once, without locks.
+/// Unlike `BlockRingBuffer` it never overwrites the oldest block, a full buffer pushes back
+/// on producers until a consumer has made room.
+/// Every position handed out is a ticket, blocks are stamped with their ticket as `seq`,
+/// so the sequence numbers follow the order blocks entered the buffer.
+pub struct ConcurrentRingBuffer {
+    /// ticket of the next block to pop
+    pub head: AtomicPosition,
+    /// ticket of the next block to push
+    pub tail: AtomicPosition,
+    slots: Box<[Slot]>,
+    pub capacity: usize,
+    /// sequence number of ticket 0 minus one, the last sequence number before this buffer
+    pub sequence: u64,
+}
+
+// a slot's block is only touched by the thread whose ticket matches its stamp
+unsafe impl Sync for ConcurrentRingBuffer {}
+
+pub trait ConcurrentRingBufferOps {
+    /// Pushes `block`, handing it back when the buffer is full.
+    /// Returns the sequence number the block was stamped with.
+    #[allow(clippy::result_large_err)]
+    fn try_push(&self, block: Block) -> std::result::Result<u64, Block>;
+    /// Pushes `block`, waiting for a consumer to make room while the buffer is full.
+    fn push(&self, block: Block) -> u64;
+    /// Oldest block not yet taken by another consumer, None when the buffer is empty.
+    fn pop(&self) -> Option<Block>;
+    /// Blocks pushed and not yet popped, a snapshot while other threads are busy.
+    fn length(&self) -> usize;
+    fn is_empty(&self) -> bool;
+    /// Pops blocks into `memtable` until either runs out, returns how many were moved.
+    fn drain(&self, memtable: &mut MemTable) -> usize;
+}
+
+impl ConcurrentRingBuffer {
+    /// Buffer of 100 blocks, as many as a `BlockRingBuffer` holds.
+    pub fn new() -> Self {
+        ConcurrentRingBuffer::with_capacity(100)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        assert!(capacity > 0, "ring buffer needs at least one slot");
+        ConcurrentRingBuffer {
+            head: AtomicPosition { data: AtomicUsize::new(0) },
+            tail: AtomicPosition { data: AtomicUsize::new(0) },
+            slots: (0..capacity)
+                .map(|i| Slot {
+                    stamp: AtomicUsize::new(i),
+                    block: UnsafeCell::new(None),
+                })
+                .collect(),
+            capacity,
+            sequence: 0,
+        }
+    }
+
+    /// Continues numbering after `sequence`, e.g. the last sequence number found on disk.
+    pub fn starting_after(mut self, sequence: u64) -> Self {
+        self.sequence = sequence;
+        self
+    }
+}
+
+impl ConcurrentRingBufferOps for ConcurrentRingBuffer {
+    fn try_push(&self, mut block: Block) -> std::result::Result<u64, Block> {
+        let mut ticket = self.tail.data.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[ticket % self.capacity];
+            let stamp = slot.stamp.load(Ordering::Acquire);
+            if stamp == ticket {
+                match self.tail.data.compare_exchange_weak(
+                    ticket,
+                    ticket + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        block.seq = self.sequence + ticket as u64 + 1;
+                        let seq = block.seq;
+                        unsafe { *slot.block.get() = Some(block) };
+                        slot.stamp.store(ticket + 1, Ordering::Release);
+                        return Ok(seq);
+                    },
+                    Err(current) => ticket = current,
+                }
+            } else if stamp < ticket {
+                // the block pushed a lap ago has not been popped yet
+                return Err(block);
+            } else {
+                // another producer took this ticket
+                ticket = self.tail.data.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn push(&self, mut block: Block) -> u64 {
+        let mut spins = 0u32;
+        loop {
+            match self.try_push(block) {
+                Ok(seq) => return seq,
+                Err(rejected) => block = rejected,
+            }
+            // spin briefly, then give the consumers the core
+            if spins < 64 {
+                spins += 1;
+                std::hint::spin_loop();
+            } else {
+                std::thread::yield_now();
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<Block> {
+        let mut ticket = self.head.data.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[ticket % self.capacity];
+            let stamp = slot.stamp.load(Ordering::Acquire);
+            if stamp == ticket + 1 {
+                match self.head.data.compare_exchange_weak(
+                    ticket,
+                    ticket + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let block = unsafe { (*slot.block.get()).take() };
+                        // hand the slot to the producer one lap ahead
+                        slot.stamp.store(ticket + self.capacity, Ordering::Release);
+                        return block;
+                    },
+                    Err(current) => ticket = current,
+                }
+            } else if stamp < ticket + 1 {
+                // nothing was pushed under this ticket yet
+                return None;
+            } else {
+                // another consumer took this ticket
+                ticket = self.head.data.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn length(&self) -> usize {
+        let head = self.head.data.load(Ordering::Acquire);
+        let tail = self.tail.data.load(Ordering::Acquire);
+        tail.saturating_sub(head).min(self.capacity)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.length() == 0
+    }
+
+    fn drain(&self, memtable: &mut MemTable) -> usize {
+        let mut moved = 0;
+        while !memtable.is_full() {
+            let Some(block) = self.pop() else {
+                break;
+            };
+            memtable.insert(block);
+            moved += 1;
+        }
+        moved
+    }
+}
+
+impl Default for ConcurrentRingBuffer {
+    fn default() -> Self {
+        ConcurrentRingBuffer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+    use std::thread;
+
+    use super::*;
+    use crate::core::skip_list::CAPACITY;
+
+    #[test]
+    fn test_many_producers_and_consumers() {
+        let ring_buffer = ConcurrentRingBuffer::with_capacity(16);
+        let popped = Mutex::new(Vec::new());
+        let taken = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for producer in 0..4u8 {
+                let ring_buffer = &ring_buffer;
+                scope.spawn(move || {
+                    for i in 0..500u32 {
+                        let mut phone = [producer; 10];
+                        phone[6..].copy_from_slice(&i.to_be_bytes());
+                        ring_buffer.push(Block::new(phone, false));
+                    }
+                });
+            }
+            for _ in 0..3 {
+                let (ring_buffer, popped, taken) = (&ring_buffer, &popped, &taken);
+                scope.spawn(move || {
+                    let mut mine = Vec::new();
+                    // 2000 blocks in total, the consumers share them out
+                    while taken.load(Ordering::SeqCst) < 2000 {
+                        match ring_buffer.pop() {
+                            Some(block) => {
+                                mine.push(block);
+                                taken.fetch_add(1, Ordering::SeqCst);
+                            },
+                            None => thread::yield_now(),
+                        }
+                    }
+                    popped.lock().unwrap().append(&mut mine);
+                });
+            }
+        });
+        let popped = popped.into_inner().unwrap();
+        assert_eq!(popped.len(), 2000);
+        // nothing lost, nothing handed out twice
+        let seqs: HashSet<u64> = popped.iter().map(|b| b.seq).collect();
+        assert_eq!(seqs, (1..=2000).collect());
+        let keys: HashSet<_> = popped.iter().map(|b| b.data).collect();
+        assert_eq!(keys.len(), 2000);
+        assert!(ring_buffer.is_empty());
+    }
+
+    #[test]
+    fn test_full_buffer_pushes_back() {
+        let ring_buffer = ConcurrentRingBuffer::with_capacity(4).starting_after(10);
+        for i in 0..4u8 {
+            assert_eq!(ring_buffer.try_push(Block::new([i; 10], false)).ok(), Some(11 + i as u64));
+        }
+        // the oldest block is not overwritten, the new one comes back
+        let rejected = ring_buffer.try_push(Block::new([9; 10], false)).unwrap_err();
+        assert_eq!(rejected.key(), Some(&[9; 10][..]));
+        assert_eq!(ring_buffer.length(), 4);
+
+        thread::scope(|scope| {
+            let blocked = scope.spawn(|| ring_buffer.push(rejected));
+            assert_eq!(ring_buffer.pop().unwrap().seq, 11);
+            // the waiting producer got the freed slot
+            assert_eq!(blocked.join().unwrap(), 15);
+        });
+        let order: Vec<u64> = std::iter::from_fn(|| ring_buffer.pop()).map(|b| b.seq).collect();
+        assert_eq!(order, vec![12, 13, 14, 15]);
+        assert!(ring_buffer.pop().is_none());
+    }
+
+    #[test]
+    fn test_drain_stops_when_memtable_is_full() {
+        let ring_buffer = ConcurrentRingBuffer::with_capacity(64);
+        let mut mt = MemTable::new();
+        for i in 0..(CAPACITY - 10) as u64 {
+            mt.add(Block::new([0; 10], false).data, i, false);
+        }
+        for i in 0..40u8 {
+            ring_buffer.push(Block::new([i; 10], false));
+        }
+        assert_eq!(ring_buffer.drain(&mut mt), 10);
+        assert!(mt.is_full());
+        assert_eq!(ring_buffer.length(), 30);
+        assert_eq!(ring_buffer.pop().unwrap().seq, 11);
+    }
+}
@@ -0,0 +1,209 @@
+use serde::{Deserialize, Serialize};
+use twox_hash::XxHash64;
+
+use crate::core::hasher::Digest;
+use crate::storage::bloom_filter::BLOOM_SEED;
+
+/// fingerprints per bucket
+const BUCKET_SIZE: usize = 4;
+/// share of the slots a filter is sized to fill, 4 way buckets rarely fail below 95%
+const LOAD_FACTOR: f64 = 0.95;
+/// evictions tried before an insert gives up
+const MAX_KICKS: usize = 500;
+/// marks an empty slot, no fingerprint is ever 0
+const EMPTY: u16 = 0;
+
+/// Cuckoo filter with 16 bit fingerprints in buckets of four, about 17 bits per key
+/// for 0.01% false positives.
+/// A key lives in one of two buckets, the second derived from the first and the fingerprint,
+/// so a stored fingerprint can always be moved to its other bucket, and removed again.
+/// Unlike bloom and xor filters it supports deletions, e.g. for a memtable whose keys
+/// come and go.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CuckooFilter {
+    /// power of two number of buckets
+    pub buckets: Vec<[u16; BUCKET_SIZE]>,
+    /// fingerprint evicted by the insert that found the filter full, with its bucket
+    pub victim: Option<(usize, u16)>,
+    pub len: usize,
+    pub seed: u64,
+}
+
+pub trait CuckooFilterOps {
+    /// Adds `hashed`, false when the filter is full and the key was not added.
+    fn insert(&mut self, hashed: &Digest) -> bool;
+    /// false means `hashed` is not in the filter, true means it probably is
+    fn may_contain(&self, hashed: &Digest) -> bool;
+    /// Removes one earlier insert of `hashed`, false when it was not found.
+    /// Removing a key that was never inserted can remove another key sharing its fingerprint.
+    fn remove(&mut self, hashed: &Digest) -> bool;
+}
+
+impl CuckooFilter {
+    /// Filter with room for `keys` keys.
+    pub fn with_capacity(keys: usize) -> Self {
+        let buckets = (keys as f64 / (BUCKET_SIZE as f64 * LOAD_FACTOR)).ceil() as usize;
+        CuckooFilter {
+            buckets: vec![[EMPTY; BUCKET_SIZE]; buckets.next_power_of_two()],
+            victim: None,
+            len: 0,
+            seed: BLOOM_SEED,
+        }
+    }
+
+    /// Filter holding `keys`, grown until every key fits. Duplicates are dropped,
+    /// more than eight copies of one fingerprint never fit its two buckets.
+    pub fn from_keys(keys: &[Digest]) -> Self {
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        keys.dedup();
+        let mut capacity = keys.len();
+        loop {
+            let mut filter = CuckooFilter::with_capacity(capacity);
+            if keys.iter().all(|k| filter.insert(k)) {
+                return filter;
+            }
+            capacity = filter.buckets.len() * BUCKET_SIZE * 2;
+        }
+    }
+
+    /// Fingerprint of `hashed` and its first bucket.
+    fn locate(&self, hashed: &Digest) -> (u16, usize) {
+        let hash = XxHash64::oneshot(self.seed, hashed.as_bytes());
+        let fingerprint = ((hash >> 48) as u16).max(1);
+        (fingerprint, hash as usize & (self.buckets.len() - 1))
+    }
+
+    /// The other bucket of `fingerprint`, going from either of its two buckets to the other.
+    fn alternate(&self, bucket: usize, fingerprint: u16) -> usize {
+        let hash = (fingerprint as u64).wrapping_mul(0x5bd1_e995) as usize;
+        (bucket ^ hash) & (self.buckets.len() - 1)
+    }
+
+    fn put(&mut self, bucket: usize, fingerprint: u16) -> bool {
+        match self.buckets[bucket].iter_mut().find(|slot| **slot == EMPTY) {
+            Some(slot) => {
+                *slot = fingerprint;
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+impl CuckooFilterOps for CuckooFilter {
+    fn insert(&mut self, hashed: &Digest) -> bool {
+        if self.victim.is_some() {
+            return false;
+        }
+        let (mut fingerprint, first) = self.locate(hashed);
+        let second = self.alternate(first, fingerprint);
+        self.len += 1;
+        if self.put(first, fingerprint) || self.put(second, fingerprint) {
+            return true;
+        }
+        // evict a random fingerprint to its other bucket until one lands in a free slot
+        let mut bucket = if rand::random::<bool>() { first } else { second };
+        for _ in 0..MAX_KICKS {
+            let slot = rand::random::<u32>() as usize % BUCKET_SIZE;
+            std::mem::swap(&mut fingerprint, &mut self.buckets[bucket][slot]);
+            bucket = self.alternate(bucket, fingerprint);
+            if self.put(bucket, fingerprint) {
+                return true;
+            }
+        }
+        // the key is in, but the last evicted fingerprint has no slot left
+        self.victim = Some((bucket, fingerprint));
+        true
+    }
+
+    fn may_contain(&self, hashed: &Digest) -> bool {
+        let (fingerprint, first) = self.locate(hashed);
+        let second = self.alternate(first, fingerprint);
+        if let Some((bucket, victim)) = self.victim {
+            if victim == fingerprint && (bucket == first || bucket == second) {
+                return true;
+            }
+        }
+        self.buckets[first].contains(&fingerprint) || self.buckets[second].contains(&fingerprint)
+    }
+
+    fn remove(&mut self, hashed: &Digest) -> bool {
+        let (fingerprint, first) = self.locate(hashed);
+        let second = self.alternate(first, fingerprint);
+        if let Some((bucket, victim)) = self.victim {
+            if victim == fingerprint && (bucket == first || bucket == second) {
+                self.victim = None;
+                self.len -= 1;
+                return true;
+            }
+        }
+        for bucket in [first, second] {
+            if let Some(slot) = self.buckets[bucket].iter_mut().find(|slot| **slot == fingerprint) {
+                *slot = EMPTY;
+                self.len -= 1;
+                if let Some((bucket, victim)) = self.victim.take() {
+                    // the freed slot may take the stashed fingerprint back
+                    if !self.put(bucket, victim)
+                        && !self.put(self.alternate(bucket, victim), victim)
+                    {
+                        self.victim = Some((bucket, victim));
+                    }
+                }
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::hasher::{BlockHasher, TruncatedSha256Hasher};
+
+    fn key(i: u32) -> Digest {
+        TruncatedSha256Hasher.digest(&i.to_be_bytes())
+    }
+
+    #[test]
+    fn test_insert_and_false_positive_rate() {
+        let mut filter = CuckooFilter::with_capacity(1000);
+        assert_eq!(filter.buckets.len(), 512);
+        assert!((0..1000).all(|i| filter.insert(&key(i))));
+        // no false negatives
+        assert!((0..1000).all(|i| filter.may_contain(&key(i))));
+        assert_eq!(filter.len, 1000);
+
+        let probes = 100_000;
+        let false_positives =
+            (1000..1000 + probes).filter(|i| filter.may_contain(&key(*i))).count();
+        assert!(false_positives < 50, "{} false positives", false_positives);
+
+        // a filter filled past its slots stashes one fingerprint, then stops taking keys
+        let mut full = CuckooFilter::with_capacity(8);
+        let inserted = (0..100).take_while(|i| full.insert(&key(*i))).count();
+        assert!(full.victim.is_some());
+        assert!((0..inserted as u32).all(|i| full.may_contain(&key(i))));
+        let grown = CuckooFilter::from_keys(&(0..100).map(key).collect::<Vec<_>>());
+        assert!((0..100).all(|i| grown.may_contain(&key(i))));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut filter = CuckooFilter::with_capacity(100);
+        (0..100).for_each(|i| assert!(filter.insert(&key(i))));
+        for i in 0..50 {
+            assert!(filter.remove(&key(i)));
+        }
+        assert_eq!(filter.len, 50);
+        assert!((50..100).all(|i| filter.may_contain(&key(i))));
+        assert!((0..50).all(|i| !filter.may_contain(&key(i))));
+        assert!(!filter.remove(&key(7)));
+        // a key inserted twice is removed one insert at a time
+        filter.insert(&key(7));
+        filter.insert(&key(7));
+        assert!(filter.remove(&key(7)));
+        assert!(filter.may_contain(&key(7)));
+    }
+}
@@ -1,5 +1,14 @@
-use crate::core::skip_list::SkipList;
+use serde::{Deserialize, Serialize};
 
+use crate::core::skip_list::SkipNode;
+
+/// encoded size a data block is filled to before the next one starts, one page
+pub const DATA_BLOCK_SIZE: usize = 4096;
+
+/// Sorted entries of a segment, copied out of the flushed `SkipList`.
+/// On disk the entries are split into blocks of about `DATA_BLOCK_SIZE` bytes,
+/// each encoded as a `DataBlock` of its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DataBlock {
-    pub memtable : SkipList 
+    pub entries: Vec<SkipNode>,
 }
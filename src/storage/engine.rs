@@ -0,0 +1,854 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::core::block::Block;
+use crate::core::exclusion::{SortedMerkleTree, SortedMerkleTreeOps};
+use crate::core::hasher::{BlockHasher, Digest, TruncatedSha256Hasher};
+use crate::datasource::DataSource;
+use crate::sys::AccessPattern;
+
+use super::compaction::{CompactionOps, CompactionOptions};
+use super::iterator::{bounds, key_range, overlaps, prefix_range, KeyRange, MergingIterator};
+use super::manifest::{file_name, Manifest, ManifestOps, SegmentMeta};
+use super::mem_table::{MemTable, MemTableOps};
+use super::options::Options;
+use super::ring_buffer::{BlockRingBuffer, BlockRingBufferOps};
+use super::snapshot::{Snapshot, Snapshots};
+use super::ss_table::{SSTableSegment, SSTableSegmentOps, SEGMENT_PREFIX, SEGMENT_SUFFIX};
+use super::ss_table_reader::{SSTableReader, SSTableReaderOps};
+use super::wal::{WriteAheadLog, WriteAheadLogOps};
+
+/// Write path of the storage layers:
+/// `BlockRingBuffer` --(full)--> `MemTable` --(full)--> `SSTableSegment`
+/// Segments live as `sstable-<millis>.segment` files in `dir`, the `MANIFEST` there decides which
+/// of them are live. Live segments are read through an `SSTableReader` each, kept in read order
+/// oldest first: deeper compaction levels before shallower ones, then by `created_at`.
+/// Every block is appended to the write-ahead log in `dir` before it reaches the ring buffer,
+/// which stamps it with the next sequence number.
+pub struct Engine {
+    pub dir: PathBuf,
+    pub wal: WriteAheadLog,
+    pub manifest: Manifest,
+    pub ring_buffer: BlockRingBuffer,
+    pub mem_table: MemTable,
+    pub segments: Vec<SSTableReader>,
+    pub snapshots: Snapshots,
+    /// sizing, compaction and key digests, fixed when the engine is opened
+    pub options: Options,
+}
+
+pub trait EngineOps: Sized {
+    /// Opens the engine in `dir` with the default size-tiered compaction
+    /// and truncated SHA-256 digests.
+    fn open(dir: &Path) -> std::result::Result<Self, Error>;
+    /// Opens the engine in `dir`, loading every segment the manifest lists and replaying
+    /// the write-ahead log into a fresh ring buffer and memtable.
+    /// Segment files unknown to the manifest are leftovers of an interrupted flush or compaction
+    /// and are deleted.
+    fn open_with(dir: &Path, compaction: CompactionOptions) -> std::result::Result<Self, Error>;
+    /// Like `open_with`, keying blocks with `hasher`. A store keeps the hasher it was created with,
+    /// opening it with another one fails.
+    fn open_with_hasher(
+        dir: &Path, compaction: CompactionOptions, hasher: Arc<dyn BlockHasher>,
+    ) -> std::result::Result<Self, Error>;
+    /// Like `open_with_hasher`, with the capacities of the ring buffer and memtable and the
+    /// segment filter taken from `options` as well.
+    /// Compaction parameters the strategy can not make progress with are rejected.
+    fn open_with_options(dir: &Path, options: Options) -> std::result::Result<Self, Error>;
+    fn add(&mut self, phone_number: [u8; 10]) -> std::result::Result<bool, Error>;
+    /// tombstone the phone number
+    fn delete(&mut self, phone_number: [u8; 10]) -> std::result::Result<bool, Error>;
+    /// Stores `value` under an arbitrary `key`, phone numbers are one kind of key.
+    fn put(&mut self, key: &[u8], value: &[u8]) -> std::result::Result<bool, Error>;
+    /// tombstone an arbitrary key
+    fn remove(&mut self, key: &[u8]) -> std::result::Result<bool, Error>;
+    /// Drains the ring buffer and persists the memtable regardless of fill level.
+    fn flush(&mut self) -> std::result::Result<bool, Error>;
+    /// Point lookup of a key digest, newest layer first:
+    /// ring buffer, memtable, then segments newest to oldest.
+    /// The first layer holding the key answers, a tombstone there reports it as absent.
+    /// Fails when a segment data block the lookup reaches is corrupted.
+    fn get(
+        &self, key: impl Into<Digest>,
+    ) -> std::result::Result<Option<(Block, DataSource)>, Error>;
+    /// Latest value stored under `key` by `put`.
+    fn get_value(&self, key: &[u8]) -> std::result::Result<Option<Vec<u8>>, Error>;
+    /// Ordered scan of every live key in `range` across all layers, newest entry wins.
+    /// The segment data blocks covering `range` are checked before the scan starts.
+    fn scan(
+        &self, range: impl RangeBounds<Digest>,
+    ) -> std::result::Result<MergingIterator<'_>, Error>;
+    /// Ordered scan of every live key starting with `prefix`.
+    fn scan_prefix(&self, prefix: &[u8]) -> std::result::Result<MergingIterator<'_>, Error>;
+    /// Pins the current state: reads through the handle ignore every later write
+    /// and compaction keeps what it sees until it is dropped.
+    fn snapshot(&self) -> Snapshot;
+    /// Point lookup as of `snapshot`.
+    fn get_at(
+        &self, key: impl Into<Digest>, snapshot: &Snapshot,
+    ) -> std::result::Result<Option<(Block, DataSource)>, Error>;
+    /// Ordered scan of `range` as of `snapshot`.
+    fn scan_at(
+        &self, range: impl RangeBounds<Digest>, snapshot: &Snapshot,
+    ) -> std::result::Result<MergingIterator<'_>, Error>;
+    /// Commits to the latest record of every key, tombstones included, as of `snapshot`.
+    /// Its root is what exclusion proofs of revoked or unknown keys are checked against.
+    fn commit_state(&self, snapshot: &Snapshot) -> std::result::Result<SortedMerkleTree, Error>;
+    /// Runs one merge picked by the compaction strategy, returns false when nothing was due.
+    fn compact(&mut self) -> std::result::Result<bool, Error>;
+}
+
+impl EngineOps for Engine {
+    fn open(dir: &Path) -> std::result::Result<Engine, Error> {
+        Engine::open_with(dir, CompactionOptions::default())
+    }
+
+    fn open_with(dir: &Path, compaction: CompactionOptions) -> std::result::Result<Engine, Error> {
+        Engine::open_with_hasher(dir, compaction, Arc::new(TruncatedSha256Hasher))
+    }
+
+    fn open_with_hasher(
+        dir: &Path, compaction: CompactionOptions, hasher: Arc<dyn BlockHasher>,
+    ) -> std::result::Result<Engine, Error> {
+        Engine::open_with_options(dir, Options { compaction, hasher, ..Options::default() })
+    }
+
+    fn open_with_options(dir: &Path, options: Options) -> std::result::Result<Engine, Error> {
+        options
+            .compaction
+            .strategy
+            .validate()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let hasher = &options.hasher;
+        fs::create_dir_all(dir)?;
+        let bootstrap = !Manifest::exists(dir);
+        let mut manifest = Manifest::open(dir)?;
+        match manifest.hasher {
+            Some(id) if id != hasher.id() => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("store is keyed with hasher {}, not {}", id, hasher.id()),
+                ));
+            },
+            Some(_) => {},
+            None => {
+                // pin the hasher before anything is keyed with it
+                manifest.hasher = Some(hasher.id());
+                manifest.apply(&[], &[])?;
+            },
+        }
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = file_name(&path);
+            if name.starts_with(SEGMENT_PREFIX) && name.ends_with(SEGMENT_SUFFIX) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        let mut segments = Vec::new();
+        if bootstrap {
+            // directory predates the manifest, adopt every segment on disk
+            segments =
+                paths.iter().map(|p| SSTableReader::open(p)).collect::<Result<Vec<_>, _>>()?;
+            if !segments.is_empty() {
+                manifest.apply(&segments.iter().map(SegmentMeta::from).collect::<Vec<_>>(), &[])?;
+            }
+        } else {
+            for meta in manifest.segments.iter() {
+                let segment = SSTableReader::open(&dir.join(&meta.file_name))?;
+                if SegmentMeta::from(&segment) != *meta {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("segment {} does not match the manifest", meta.file_name),
+                    ));
+                }
+                segments.push(segment);
+            }
+            for path in paths.iter().filter(|p| !manifest.contains(&file_name(p))) {
+                fs::remove_file(path)?;
+            }
+        }
+        // deeper levels hold older data, file name breaks ties of the same millis
+        segments.sort_by(|a, b| {
+            b.meta_block
+                .level
+                .cmp(&a.meta_block.level)
+                .then(a.meta_block.created_at.cmp(&b.meta_block.created_at))
+        });
+        // persisted blocks keep their sequence numbers, replayed ones are numbered after them
+        let mut ring_buffer = BlockRingBuffer::with_capacity(options.ring_buffer_capacity);
+        ring_buffer.sequence = segments.iter().map(|s| s.meta_block.max_seq).max().unwrap_or(0);
+        let mut wal = WriteAheadLog::open(dir)?;
+        let recovered = wal.replay()?;
+        let mut engine = Engine {
+            dir: dir.to_path_buf(),
+            wal,
+            manifest,
+            ring_buffer,
+            mem_table: MemTable::with_options(&options),
+            segments,
+            snapshots: Snapshots::default(),
+            options,
+        };
+        // the log is only rewritten once every recovered block is back in memory
+        let mut flushed = false;
+        for block in recovered {
+            engine.ring_buffer.push(block);
+            flushed |= engine.flush_full_layers()?;
+        }
+        if flushed {
+            let unflushed = engine.unflushed();
+            engine.wal.rewrite(unflushed.iter())?;
+        }
+        Ok(engine)
+    }
+
+    fn add(&mut self, phone_number: [u8; 10]) -> std::result::Result<bool, Error> {
+        self.put(&phone_number, &[])
+    }
+
+    fn delete(&mut self, phone_number: [u8; 10]) -> std::result::Result<bool, Error> {
+        self.remove(&phone_number)
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> std::result::Result<bool, Error> {
+        self.write(Block::with_hasher(&*self.options.hasher, key.to_vec(), value.to_vec(), false))
+    }
+
+    fn remove(&mut self, key: &[u8]) -> std::result::Result<bool, Error> {
+        self.write(Block::with_hasher(&*self.options.hasher, key.to_vec(), Vec::new(), true))
+    }
+
+    fn flush(&mut self) -> std::result::Result<bool, Error> {
+        if self.ring_buffer.length() + self.mem_table.size() == 0 {
+            return Ok(false);
+        }
+        if !self.ring_buffer.drain(&mut self.mem_table) && self.ring_buffer.length() > 0 {
+            // memtable has no room left for the ring buffer
+            self.flush_mem_table()?;
+            self.ring_buffer.drain(&mut self.mem_table);
+        }
+        self.flush_mem_table()?;
+        // everything is in segments now
+        self.wal.rewrite(std::iter::empty())?;
+        Ok(true)
+    }
+
+    fn get(
+        &self, key: impl Into<Digest>,
+    ) -> std::result::Result<Option<(Block, DataSource)>, Error> {
+        self.get_seq(key.into(), u64::MAX)
+    }
+
+    fn get_value(&self, key: &[u8]) -> std::result::Result<Option<Vec<u8>>, Error> {
+        let Some((block, _)) = self.get(self.options.hasher.digest(key))? else {
+            return Ok(None);
+        };
+        // the digest is only an index, the stored key settles it
+        Ok(block.payload.filter(|p| p.key == key).map(|p| p.value))
+    }
+
+    fn scan(
+        &self, range: impl RangeBounds<Digest>,
+    ) -> std::result::Result<MergingIterator<'_>, Error> {
+        self.scan_range(key_range(range), u64::MAX)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> std::result::Result<MergingIterator<'_>, Error> {
+        self.scan_range(prefix_range(prefix), u64::MAX)
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        self.snapshots.acquire(self.ring_buffer.sequence)
+    }
+
+    fn get_at(
+        &self, key: impl Into<Digest>, snapshot: &Snapshot,
+    ) -> std::result::Result<Option<(Block, DataSource)>, Error> {
+        self.get_seq(key.into(), snapshot.seq)
+    }
+
+    fn scan_at(
+        &self, range: impl RangeBounds<Digest>, snapshot: &Snapshot,
+    ) -> std::result::Result<MergingIterator<'_>, Error> {
+        self.scan_range(key_range(range), snapshot.seq)
+    }
+
+    fn commit_state(&self, snapshot: &Snapshot) -> std::result::Result<SortedMerkleTree, Error> {
+        let latest = self.scan_range(key_range(..), snapshot.seq)?.with_tombstones();
+        Ok(SortedMerkleTree::new(&*self.options.hasher, latest.map(|(block, _)| block)))
+    }
+
+    fn compact(&mut self) -> std::result::Result<bool, Error> {
+        let mut task = match self.options.compaction.pick(&self.segments) {
+            Some(task) => task,
+            None => return Ok(false),
+        };
+        task.inputs.sort();
+        let first_input = task.inputs[0];
+        let inputs: Vec<&SSTableReader> = task.inputs.iter().map(|&i| &self.segments[i]).collect();
+        let older: Vec<&SSTableReader> = self.segments[..first_input].iter().collect();
+        let created_at = inputs.iter().map(|s| s.meta_block.created_at).max().unwrap();
+        let now = chrono::Utc::now().timestamp_millis();
+        for segment in inputs.iter() {
+            segment.advise(AccessPattern::Sequential)?;
+        }
+        let merged = self.options.compaction.merge(&inputs, &older, &self.snapshots.live(), now)?;
+
+        let mut outputs = Vec::new();
+        for entries in self.options.compaction.split(merged) {
+            if !entries.is_empty() {
+                let segment = SSTableSegment::create_from_entries(
+                    &self.dir,
+                    entries,
+                    task.output_level,
+                    created_at,
+                    self.options.filter,
+                )?;
+                outputs.push(SSTableReader::open(&segment.path)?);
+            }
+        }
+        let added: Vec<SegmentMeta> = outputs.iter().map(SegmentMeta::from).collect();
+        let removed: Vec<SegmentMeta> = inputs.iter().map(|&s| SegmentMeta::from(s)).collect();
+        // commit point, inputs are dead from here on even if deleting them fails
+        self.manifest.apply(&added, &removed)?;
+        let mut removed = Vec::new();
+        for &i in task.inputs.iter().rev() {
+            removed.push(self.segments.remove(i));
+        }
+        let position =
+            self.options.compaction.insert_position(&self.segments, first_input, task.output_level);
+        self.segments.splice(position..position, outputs);
+        for segment in removed {
+            fs::remove_file(&segment.path)?;
+        }
+        Ok(true)
+    }
+}
+
+impl Engine {
+    /// Newest layer first, only blocks written at or before `seq` are seen.
+    /// A segment reads at most the one data block its index points the key to,
+    /// one left advised sequential by a scan or compaction is advised random again.
+    fn get_seq(
+        &self, key: Digest, seq: u64,
+    ) -> std::result::Result<Option<(Block, DataSource)>, Error> {
+        let mut found = self
+            .ring_buffer
+            .search_at(key, seq)
+            .map(|b| (b, DataSource::RingBuffer))
+            .or_else(|| self.mem_table.search_at(key, seq).map(|b| (b, DataSource::MemTable)));
+        if found.is_none() {
+            for segment in self.segments.iter().rev() {
+                segment.advise(AccessPattern::Random)?;
+                if let Some(block) = segment.search_at(key, seq)? {
+                    found = Some((block, DataSource::SSTable));
+                    break;
+                }
+            }
+        }
+        Ok(found.filter(|(block, _)| !block.disabled))
+    }
+
+    /// Segment entries are read in place from the data blocks covering `range`,
+    /// each checked up front so the scan itself can not fail. Segments are advised sequential
+    /// before their blocks are read.
+    fn scan_range(
+        &self, range: KeyRange, seq: u64,
+    ) -> std::result::Result<MergingIterator<'_>, Error> {
+        let mut merged = MergingIterator::new();
+        // ring buffer is in insertion order, a stable sort keeps later writes after earlier ones
+        let mut buffered: Vec<Block> = self
+            .ring_buffer
+            .iter()
+            .filter(|b| range.contains(&b.data) && b.seq <= seq)
+            .cloned()
+            .collect();
+        buffered.sort_by_key(|b| b.data);
+        merged.push(DataSource::RingBuffer, buffered.into_iter());
+
+        let nodes = &self.mem_table.blocks.blocks[..self.mem_table.size()];
+        let nodes = &nodes[bounds(nodes, &range, |n| n.as_ref().unwrap().data)];
+        let nodes = nodes.iter().flatten().filter(move |n| n.seq <= seq);
+        merged.push(DataSource::MemTable, nodes.map(Block::from));
+
+        for segment in self.segments.iter().rev() {
+            if overlaps(&range, &segment.footer.min_key, &segment.footer.max_key) {
+                segment.advise(AccessPattern::Sequential)?;
+                let blocks = segment.index_block.blocks(&range);
+                let blocks = blocks.map(|i| segment.block(i)).collect::<Result<Vec<_>, _>>()?;
+                let entries = blocks
+                    .into_iter()
+                    .flatten()
+                    .filter(move |e| range.contains(&Digest::new(e.key)) && e.seq <= seq);
+                // a parsed entry always decodes
+                merged.push(DataSource::SSTable, entries.map(|e| e.to_block().unwrap()));
+            }
+        }
+        Ok(merged)
+    }
+
+    /// Logs the block, then buffers it. Once a segment was written the log is rewritten
+    /// with the blocks not yet in a segment. Making room for a full ring buffer leaves
+    /// it drained into the fresh memtable, so both layers are logged.
+    fn write(&mut self, block: Block) -> std::result::Result<bool, Error> {
+        self.wal.append(&block)?;
+        self.ring_buffer.push(block);
+        if self.flush_full_layers()? {
+            let unflushed = self.unflushed();
+            self.wal.rewrite(unflushed.iter())?;
+        }
+        Ok(true)
+    }
+
+    /// Blocks of the memtable followed by those of the ring buffer, oldest first.
+    fn unflushed(&self) -> Vec<Block> {
+        self.mem_table
+            .blocks
+            .iter()
+            .map(Block::from)
+            .chain(self.ring_buffer.iter().cloned())
+            .collect()
+    }
+
+    /// Moves a full ring buffer into the memtable and a full memtable into a new segment,
+    /// returns true when a segment was written.
+    fn flush_full_layers(&mut self) -> std::result::Result<bool, Error> {
+        let mut flushed = false;
+        if self.ring_buffer.length() == self.ring_buffer.capacity
+            && !self.ring_buffer.flush(&mut self.mem_table)
+        {
+            // memtable cannot take the whole ring buffer, make room first
+            flushed |= self.flush_mem_table()?;
+            self.ring_buffer.flush(&mut self.mem_table);
+        }
+        if self.mem_table.is_full() {
+            flushed |= self.flush_mem_table()?;
+        }
+        Ok(flushed)
+    }
+
+    fn flush_mem_table(&mut self) -> std::result::Result<bool, Error> {
+        if self.mem_table.size() == 0 {
+            return Ok(false);
+        }
+        let segment = self.mem_table.flush(&self.dir)?;
+        let reader = SSTableReader::open(&segment.path)?;
+        self.manifest.apply(&[SegmentMeta::from(&reader)], &[])?;
+        self.segments.push(reader);
+        while self.compact()? {}
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use super::*;
+    use crate::core::block::sha_hash;
+    use crate::core::exclusion::ExclusionProof;
+    use crate::core::hasher::Blake3Hasher;
+    use crate::core::skip_list::CAPACITY;
+    use crate::storage::compaction::CompactionStrategy;
+    use crate::storage::membership_filter::{FilterKind, MembershipFilter};
+    use crate::storage::options::OptionsBuilder;
+    use crate::storage::test_dir;
+
+    fn phone(i: u16) -> [u8; 10] {
+        let mut phone = [0u8; 10];
+        phone[..2].copy_from_slice(&i.to_be_bytes());
+        phone
+    }
+
+    #[test]
+    fn test_full_memtable_is_persisted_as_segment() {
+        let dir = test_dir("engine-write-path");
+        let mut engine = Engine::open(&dir).unwrap();
+        for i in 0..CAPACITY as u16 {
+            assert!(engine.add(phone(i)).unwrap());
+        }
+        // ten ring buffers fill the memtable which is flushed and reset
+        assert_eq!(engine.segments.len(), 1);
+        assert_eq!(engine.segments[0].meta_block.entry_count as usize, CAPACITY);
+        assert_eq!(engine.mem_table.size(), 0);
+        assert_eq!(engine.ring_buffer.length(), 0);
+
+        let entries = engine.segments[0].entries().unwrap();
+        assert!(entries.windows(2).all(|w| w[0].data <= w[1].data));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_capacities_come_from_options() {
+        let dir = test_dir("engine-options");
+        let options = OptionsBuilder::default()
+            .ring_buffer_capacity(8)
+            .mem_table_capacity(40)
+            .max_level(3)
+            .filter(FilterKind::Bloom { bits_per_key: 16 })
+            .build()
+            .unwrap();
+        let mut engine = Engine::open_with_options(&dir, options).unwrap();
+        for i in 0..100 {
+            engine.add(phone(i)).unwrap();
+        }
+        // two memtables of 40 were flushed, the rest waits in the memtable and ring buffer
+        assert_eq!(engine.segments.len(), 2);
+        assert_eq!(engine.segments[0].meta_block.entry_count as usize, 40);
+        assert_eq!(engine.segments[0].filter.bit_size(), 40 * 16);
+        assert_eq!(engine.mem_table.size(), 16);
+        assert_eq!(engine.ring_buffer.length(), 4);
+        assert!((0..100).all(|i| engine.get(sha_hash(&phone(i))).unwrap().is_some()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_segments_survive_reopen() {
+        let dir = test_dir("engine-reopen");
+        {
+            let mut engine = Engine::open(&dir).unwrap();
+            for i in 0..1500 {
+                engine.add(phone(i)).unwrap();
+            }
+            engine.delete(phone(3)).unwrap();
+            assert!(engine.flush().unwrap());
+            assert_eq!(engine.segments.len(), 2);
+            assert!(!engine.flush().unwrap());
+        }
+        let engine = Engine::open(&dir).unwrap();
+        assert_eq!(engine.segments.len(), 2);
+        let persisted: usize =
+            engine.segments.iter().map(|s| s.meta_block.entry_count as usize).sum();
+        assert_eq!(persisted, 1501);
+        assert!(engine.segments[1].meta_block.tombstone);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_reports_answering_layer() {
+        let dir = test_dir("engine-get");
+        let mut engine = Engine::open(&dir).unwrap();
+        for i in 0..1150 {
+            engine.add(phone(i)).unwrap();
+        }
+        // 0..1000 in a segment, 1000..1100 in the memtable, 1100..1150 in the ring buffer
+        assert_eq!(engine.get(sha_hash(&phone(5))).unwrap().unwrap().1, DataSource::SSTable);
+        assert_eq!(engine.get(sha_hash(&phone(1050))).unwrap().unwrap().1, DataSource::MemTable);
+        assert_eq!(engine.get(sha_hash(&phone(1120))).unwrap().unwrap().1, DataSource::RingBuffer);
+        assert!(engine.get(sha_hash(&phone(4000))).unwrap().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_scans_advise_segments_sequential() {
+        let dir = test_dir("engine-advise");
+        let mut engine = Engine::open(&dir).unwrap();
+        for i in 0..CAPACITY as u16 {
+            engine.add(phone(i)).unwrap();
+        }
+        let segment = &engine.segments[0];
+        assert_eq!(segment.access_pattern(), AccessPattern::Random);
+        assert_eq!(engine.scan(..).unwrap().count(), CAPACITY);
+        assert_eq!(segment.access_pattern(), AccessPattern::Sequential);
+        assert!(engine.get(sha_hash(&phone(7))).unwrap().is_some());
+        assert_eq!(segment.access_pattern(), AccessPattern::Random);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_damaged_data_block_only_fails_reads_reaching_it() {
+        let dir = test_dir("engine-damaged-block");
+        let engine = {
+            let mut engine = Engine::open(&dir).unwrap();
+            for i in 0..CAPACITY as u16 {
+                engine.add(phone(i)).unwrap();
+            }
+            engine
+        };
+        let segment = &engine.segments[0];
+        let handle = segment.index_block.entries[0].handle;
+        let last_key = segment.index_block.entries[0].last_key;
+        let mut bytes = fs::read(&segment.path).unwrap();
+        bytes[handle.offset as usize + 20] ^= 0xFF;
+        fs::write(&segment.path, &bytes).unwrap();
+        drop(engine);
+
+        // opening decodes no data block, only reads reaching the damaged one fail
+        let engine = Engine::open(&dir).unwrap();
+        let (damaged, intact): (Vec<_>, Vec<_>) = (0..CAPACITY as u16)
+            .map(|i| Digest::from(sha_hash(&phone(i))))
+            .partition(|&k| k <= last_key);
+        assert!(engine.get(damaged[0]).is_err());
+        assert!(intact.iter().all(|&k| engine.get(k).unwrap().is_some()));
+        assert!(engine.scan(..).is_err());
+        let after = engine.scan((Bound::Excluded(last_key), Bound::Unbounded)).unwrap();
+        assert_eq!(after.count(), intact.len());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_honors_tombstones() {
+        let dir = test_dir("engine-get-tombstone");
+        let mut engine = Engine::open(&dir).unwrap();
+        for i in 0..1000 {
+            engine.add(phone(i)).unwrap();
+        }
+        // tombstone in the ring buffer shadows the live entry in the segment
+        engine.delete(phone(7)).unwrap();
+        assert!(engine.get(sha_hash(&phone(7))).unwrap().is_none());
+        // and keeps shadowing it once persisted to a newer segment
+        engine.flush().unwrap();
+        assert_eq!(engine.segments.len(), 2);
+        assert!(engine.get(sha_hash(&phone(7))).unwrap().is_none());
+        assert!(engine.get(sha_hash(&phone(8))).unwrap().is_some());
+
+        // re-adding revives it
+        engine.add(phone(7)).unwrap();
+        assert_eq!(engine.get(sha_hash(&phone(7))).unwrap().unwrap().1, DataSource::RingBuffer);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_unflushed_blocks_survive_crash() {
+        let dir = test_dir("engine-wal-recovery");
+        {
+            let mut engine = Engine::open(&dir).unwrap();
+            for i in 0..1150 {
+                engine.add(phone(i)).unwrap();
+            }
+            engine.delete(phone(1120)).unwrap();
+            // dropped without flush, ring buffer and memtable contents only live in the log
+        }
+        let engine = Engine::open(&dir).unwrap();
+        assert_eq!(engine.segments.len(), 1);
+        assert_eq!(engine.get(sha_hash(&phone(1050))).unwrap().unwrap().1, DataSource::MemTable);
+        assert_eq!(engine.get(sha_hash(&phone(1149))).unwrap().unwrap().1, DataSource::RingBuffer);
+        assert!(engine.get(sha_hash(&phone(1120))).unwrap().is_none());
+        assert_eq!(engine.mem_table.size() + engine.ring_buffer.length(), 151);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_blocks_drained_after_making_room_survive_crash() {
+        let dir = test_dir("engine-wal-make-room");
+        let options = Options {
+            ring_buffer_capacity: 30,
+            mem_table_capacity: 40,
+            ..Options::default()
+        };
+        {
+            let mut engine = Engine::open_with_options(&dir, options.clone()).unwrap();
+            for i in 0..60 {
+                engine.add(phone(i)).unwrap();
+            }
+            // the second ring buffer did not fit, the memtable was flushed to make room
+            assert_eq!(engine.segments.len(), 1);
+            assert_eq!(engine.mem_table.size(), 30);
+            assert_eq!(engine.ring_buffer.length(), 0);
+        }
+        let engine = Engine::open_with_options(&dir, options).unwrap();
+        assert_eq!(engine.mem_table.size() + engine.ring_buffer.length(), 30);
+        assert!((0..60).all(|i| engine.get(sha_hash(&phone(i))).unwrap().is_some()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_wal_truncated_once_data_reaches_segment() {
+        let dir = test_dir("engine-wal-truncate");
+        let mut engine = Engine::open(&dir).unwrap();
+        for i in 0..1000 {
+            engine.add(phone(i)).unwrap();
+        }
+        // memtable went to a segment and the ring buffer is empty
+        assert_eq!(fs::metadata(&engine.wal.path).unwrap().len(), 0);
+        engine.add(phone(1000)).unwrap();
+        assert_eq!(engine.wal.replay().unwrap().len(), 1);
+        engine.flush().unwrap();
+        assert_eq!(fs::metadata(&engine.wal.path).unwrap().len(), 0);
+
+        // nothing is replayed twice
+        drop(engine);
+        let engine = Engine::open(&dir).unwrap();
+        assert_eq!(engine.mem_table.size() + engine.ring_buffer.length(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_decides_live_segments() {
+        let dir = test_dir("engine-manifest");
+        let mut engine = Engine::open(&dir).unwrap();
+        for i in 0..2000 {
+            engine.add(phone(i)).unwrap();
+        }
+        let live: Vec<_> = engine.segments.iter().map(|s| file_name(&s.path)).collect();
+        let listed: Vec<_> = engine.manifest.segments.iter().map(|m| m.file_name.clone()).collect();
+        assert_eq!(live, listed);
+        assert_eq!(engine.manifest.segments[1].entry_count, 1000);
+
+        // a segment written by a flush that crashed before its manifest edit
+        let mut orphan = MemTable::new();
+        orphan.add(sha_hash(&phone(9000)).into(), 0, false);
+        let orphan = orphan.flush(&dir).unwrap();
+        drop(engine);
+
+        let engine = Engine::open(&dir).unwrap();
+        assert_eq!(engine.segments.len(), 2);
+        assert!(!orphan.path.exists());
+        assert!(engine.get(sha_hash(&phone(9000))).unwrap().is_none());
+        assert!(engine.get(sha_hash(&phone(1999))).unwrap().is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_sees_point_in_time_view() {
+        let dir = test_dir("engine-snapshot");
+        let compaction = CompactionOptions {
+            strategy: CompactionStrategy::SizeTiered {
+                min_threshold: 2,
+                max_threshold: 32,
+                bucket_low: 0.5,
+                bucket_high: 1.5,
+            },
+            tombstone_horizon_millis: 0,
+        };
+        let mut engine = Engine::open_with(&dir, compaction).unwrap();
+        for i in 0..1000 {
+            engine.add(phone(i)).unwrap();
+        }
+        engine.delete(phone(7)).unwrap();
+        let snapshot = engine.snapshot();
+        engine.add(phone(7)).unwrap();
+        engine.delete(phone(8)).unwrap();
+        for i in 1000..2000 {
+            engine.add(phone(i)).unwrap();
+        }
+        // both segments were merged while the snapshot was held
+        assert_eq!(engine.segments.len(), 1);
+
+        let (seven, eight) = (sha_hash(&phone(7)), sha_hash(&phone(8)));
+        assert!(engine.get_at(seven, &snapshot).unwrap().is_none());
+        assert!(engine.get(seven).unwrap().is_some());
+        assert!(engine.get_at(eight, &snapshot).unwrap().is_some());
+        assert!(engine.get(eight).unwrap().is_none());
+        assert!(engine.get_at(sha_hash(&phone(1500)), &snapshot).unwrap().is_none());
+        assert_eq!(engine.scan_at(.., &snapshot).unwrap().count(), 999);
+        assert_eq!(engine.scan(..).unwrap().count(), 1999);
+        // the version of 7 no reader can see was compacted away
+        let entries = engine.segments[0].entries().unwrap();
+        assert_eq!(entries.iter().filter(|e| e.data == seven).count(), 2);
+
+        drop(snapshot);
+        assert!(engine.snapshots.live().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_commit_state_proves_revoked_and_unknown_keys() {
+        let dir = test_dir("engine-exclusion");
+        let mut engine = Engine::open(&dir).unwrap();
+        for i in 0..1500 {
+            engine.add(phone(i)).unwrap();
+        }
+        // the tombstone lands in the ring buffer, its key's live record sits in a segment
+        engine.delete(phone(42)).unwrap();
+        let snapshot = engine.snapshot();
+        let state = engine.commit_state(&snapshot).unwrap();
+        assert_eq!(state.len(), 1500);
+        engine.delete(phone(43)).unwrap();
+        assert_eq!(engine.commit_state(&snapshot).unwrap().root(), state.root());
+
+        let hasher = &*engine.options.hasher;
+        let revoked = Digest::from(sha_hash(&phone(42)));
+        let proof = state.prove_exclusion(&revoked).unwrap();
+        assert!(matches!(proof, ExclusionProof::Tombstoned(_)));
+        assert!(proof.verify(hasher, &state.root(), &revoked));
+        let unknown = Digest::from(sha_hash(&phone(1500)));
+        let proof = state.prove_exclusion(&unknown).unwrap();
+        assert!(proof.verify(hasher, &state.root(), &unknown));
+        // 43 was still live at the snapshot
+        assert!(state.prove_exclusion(&sha_hash(&phone(43)).into()).is_none());
+        let now = engine.commit_state(&engine.snapshot()).unwrap();
+        assert!(now.prove_exclusion(&sha_hash(&phone(43)).into()).is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sequence_numbers_continue_after_reopen() {
+        let dir = test_dir("engine-sequence");
+        {
+            let mut engine = Engine::open(&dir).unwrap();
+            for i in 0..1500 {
+                engine.add(phone(i)).unwrap();
+            }
+            assert_eq!(engine.ring_buffer.sequence, 1500);
+        }
+        // 1000 blocks come back from the segment, the rest from the write-ahead log
+        let mut engine = Engine::open(&dir).unwrap();
+        assert_eq!(engine.ring_buffer.sequence, 1500);
+        assert_eq!(engine.get(sha_hash(&phone(999))).unwrap().unwrap().0.seq, 1000);
+        engine.add(phone(0)).unwrap();
+        assert_eq!(engine.get(sha_hash(&phone(0))).unwrap().unwrap().0.seq, 1501);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_payloads_round_trip_through_every_layer() {
+        let dir = test_dir("engine-payload");
+        let record = |i: usize| (format!("nft/{}", i).into_bytes(), vec![i as u8; i % 300]);
+        {
+            let mut engine = Engine::open(&dir).unwrap();
+            for i in 0..1550 {
+                let (key, value) = record(i);
+                engine.put(&key, &value).unwrap();
+            }
+            engine.remove(b"nft/3").unwrap();
+            engine.put(b"nft/1540", b"updated").unwrap();
+            // spread over a segment, the memtable and the ring buffer
+            assert_eq!(engine.segments.len(), 1);
+            assert!(engine.ring_buffer.length() > 0);
+            assert_eq!(engine.get_value(b"nft/1200").unwrap(), Some(record(1200).1));
+        }
+        // the write-ahead log carries payloads too
+        let engine = Engine::open(&dir).unwrap();
+        for i in (0..1550).filter(|&i| i != 3 && i != 1540) {
+            let (key, value) = record(i);
+            assert_eq!(engine.get_value(&key).unwrap(), Some(value));
+        }
+        assert_eq!(engine.get_value(b"nft/3").unwrap(), None);
+        assert_eq!(engine.get_value(b"nft/1540").unwrap(), Some(b"updated".to_vec()));
+        assert_eq!(engine.get_value(b"nft/missing").unwrap(), None);
+
+        let (block, _) = engine.scan(..).unwrap().next().unwrap();
+        assert!(block.key().unwrap().starts_with(b"nft/"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_store_keeps_its_hasher() {
+        let dir = test_dir("engine-hasher");
+        let blake3: Arc<dyn BlockHasher> = Arc::new(Blake3Hasher);
+        {
+            let mut engine =
+                Engine::open_with_hasher(&dir, CompactionOptions::default(), blake3.clone())
+                    .unwrap();
+            for i in 0..1200 {
+                engine.add(phone(i)).unwrap();
+            }
+            assert_eq!(engine.segments[0].footer.min_key.len(), 32);
+            assert!(engine.get(Blake3Hasher.digest(&phone(7))).unwrap().is_some());
+            assert!(engine.get(sha_hash(&phone(7))).unwrap().is_none());
+        }
+        let error = Engine::open(&dir).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+
+        let engine = Engine::open_with_hasher(&dir, CompactionOptions::default(), blake3).unwrap();
+        assert_eq!(engine.get_value(&phone(1100)).unwrap(), Some(Vec::new()));
+        assert!(engine.get(Blake3Hasher.digest(&phone(7))).unwrap().is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
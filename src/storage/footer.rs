@@ -1,6 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+use crate::core::hasher::Digest;
+
+/// Where one block sits in a segment file, and the xxHash32 of its bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockHandle {
+    pub offset: u64,
+    pub length: u64,
+    pub checksum: u32,
+}
+
+impl BlockHandle {
+    /// Byte range of the block within the file.
+    pub fn range(&self) -> std::ops::Range<u64> {
+        self.offset..self.offset + self.length
+    }
+}
+
+/// Fixed size tail of every segment file, read first to find the blocks in front of it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Footer {
     pub magic_number: u32,
+    /// `SSTABLE_FORMAT_VERSION` the file was written with
+    pub format_version: u16,
+    /// `FilterKind::id` of the filter block
+    pub filter: u8,
+    pub filter_handle: BlockHandle,
+    pub index_handle: BlockHandle,
+    pub data_handle: BlockHandle,
+    pub meta_handle: BlockHandle,
+    pub max_key: Digest,
+    pub min_key: Digest,
+    /// xxHash32 of the footer encoded with this field zeroed
     pub checksum: u32,
-    pub max_key: [u8; 16],
-    pub min_key: [u8; 16],
 }
@@ -1,16 +1,95 @@
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+use std::ops::{Bound, Range};
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::hasher::Digest;
+use crate::storage::footer::BlockHandle;
+use crate::storage::iterator::KeyRange;
+
+/// Where one data block of a segment is stored and which keys it covers.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// largest key of the block, every key of the blocks after it is larger
+    pub last_key: Digest,
+    pub handle: BlockHandle,
+    /// position of the block's first entry among all entries of the segment
+    pub first_entry: u64,
+}
+
+/// Sparse index of a segment, one entry per data block in key order.
+/// All versions of a key are written to the same block, so a lookup reads a single block.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct IndexBlock {
-    pub index: [u8; 8],
-    pub hashed_data: [u8; 16],
-    pub offset: usize,
+    pub entries: Vec<IndexEntry>,
 }
 
 impl IndexBlock {
     pub fn new() -> IndexBlock {
-        IndexBlock {
-            index: [0; 8],
-            hashed_data: [0; 16],
-            offset: 0,
+        IndexBlock { entries: Vec::new() }
+    }
+
+    /// Position of the only data block that can hold `key`, None when `key` is larger than
+    /// every key of the segment.
+    pub fn find(&self, key: &Digest) -> Option<usize> {
+        let block = self.entries.partition_point(|e| e.last_key < *key);
+        (block < self.entries.len()).then_some(block)
+    }
+
+    /// Data blocks that may hold keys within `range`, the first and the last of them
+    /// may hold keys outside of it too.
+    pub fn blocks(&self, range: &KeyRange) -> Range<usize> {
+        let start = match range.0 {
+            Bound::Included(s) => self.entries.partition_point(|e| e.last_key < s),
+            Bound::Excluded(s) => self.entries.partition_point(|e| e.last_key <= s),
+            Bound::Unbounded => 0,
+        };
+        // keys of a block are all larger than the last key of the block before it
+        let end = match range.1 {
+            Bound::Included(e) | Bound::Excluded(e) => {
+                (self.entries.partition_point(|i| i.last_key < e) + 1).min(self.entries.len())
+            },
+            Bound::Unbounded => self.entries.len(),
+        };
+        start..end.max(start)
+    }
+
+    /// Range of segment entries stored in data block `block`, given `total` entries.
+    pub fn entry_range(&self, block: usize, total: usize) -> Range<usize> {
+        let end = self.entries.get(block + 1).map_or(total, |e| e.first_entry as usize);
+        self.entries[block].first_entry as usize..end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::iterator::key_range;
+
+    #[test]
+    fn test_find_binary_searches_last_keys() {
+        let mut index = IndexBlock::new();
+        for (i, last) in [10u8, 20, 30].into_iter().enumerate() {
+            index.entries.push(IndexEntry {
+                last_key: [last; 16].into(),
+                handle: BlockHandle::default(),
+                first_entry: i as u64 * 5,
+            });
         }
+        assert_eq!(index.find(&[0; 16].into()), Some(0));
+        assert_eq!(index.find(&[10; 16].into()), Some(0));
+        assert_eq!(index.find(&[11; 16].into()), Some(1));
+        assert_eq!(index.find(&[30; 16].into()), Some(2));
+        assert_eq!(index.find(&[31; 16].into()), None);
+        assert_eq!(index.entry_range(1, 12), 5..10);
+        assert_eq!(index.entry_range(2, 12), 10..12);
+        assert_eq!(IndexBlock::new().find(&[0; 16].into()), None);
+
+        let key = |k: u8| Digest::from([k; 16]);
+        assert_eq!(index.blocks(&key_range(..)), 0..3);
+        assert_eq!(index.blocks(&key_range(key(11)..=key(20))), 1..2);
+        assert_eq!(index.blocks(&key_range(key(10)..key(21))), 0..3);
+        assert_eq!(index.blocks(&key_range(key(5)..key(10))), 0..1);
+        assert_eq!(index.blocks(&key_range(key(31)..)), 3..3);
+        assert_eq!(index.blocks(&(Bound::Excluded(key(10)), Bound::Unbounded)), 1..3);
     }
 }
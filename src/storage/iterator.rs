@@ -0,0 +1,230 @@
+use std::iter::Peekable;
+use std::ops::{Bound, Range, RangeBounds};
+
+use crate::core::block::Block;
+use crate::core::hasher::{Digest, MAX_DIGEST_SIZE};
+use crate::datasource::DataSource;
+
+pub type KeyRange = (Bound<Digest>, Bound<Digest>);
+
+/// Copies any `RangeBounds` over keys into an owned pair of bounds.
+pub fn key_range(range: impl RangeBounds<Digest>) -> KeyRange {
+    (range.start_bound().cloned(), range.end_bound().cloned())
+}
+
+/// Every key starting with `prefix`, whatever the digest width.
+/// Only the first `MAX_DIGEST_SIZE` bytes of it are significant.
+pub fn prefix_range(prefix: &[u8]) -> KeyRange {
+    let len = prefix.len().min(MAX_DIGEST_SIZE);
+    let mut end = [0xFF; MAX_DIGEST_SIZE];
+    end[..len].copy_from_slice(&prefix[..len]);
+    (Bound::Included(Digest::new(&prefix[..len])), Bound::Included(Digest::from(end)))
+}
+
+/// Index range of `items`, sorted by `key`, that falls within `range`.
+pub fn bounds<T>(items: &[T], range: &KeyRange, key: impl Fn(&T) -> Digest) -> Range<usize> {
+    let start = match range.0 {
+        Bound::Included(s) => items.partition_point(|i| key(i) < s),
+        Bound::Excluded(s) => items.partition_point(|i| key(i) <= s),
+        Bound::Unbounded => 0,
+    };
+    let end = match range.1 {
+        Bound::Included(e) => items.partition_point(|i| key(i) <= e),
+        Bound::Excluded(e) => items.partition_point(|i| key(i) < e),
+        Bound::Unbounded => items.len(),
+    };
+    start..end.max(start)
+}
+
+/// Does any key between `min` and `max` fall within `range`.
+pub fn overlaps(range: &KeyRange, min: &Digest, max: &Digest) -> bool {
+    let after_start = match range.0 {
+        Bound::Included(s) => *max >= s,
+        Bound::Excluded(s) => *max > s,
+        Bound::Unbounded => true,
+    };
+    let before_end = match range.1 {
+        Bound::Included(e) => *min <= e,
+        Bound::Excluded(e) => *min < e,
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
+
+/// Collapses runs of equal keys of a sorted source to their last, i.e. latest, entry.
+pub struct LatestPerKey<I: Iterator<Item = Block>> {
+    inner: Peekable<I>,
+}
+
+impl<I: Iterator<Item = Block>> LatestPerKey<I> {
+    pub fn new(inner: I) -> Self {
+        LatestPerKey { inner: inner.peekable() }
+    }
+}
+
+impl<I: Iterator<Item = Block>> Iterator for LatestPerKey<I> {
+    type Item = Block;
+
+    fn next(&mut self) -> Option<Block> {
+        let mut latest = self.inner.next()?;
+        while let Some(next) = self.inner.next_if(|b| b.data == latest.data) {
+            latest = next;
+        }
+        Some(latest)
+    }
+}
+
+type Source<'a> = Peekable<LatestPerKey<Box<dyn Iterator<Item = Block> + 'a>>>;
+
+/// Ordered merge of sorted storage layers.
+/// Sources are added newest first, for every key only the newest source's entry is kept
+/// and keys whose newest entry is a tombstone are skipped.
+pub struct MergingIterator<'a> {
+    sources: Vec<(DataSource, Source<'a>)>,
+    tombstones: bool,
+}
+
+impl<'a> MergingIterator<'a> {
+    pub fn new() -> Self {
+        MergingIterator { sources: Vec::new(), tombstones: false }
+    }
+
+    /// Also yields keys whose newest entry is a tombstone.
+    pub fn with_tombstones(mut self) -> Self {
+        self.tombstones = true;
+        self
+    }
+
+    /// Adds a source sorted by key, older than every source added before.
+    /// Equal keys within a source must be ordered oldest first.
+    pub fn push(&mut self, source: DataSource, blocks: impl Iterator<Item = Block> + 'a) {
+        let blocks: Box<dyn Iterator<Item = Block> + 'a> = Box::new(blocks);
+        self.sources.push((source, LatestPerKey::new(blocks).peekable()));
+    }
+}
+
+impl Default for MergingIterator<'_> {
+    fn default() -> Self {
+        MergingIterator::new()
+    }
+}
+
+impl Iterator for MergingIterator<'_> {
+    type Item = (Block, DataSource);
+
+    fn next(&mut self) -> Option<(Block, DataSource)> {
+        loop {
+            // smallest key across sources, the first (newest) source wins ties
+            let mut newest: Option<(usize, Digest)> = None;
+            for (i, (_, blocks)) in self.sources.iter_mut().enumerate() {
+                if let Some(block) = blocks.peek() {
+                    if newest.is_none_or(|(_, key)| block.data < key) {
+                        newest = Some((i, block.data));
+                    }
+                }
+            }
+            let (i, key) = newest?;
+            let source = self.sources[i].0;
+            let block = self.sources[i].1.next().unwrap();
+            // drop the versions it shadows
+            for (_, blocks) in self.sources.iter_mut() {
+                blocks.next_if(|b| b.data == key);
+            }
+            if !block.disabled || self.tombstones {
+                return Some((block, source));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    use super::*;
+    use crate::core::block::sha_hash;
+    use crate::storage::engine::{Engine, EngineOps};
+    use crate::storage::mem_table::MemTableOps;
+    use crate::storage::ring_buffer::BlockRingBufferOps;
+    use crate::storage::test_dir;
+
+    fn phone(i: u16) -> [u8; 10] {
+        let mut phone = [0u8; 10];
+        phone[..2].copy_from_slice(&i.to_be_bytes());
+        phone
+    }
+
+    #[test]
+    fn test_merge_prefers_newest_source_and_skips_tombstones() {
+        let block = |key: u8, disabled: bool| Block {
+            data: [key; 16].into(),
+            timestamp: 0,
+            seq: 0,
+            disabled,
+            next: None,
+            payload: None,
+        };
+        let mut merged = MergingIterator::new();
+        merged.push(DataSource::RingBuffer, vec![block(2, true), block(4, false)].into_iter());
+        merged.push(
+            DataSource::MemTable,
+            vec![block(1, false), block(3, false), block(3, true)].into_iter(),
+        );
+        merged.push(
+            DataSource::SSTable,
+            vec![block(1, false), block(2, false), block(3, false), block(5, false)].into_iter(),
+        );
+        let keys: Vec<_> = merged.map(|(b, source)| (b.data.as_bytes()[0], source)).collect();
+        assert_eq!(
+            keys,
+            vec![(1, DataSource::MemTable), (4, DataSource::RingBuffer), (5, DataSource::SSTable)]
+        );
+    }
+
+    #[test]
+    fn test_bounds_and_prefix_range() {
+        let key = |i: u8| Digest::from([i; 16]);
+        let keys: Vec<Digest> = (0..10u8).map(key).collect();
+        assert_eq!(bounds(&keys, &key_range(key(2)..key(5)), |k| *k), 2..5);
+        assert_eq!(bounds(&keys, &key_range(key(2)..=key(5)), |k| *k), 2..6);
+        assert_eq!(bounds(&keys, &key_range(..), |k| *k), 0..10);
+        assert_eq!(bounds(&keys, &key_range(key(8)..key(3)), |k| *k), 8..8);
+        assert_eq!(bounds(&keys, &prefix_range(&[7]), |k| *k), 7..8);
+        assert!(overlaps(&prefix_range(&[7]), &key(0), &key(7)));
+        assert!(!overlaps(&key_range(key(8)..), &key(0), &key(7)));
+    }
+
+    #[test]
+    fn test_scan_matches_model_across_layers() {
+        let dir = test_dir("iterator-scan");
+        let mut engine = Engine::open(&dir).unwrap();
+        let mut model = BTreeMap::new();
+        for i in 0..2650u16 {
+            // rewrite and delete older keys so every layer shadows another
+            let key = if i % 3 == 0 { i / 2 } else { i };
+            if i % 7 == 0 {
+                engine.delete(phone(key)).unwrap();
+                model.remove(&Digest::from(sha_hash(&phone(key))));
+            } else {
+                engine.add(phone(key)).unwrap();
+                model.insert(Digest::from(sha_hash(&phone(key))), ());
+            }
+        }
+        assert!(engine.segments.len() >= 2);
+        assert!(engine.mem_table.size() > 0 && engine.ring_buffer.length() > 0);
+
+        let all: Vec<_> = engine.scan(..).unwrap().map(|(b, _)| b.data).collect();
+        assert_eq!(all, model.keys().copied().collect::<Vec<_>>());
+
+        let (start, end) = (Digest::from([0x40; 16]), Digest::from([0xA0; 16]));
+        let ranged: Vec<_> = engine.scan(start..end).unwrap().map(|(b, _)| b.data).collect();
+        assert_eq!(ranged, model.range(start..end).map(|(k, _)| *k).collect::<Vec<_>>());
+
+        let prefixed: Vec<_> = engine.scan_prefix(&[0x7F]).unwrap().map(|(b, _)| b.data).collect();
+        assert!(!prefixed.is_empty());
+        assert!(prefixed.iter().all(|k| k.as_bytes()[0] == 0x7F));
+        assert_eq!(prefixed.len(), model.keys().filter(|k| k.as_bytes()[0] == 0x7F).count());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::hasher::Digest;
+use crate::storage::membership_filter::MembershipFilter;
+use crate::storage::ss_table::SSTableSegment;
+use crate::storage::ss_table_reader::SSTableReader;
+use crate::storage::wal::{decode_record, encode_record, is_incomplete_record, replace_file};
+
+pub const MANIFEST_FILE: &str = "MANIFEST";
+/// bumped whenever `ManifestRecord` changes shape
+pub const MANIFEST_FORMAT_VERSION: u16 = 3;
+/// edits appended before the log is folded into a single snapshot record
+pub const SNAPSHOT_INTERVAL: usize = 64;
+
+/// What the manifest knows about one live segment file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SegmentMeta {
+    pub file_name: String,
+    pub level: u8,
+    pub min_key: Digest,
+    pub max_key: Digest,
+    pub entry_count: u64,
+    pub created_at: i64,
+    /// `FilterKind::id` of the segment filter
+    pub filter: u8,
+    /// segment filter size in bits
+    pub filter_bits: u32,
+}
+
+impl From<&SSTableSegment> for SegmentMeta {
+    fn from(segment: &SSTableSegment) -> SegmentMeta {
+        SegmentMeta {
+            file_name: file_name(&segment.path),
+            level: segment.meta_block.level,
+            min_key: segment.footer.min_key,
+            max_key: segment.footer.max_key,
+            entry_count: segment.meta_block.entry_count,
+            created_at: segment.meta_block.created_at,
+            filter: segment.footer.filter,
+            filter_bits: segment.filter.bit_size() as u32,
+        }
+    }
+}
+
+impl From<&SSTableReader> for SegmentMeta {
+    fn from(segment: &SSTableReader) -> SegmentMeta {
+        SegmentMeta {
+            file_name: file_name(&segment.path),
+            level: segment.meta_block.level,
+            min_key: segment.footer.min_key,
+            max_key: segment.footer.max_key,
+            entry_count: segment.meta_block.entry_count,
+            created_at: segment.meta_block.created_at,
+            filter: segment.footer.filter,
+            filter_bits: segment.filter.bit_size() as u32,
+        }
+    }
+}
+
+/// One atomic change to the live segment set. A snapshot record replaces the whole set,
+/// an edit record adds and removes segments relative to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestRecord {
+    pub format_version: u16,
+    /// monotonically increasing across the lifetime of the manifest
+    pub version: u64,
+    /// `BlockHasher::id` every segment of the store was keyed with
+    pub hasher: u8,
+    pub snapshot: bool,
+    pub added: Vec<SegmentMeta>,
+    pub removed: Vec<String>,
+}
+
+/// Versioned, checksummed log of the live `sstable-*.segment` files.
+/// Records use the write-ahead log framing (`len | xxh32 | bincode`), each one is synced before
+/// the change it describes becomes visible, so a segment is live exactly when the manifest says so.
+/// Every `SNAPSHOT_INTERVAL` edits the log is atomically replaced by one snapshot record.
+pub struct Manifest {
+    pub path: PathBuf,
+    pub version: u64,
+    /// `BlockHasher::id` of the store, None until the first record is written
+    pub hasher: Option<u8>,
+    /// live segments in the order they were added
+    pub segments: Vec<SegmentMeta>,
+    edits_since_snapshot: usize,
+    file: File,
+}
+
+pub trait ManifestOps: Sized {
+    fn exists(dir: &Path) -> bool;
+    /// Opens `MANIFEST` in `dir`, creating an empty one, and replays it into the live set.
+    fn open(dir: &Path) -> std::result::Result<Self, Error>;
+    /// Durably records segments added and removed together, e.g. by a compaction.
+    fn apply(
+        &mut self, added: &[SegmentMeta], removed: &[SegmentMeta],
+    ) -> std::result::Result<(), Error>;
+    /// Replaces the log with a single record of the live set.
+    fn snapshot(&mut self) -> std::result::Result<(), Error>;
+    fn contains(&self, file_name: &str) -> bool;
+}
+
+impl ManifestOps for Manifest {
+    fn exists(dir: &Path) -> bool {
+        dir.join(MANIFEST_FILE).exists()
+    }
+
+    fn open(dir: &Path) -> std::result::Result<Manifest, Error> {
+        let path = dir.join(MANIFEST_FILE);
+        let bytes = if path.exists() { fs::read(&path)? } else { Vec::new() };
+        let mut manifest = Manifest {
+            file: OpenOptions::new().read(true).append(true).create(true).open(&path)?,
+            path,
+            version: 0,
+            hasher: None,
+            segments: Vec::new(),
+            edits_since_snapshot: 0,
+        };
+        let mut offset = 0;
+        while let Some((record, len)) = decode_record::<ManifestRecord>(&bytes[offset..]) {
+            if record.format_version != MANIFEST_FORMAT_VERSION {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unsupported manifest format version {}", record.format_version),
+                ));
+            }
+            manifest.replay(record);
+            offset += len;
+        }
+        if offset < bytes.len() {
+            // a whole record failing its checksum is damage, cutting there would drop
+            // every later edit and leave their segments to be deleted as orphans
+            if !is_incomplete_record(&bytes[offset..]) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("corrupted manifest record at offset {}", offset),
+                ));
+            }
+            // torn tail of an edit that never committed
+            manifest.file.set_len(offset as u64)?;
+            manifest.file.sync_data()?;
+        }
+        Ok(manifest)
+    }
+
+    fn apply(
+        &mut self, added: &[SegmentMeta], removed: &[SegmentMeta],
+    ) -> std::result::Result<(), Error> {
+        let record = ManifestRecord {
+            format_version: MANIFEST_FORMAT_VERSION,
+            version: self.version + 1,
+            hasher: self.hasher.unwrap_or_default(),
+            snapshot: false,
+            added: added.to_vec(),
+            removed: removed.iter().map(|s| s.file_name.clone()).collect(),
+        };
+        self.file.write_all(&encode_record(&record)?)?;
+        self.file.sync_data()?;
+        self.replay(record);
+        if self.edits_since_snapshot >= SNAPSHOT_INTERVAL {
+            self.snapshot()?;
+        }
+        Ok(())
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<(), Error> {
+        let record = ManifestRecord {
+            format_version: MANIFEST_FORMAT_VERSION,
+            version: self.version + 1,
+            hasher: self.hasher.unwrap_or_default(),
+            snapshot: true,
+            added: self.segments.clone(),
+            removed: Vec::new(),
+        };
+        replace_file(&self.path, &encode_record(&record)?)?;
+        self.file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+        self.replay(record);
+        Ok(())
+    }
+
+    fn contains(&self, file_name: &str) -> bool {
+        self.segments.iter().any(|s| s.file_name == file_name)
+    }
+}
+
+impl Manifest {
+    fn replay(&mut self, record: ManifestRecord) {
+        if record.snapshot {
+            self.segments.clear();
+            self.edits_since_snapshot = 0;
+        } else {
+            self.edits_since_snapshot += 1;
+        }
+        self.segments.retain(|s| !record.removed.contains(&s.file_name));
+        self.segments.extend(record.added);
+        self.version = record.version;
+        self.hasher = Some(record.hasher);
+    }
+}
+
+pub fn file_name(path: &Path) -> String {
+    path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use super::*;
+    use crate::core::skip_list::{SkipList, SkipListOps};
+    use crate::storage::ss_table::SSTableSegmentOps;
+    use crate::storage::test_dir;
+
+    fn segment(dir: &Path, key: u8) -> SegmentMeta {
+        let mut skip_list = SkipList::init();
+        skip_list.add([key; 16].into(), 0, false);
+        skip_list.add([key + 1; 16].into(), 1, false);
+        SegmentMeta::from(&SSTableSegment::create(dir, &skip_list).unwrap())
+    }
+
+    #[test]
+    fn test_edits_survive_reopen() {
+        let dir = test_dir("manifest-reopen");
+        let a = segment(&dir, 1);
+        let b = segment(&dir, 5);
+        let c = segment(&dir, 9);
+        let mut manifest = Manifest::open(&dir).unwrap();
+        manifest.apply(slice::from_ref(&a), &[]).unwrap();
+        manifest.apply(slice::from_ref(&b), &[]).unwrap();
+        // compaction swaps both for c in one edit
+        manifest.apply(slice::from_ref(&c), &[a.clone(), b.clone()]).unwrap();
+        assert_eq!(manifest.version, 3);
+
+        let reopened = Manifest::open(&dir).unwrap();
+        assert_eq!(reopened.version, 3);
+        assert_eq!(reopened.segments, vec![c.clone()]);
+        assert_eq!(reopened.segments[0].entry_count, 2);
+        assert_eq!(reopened.segments[0].min_key, [9; 16]);
+        assert_eq!(reopened.segments[0].max_key, [10; 16]);
+        assert_eq!(reopened.segments[0].filter_bits, c.filter_bits);
+        assert!(reopened.contains(&c.file_name));
+        assert!(!reopened.contains(&a.file_name));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_periodic_snapshot_folds_log() {
+        let dir = test_dir("manifest-snapshot");
+        let segments: Vec<_> = (0..4).map(|i| segment(&dir, i * 3)).collect();
+        let mut manifest = Manifest::open(&dir).unwrap();
+        manifest.apply(&segments[..1], &[]).unwrap();
+        for _ in 1..SNAPSHOT_INTERVAL {
+            manifest.apply(&segments[1..2], &segments[1..2]).unwrap();
+        }
+        // the snapshot replaced every edit with a single record
+        let bytes = fs::read(&manifest.path).unwrap();
+        let (record, len) = decode_record::<ManifestRecord>(&bytes).unwrap();
+        assert!(record.snapshot);
+        assert_eq!(len, bytes.len());
+
+        manifest.apply(&segments[2..3], &[]).unwrap();
+        let reopened = Manifest::open(&dir).unwrap();
+        assert_eq!(reopened.version, SNAPSHOT_INTERVAL as u64 + 2);
+        let names: Vec<_> = reopened.segments.iter().map(|s| s.file_name.clone()).collect();
+        assert_eq!(names, [0, 1, 2].map(|i| segments[i].file_name.clone()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_edit_is_discarded() {
+        let dir = test_dir("manifest-torn");
+        let a = segment(&dir, 1);
+        let b = segment(&dir, 5);
+        let mut manifest = Manifest::open(&dir).unwrap();
+        manifest.apply(slice::from_ref(&a), &[]).unwrap();
+        manifest.apply(slice::from_ref(&b), &[]).unwrap();
+        let len = fs::metadata(&manifest.path).unwrap().len();
+        manifest.file.set_len(len - 3).unwrap();
+
+        let reopened = Manifest::open(&dir).unwrap();
+        assert_eq!(reopened.segments, vec![a.clone()]);
+        assert_eq!(reopened.version, 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_middle_record_is_an_error() {
+        let dir = test_dir("manifest-corrupt");
+        let a = segment(&dir, 1);
+        let b = segment(&dir, 5);
+        let mut manifest = Manifest::open(&dir).unwrap();
+        manifest.apply(slice::from_ref(&a), &[]).unwrap();
+        manifest.apply(slice::from_ref(&b), &[]).unwrap();
+        manifest.apply(&[], slice::from_ref(&a)).unwrap();
+
+        let mut bytes = fs::read(&manifest.path).unwrap();
+        let (_, first) = decode_record::<ManifestRecord>(&bytes).unwrap();
+        bytes[first + 12] ^= 0xFF;
+        fs::write(&manifest.path, &bytes).unwrap();
+        let err = Manifest::open(&dir).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        // the later edits are still there to be repaired
+        assert_eq!(fs::read(&manifest.path).unwrap(), bytes);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,22 +1,152 @@
-use crate::core::skip_list::{SkipList, SkipListOps};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use crate::core::accumulator::MultisetHash;
+use crate::core::block::Block;
+use crate::core::hasher::Digest;
+use crate::core::skip_list::{SkipList, SkipListOps, SkipNode};
+use crate::storage::membership_filter::FilterKind;
+use crate::storage::options::Options;
+use crate::storage::ss_table::{SSTableSegment, SSTableSegmentOps};
 
 pub struct MemTable {
      /// stores 10 ringbuffers worth data
     pub blocks: SkipList,
     pub last_flushed: i64,
+    /// multiset hash of every block inserted since the last flush
+    pub accumulator: MultisetHash,
+    /// filter of the segments it flushes to
+    pub filter: FilterKind,
 }
 pub trait MemTableOps {
     fn new() -> Self;
-    fn add(&mut self, phone_number: &[u8; 16], tombstone_marker: bool) -> bool;
+    fn add(&mut self, key: Digest, seq: u64, tombstone_marker: bool) -> bool;
+    /// Adds a buffered block together with its payload.
+    fn insert(&mut self, block: Block) -> bool;
+    fn size(&self) -> usize;
+    fn capacity(&self) -> usize;
+    fn is_full(&self) -> bool;
+    fn search(&self, key: Digest) -> Option<Block>;
+    /// Latest entry for `key` written at or before `seq`.
+    fn search_at(&self, key: Digest, seq: u64) -> Option<Block>;
+    /// Persists the memtable as a new `SSTableSegment` in `dir` and resets it.
+    fn flush(&mut self, dir: &Path) -> std::result::Result<SSTableSegment, Error>;
 }
 impl MemTableOps for MemTable {
     fn new() -> Self {
+        MemTable::with_options(&Options::default())
+    }
+    fn add(&mut self, key: Digest, seq: u64, tombstone_marker: bool) -> bool {
+        self.insert(Block {
+            data: key,
+            timestamp: 0,
+            seq,
+            disabled: tombstone_marker,
+            next: None,
+            payload: None,
+        })
+    }
+
+    fn insert(&mut self, block: Block) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.accumulator.insert(&block);
+        self.blocks.insert(SkipNode::from(block))
+    }
+
+    fn size(&self) -> usize {
+        self.blocks.size()
+    }
+
+    fn capacity(&self) -> usize {
+        self.blocks.capacity()
+    }
+
+    fn is_full(&self) -> bool {
+        self.blocks.size() == self.blocks.capacity()
+    }
+
+    fn search(&self, key: Digest) -> Option<Block> {
+        self.blocks.search(key).map(Block::from)
+    }
+
+    fn search_at(&self, key: Digest, seq: u64) -> Option<Block> {
+        self.blocks.search_at(key, seq).map(Block::from)
+    }
+
+    fn flush(&mut self, dir: &Path) -> std::result::Result<SSTableSegment, Error> {
+        let entries = self.blocks.iter().cloned().collect();
+        let now = chrono::Utc::now().timestamp_millis();
+        let segment = SSTableSegment::create_from_entries(dir, entries, 0, now, self.filter)?;
+        if segment.meta_block.accumulator != self.accumulator {
+            // not in the manifest yet, a file left behind is removed as an orphan on open
+            let _ = fs::remove_file(&segment.path);
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("segment {:?} does not hold what the memtable accumulated", segment.path),
+            ));
+        }
+        // only reset once the segment is safely on disk
+        self.blocks.flush();
+        self.accumulator = MultisetHash::new();
+        self.last_flushed = chrono::Utc::now().timestamp_millis();
+        Ok(segment)
+    }
+}
+
+impl MemTable {
+    /// Memtable sized by `options`.
+    pub fn with_options(options: &Options) -> Self {
         MemTable {
-            blocks: SkipList::init(),
+            blocks: SkipList::with_capacity(options.mem_table_capacity, options.max_level),
             last_flushed: 0,
+            accumulator: MultisetHash::new(),
+            filter: options.filter,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::skip_list::CAPACITY;
+    use crate::storage::test_dir;
+
+    #[test]
+    fn test_flush_full_memtable() {
+        let dir = test_dir("memtable-flush");
+        let mut mt = MemTable::new();
+        for i in 0..CAPACITY as u16 {
+            let mut key = [0u8; 16];
+            key[..2].copy_from_slice(&i.to_be_bytes());
+            assert!(mt.add(key.into(), i as u64, false));
         }
+        assert!(mt.is_full());
+        assert!(!mt.add([0xFF; 16].into(), CAPACITY as u64, false));
+
+        let segment = mt.flush(&dir).unwrap();
+        assert_eq!(segment.data_block.entries.len(), CAPACITY);
+        assert_eq!(mt.size(), 0);
+        assert!(mt.last_flushed > 0);
+        let reopened = SSTableSegment::open(&segment.path).unwrap();
+        assert_eq!(reopened.data_block.entries.len(), CAPACITY);
+        std::fs::remove_dir_all(dir).unwrap();
     }
-    fn add(&mut self, phone_number: &[u8; 16], tombstone_marker: bool) -> bool {
-        return self.blocks.add(phone_number, tombstone_marker);
+
+    #[test]
+    fn test_mismatched_accumulator_leaves_no_segment() {
+        let dir = test_dir("memtable-accumulator");
+        let mut mt = MemTable::new();
+        assert!(mt.add([1; 16].into(), 1, false));
+        // a block the memtable never stored
+        mt.accumulator.insert(&Block::new([2; 10], false));
+
+        let error = mt.flush(&dir).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        assert_eq!(mt.size(), 1);
+        fs::remove_dir_all(dir).unwrap();
     }
 }
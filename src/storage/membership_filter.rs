@@ -0,0 +1,200 @@
+use std::io::{Error, ErrorKind};
+
+use serde::Deserialize;
+
+use crate::core::hasher::Digest;
+use crate::storage::blocked_bloom_filter::{BlockedBloomFilter, BlockedBloomFilterOps};
+use crate::storage::bloom_filter::{BloomFilter, BloomFilterOps, BLOOM_BITS_PER_KEY};
+use crate::storage::cuckoo_filter::{CuckooFilter, CuckooFilterOps};
+use crate::storage::xor_filter::XorFilter;
+
+/// Answers whether a key may be in a set without false negatives,
+/// what a segment asks before it searches its data block.
+pub trait MembershipFilter {
+    /// false means `hashed` is not in the set, true means it probably is
+    fn may_contain(&self, hashed: &Digest) -> bool;
+    /// size of the filter in bits
+    fn bit_size(&self) -> usize;
+}
+
+impl MembershipFilter for BloomFilter {
+    fn may_contain(&self, hashed: &Digest) -> bool {
+        BloomFilterOps::may_contain(self, hashed)
+    }
+
+    fn bit_size(&self) -> usize {
+        self.bits.len() * 8
+    }
+}
+
+impl MembershipFilter for BlockedBloomFilter {
+    fn may_contain(&self, hashed: &Digest) -> bool {
+        BlockedBloomFilterOps::may_contain(self, hashed)
+    }
+
+    fn bit_size(&self) -> usize {
+        self.blocks.len() * 512
+    }
+}
+
+impl MembershipFilter for XorFilter {
+    fn may_contain(&self, hashed: &Digest) -> bool {
+        XorFilter::may_contain(self, hashed)
+    }
+
+    fn bit_size(&self) -> usize {
+        self.fingerprints.len() * 8
+    }
+}
+
+impl MembershipFilter for CuckooFilter {
+    fn may_contain(&self, hashed: &Digest) -> bool {
+        CuckooFilterOps::may_contain(self, hashed)
+    }
+
+    fn bit_size(&self) -> usize {
+        self.buckets.len() * 64
+    }
+}
+
+/// Filter a segment is written with, see `Options::filter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterKind {
+    /// `bits_per_key` bits per entry, 10 give about 1% false positives
+    Bloom { bits_per_key: usize },
+    /// bloom filter confined to one cache line per key, slightly less accurate
+    BlockedBloom { bits_per_key: usize },
+    /// about 9.8 bits per key for 0.4% false positives
+    Xor,
+    /// about 17 bits per key for 0.01% false positives
+    Cuckoo,
+}
+
+impl FilterKind {
+    /// Tag stored in the segment footer.
+    pub fn id(&self) -> u8 {
+        match self {
+            FilterKind::Bloom { .. } => 0,
+            FilterKind::BlockedBloom { .. } => 1,
+            FilterKind::Xor => 2,
+            FilterKind::Cuckoo => 3,
+        }
+    }
+}
+
+impl Default for FilterKind {
+    fn default() -> Self {
+        FilterKind::Bloom { bits_per_key: BLOOM_BITS_PER_KEY }
+    }
+}
+
+/// Filter of one segment, whichever kind it was written with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SegmentFilter {
+    Bloom(BloomFilter),
+    BlockedBloom(BlockedBloomFilter),
+    Xor(XorFilter),
+    Cuckoo(CuckooFilter),
+}
+
+impl SegmentFilter {
+    /// Filter of `kind` holding `keys`.
+    pub fn build(kind: FilterKind, keys: &[Digest]) -> Self {
+        match kind {
+            FilterKind::Bloom { bits_per_key } => {
+                let mut filter = BloomFilter::with_capacity(keys.len(), bits_per_key);
+                keys.iter().for_each(|k| filter.add(k));
+                SegmentFilter::Bloom(filter)
+            },
+            FilterKind::BlockedBloom { bits_per_key } => {
+                let mut filter = BlockedBloomFilter::with_capacity(keys.len(), bits_per_key);
+                keys.iter().for_each(|k| filter.add(k));
+                SegmentFilter::BlockedBloom(filter)
+            },
+            FilterKind::Xor => SegmentFilter::Xor(XorFilter::new(keys)),
+            FilterKind::Cuckoo => SegmentFilter::Cuckoo(CuckooFilter::from_keys(keys)),
+        }
+    }
+
+    /// `FilterKind::id` of the filter.
+    pub fn id(&self) -> u8 {
+        match self {
+            SegmentFilter::Bloom(_) => 0,
+            SegmentFilter::BlockedBloom(_) => 1,
+            SegmentFilter::Xor(_) => 2,
+            SegmentFilter::Cuckoo(_) => 3,
+        }
+    }
+
+    pub fn encode(&self) -> std::result::Result<Vec<u8>, Error> {
+        let bytes = match self {
+            SegmentFilter::Bloom(filter) => bincode::serialize(filter),
+            SegmentFilter::BlockedBloom(filter) => bincode::serialize(filter),
+            SegmentFilter::Xor(filter) => bincode::serialize(filter),
+            SegmentFilter::Cuckoo(filter) => bincode::serialize(filter),
+        };
+        bytes.map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Reads a filter of the kind tagged `id` from the front of `reader`.
+    pub fn decode(id: u8, reader: &mut &[u8]) -> std::result::Result<SegmentFilter, Error> {
+        match id {
+            0 => read(reader).map(SegmentFilter::Bloom),
+            1 => read(reader).map(SegmentFilter::BlockedBloom),
+            2 => read(reader).map(SegmentFilter::Xor),
+            3 => read(reader).map(SegmentFilter::Cuckoo),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("unknown filter kind {}", id))),
+        }
+    }
+}
+
+impl MembershipFilter for SegmentFilter {
+    fn may_contain(&self, hashed: &Digest) -> bool {
+        match self {
+            SegmentFilter::Bloom(filter) => MembershipFilter::may_contain(filter, hashed),
+            SegmentFilter::BlockedBloom(filter) => MembershipFilter::may_contain(filter, hashed),
+            SegmentFilter::Xor(filter) => MembershipFilter::may_contain(filter, hashed),
+            SegmentFilter::Cuckoo(filter) => MembershipFilter::may_contain(filter, hashed),
+        }
+    }
+
+    fn bit_size(&self) -> usize {
+        match self {
+            SegmentFilter::Bloom(filter) => filter.bit_size(),
+            SegmentFilter::BlockedBloom(filter) => filter.bit_size(),
+            SegmentFilter::Xor(filter) => filter.bit_size(),
+            SegmentFilter::Cuckoo(filter) => filter.bit_size(),
+        }
+    }
+}
+
+fn read<T: for<'de> Deserialize<'de>>(reader: &mut &[u8]) -> std::result::Result<T, Error> {
+    bincode::deserialize_from(reader).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::hasher::{BlockHasher, TruncatedSha256Hasher};
+
+    #[test]
+    fn test_every_kind_roundtrips_without_false_negatives() {
+        let keys: Vec<Digest> =
+            (0..500u32).map(|i| TruncatedSha256Hasher.digest(&i.to_be_bytes())).collect();
+        let kinds = [
+            FilterKind::default(),
+            FilterKind::BlockedBloom { bits_per_key: 10 },
+            FilterKind::Xor,
+            FilterKind::Cuckoo,
+        ];
+        for kind in kinds {
+            let filter = SegmentFilter::build(kind, &keys);
+            assert_eq!(filter.id(), kind.id());
+            assert!(keys.iter().all(|k| filter.may_contain(k)));
+            let bytes = filter.encode().unwrap();
+            let decoded = SegmentFilter::decode(kind.id(), &mut &bytes[..]).unwrap();
+            assert_eq!(decoded, filter);
+        }
+        assert!(SegmentFilter::decode(9, &mut &[0u8; 8][..]).is_err());
+    }
+}
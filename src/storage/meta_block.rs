@@ -1,4 +1,18 @@
+use serde::{Deserialize, Serialize};
+
+use crate::core::accumulator::MultisetHash;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MetaBlock {
     pub tombstone: bool,
-    pub cumulative_hash: [u8; 16],
+    /// multiset hash of every entry, equal to what the memtable accumulated before the flush
+    pub accumulator: MultisetHash,
+    /// compaction level, freshly flushed segments start at 0
+    pub level: u8,
+    /// millis of the newest data in the segment, orders segments of the same level
+    pub created_at: i64,
+    /// entries across all data blocks
+    pub entry_count: u64,
+    /// highest sequence number of any entry, new writes are numbered after it on open
+    pub max_seq: u64,
 }
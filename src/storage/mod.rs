@@ -1,8 +1,34 @@
+pub mod blocked_bloom_filter;
 pub mod bloom_filter;
+pub mod compaction;
+pub mod concurrent_mem_table;
+pub mod concurrent_ring_buffer;
+pub mod cuckoo_filter;
 pub mod data_block;
+pub mod engine;
 pub mod footer;
 pub mod index_block;
+pub mod iterator;
+pub mod manifest;
 pub mod mem_table;
+pub mod membership_filter;
 pub mod meta_block;
+pub mod options;
 pub mod ring_buffer;
+pub mod snapshot;
 pub mod ss_table;
+pub mod ss_table_reader;
+pub mod wal;
+pub mod xor_filter;
+
+/// Fresh scratch directory for tests that write segment files.
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "onechain-{}-{}",
+        name,
+        chrono::Utc::now().timestamp_nanos_opt().unwrap()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
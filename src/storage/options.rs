@@ -0,0 +1,133 @@
+use std::sync::Arc;
+
+use derive_builder::Builder;
+
+use crate::core::hasher::{BlockHasher, TruncatedSha256Hasher};
+use crate::core::skip_list::{CAPACITY, MAX_LEVEL};
+use crate::storage::compaction::CompactionOptions;
+use crate::storage::membership_filter::FilterKind;
+use crate::storage::ring_buffer::RING_BUFFER_CAPACITY;
+
+/// Sizing of one database instance, fixed once it is opened.
+/// Built with `OptionsBuilder`, every field left unset keeps its default,
+/// e.g. `OptionsBuilder::default().mem_table_capacity(10_000).build()`.
+#[derive(Clone, Builder)]
+#[builder(default, build_fn(validate = "Self::validate"))]
+pub struct Options {
+    /// blocks buffered before they move to the memtable as one batch
+    pub ring_buffer_capacity: usize,
+    /// entries a memtable holds before it is flushed to a segment
+    pub mem_table_capacity: usize,
+    /// skip list layers, searches stay logarithmic up to 4^max_level entries
+    pub max_level: usize,
+    /// filter written into every segment, a bloom filter of 10 bits per key by default
+    pub filter: FilterKind,
+    pub compaction: CompactionOptions,
+    /// digests every key, fixed for the lifetime of the store
+    pub hasher: Arc<dyn BlockHasher>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            ring_buffer_capacity: RING_BUFFER_CAPACITY,
+            mem_table_capacity: CAPACITY,
+            max_level: MAX_LEVEL,
+            filter: FilterKind::default(),
+            compaction: CompactionOptions::default(),
+            hasher: Arc::new(TruncatedSha256Hasher),
+        }
+    }
+}
+
+impl OptionsBuilder {
+    fn validate(&self) -> std::result::Result<(), String> {
+        let defaults = Options::default();
+        let ring_buffer = self.ring_buffer_capacity.unwrap_or(defaults.ring_buffer_capacity);
+        let mem_table = self.mem_table_capacity.unwrap_or(defaults.mem_table_capacity);
+        if ring_buffer == 0 || mem_table == 0 {
+            return Err("ring buffer and memtable need room for at least one block".to_string());
+        }
+        // a full ring buffer is drained into the memtable in one go,
+        // so the memtable fills up exactly at the end of a drain
+        if !mem_table.is_multiple_of(ring_buffer) {
+            return Err(format!(
+                "memtable of {} blocks is not a multiple of the ring buffer of {}",
+                mem_table, ring_buffer
+            ));
+        }
+        if self.max_level == Some(0) {
+            return Err("skip list needs at least one layer".to_string());
+        }
+        if let Some(
+            FilterKind::Bloom { bits_per_key: 0 } | FilterKind::BlockedBloom { bits_per_key: 0 },
+        ) = self.filter
+        {
+            return Err("bloom filter needs at least one bit per key".to_string());
+        }
+        if let Some(compaction) = &self.compaction {
+            compaction.strategy.validate()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::hasher::Blake3Hasher;
+    use crate::storage::compaction::CompactionStrategy;
+
+    #[test]
+    fn test_builder_keeps_defaults_and_validates() {
+        let options = OptionsBuilder::default()
+            .ring_buffer_capacity(500)
+            .mem_table_capacity(5000)
+            .hasher(Arc::new(Blake3Hasher))
+            .build()
+            .unwrap();
+        assert_eq!(options.mem_table_capacity, 5000);
+        assert_eq!(options.max_level, MAX_LEVEL);
+        assert_eq!(options.filter, FilterKind::default());
+        assert_eq!(options.hasher.id(), Blake3Hasher.id());
+
+        let too_big = OptionsBuilder::default().ring_buffer_capacity(2000).build();
+        assert!(matches!(too_big, Err(OptionsBuilderError::ValidationError(_))));
+        assert!(OptionsBuilder::default()
+            .ring_buffer_capacity(30)
+            .mem_table_capacity(40)
+            .build()
+            .is_err());
+        assert!(OptionsBuilder::default().mem_table_capacity(0).build().is_err());
+        assert!(OptionsBuilder::default().ring_buffer_capacity(0).build().is_err());
+        assert!(OptionsBuilder::default().max_level(0).build().is_err());
+        assert!(OptionsBuilder::default()
+            .filter(FilterKind::Bloom { bits_per_key: 0 })
+            .build()
+            .is_err());
+        assert!(OptionsBuilder::default()
+            .filter(FilterKind::BlockedBloom { bits_per_key: 0 })
+            .build()
+            .is_err());
+        let single_segment_runs = CompactionOptions {
+            strategy: CompactionStrategy::SizeTiered {
+                min_threshold: 1,
+                max_threshold: 32,
+                bucket_low: 0.5,
+                bucket_high: 1.5,
+            },
+            ..CompactionOptions::default()
+        };
+        assert!(OptionsBuilder::default().compaction(single_segment_runs).build().is_err());
+        let no_fanout = CompactionOptions {
+            strategy: CompactionStrategy::Leveled {
+                level0_limit: 4,
+                fanout: 1,
+                base_level_entries: 10 * CAPACITY,
+                target_segment_entries: CAPACITY,
+            },
+            ..CompactionOptions::default()
+        };
+        assert!(OptionsBuilder::default().compaction(no_fanout).build().is_err());
+    }
+}
@@ -1,10 +1,15 @@
-use crate::core::{block::*, quick_sort};
+#[cfg(test)]
+use crate::core::accumulator::MultisetHash;
+use crate::core::block::*;
+use crate::core::hasher::Digest;
 use proptest::prelude::*;
 use sha2::*;
 use std::time::Instant;
 
 use super::mem_table::{MemTable, MemTableOps};
 
+/// default number of buffered blocks, see `Options::ring_buffer_capacity`
+pub const RING_BUFFER_CAPACITY: usize = 100;
 const PADDING: [u8; 56] = [0; 56];
 #[repr(align(64))] // align to 64 bytes for cache line alignment
 pub struct AlignedPosition {
@@ -13,46 +18,74 @@ pub struct AlignedPosition {
 }
 
 pub struct BlockRingBuffer {
-    pub cumulative_hash: [u8; 16],
-    pub bitmap: [u8; 13],
-    pub blocks: [Option<Block>; 100], // pre-allocate fixed length array of blocks
+    pub bitmap: Vec<u8>,
+    pub blocks: Vec<Option<Block>>, // pre-allocate fixed length array of blocks
     pub head: AlignedPosition,
     pub tail: AlignedPosition,
     pub size: usize,
     pub capacity: usize,
+    /// last sequence number handed out, every buffered block gets the next one
+    pub sequence: u64,
 }
 pub trait BlockRingBufferOps {
     fn add(&mut self, phone_number: [u8; 10]) -> bool;
     /// tombstone the block
     fn delete(&mut self, phone_number: [u8; 10]) -> bool;
-    /// Reader flushes the read blocks from ring buffer
+    /// Appends an already built block, used when replaying the write-ahead log
+    fn push(&mut self, block: Block) -> bool;
+    /// Reader flushes the read blocks from ring buffer once it reached capacity
     fn flush(&mut self, memtable: &mut MemTable) -> bool;
+    /// Moves every buffered block into the memtable in insertion order and empties the buffer,
+    /// returns false when there is nothing to drain or the memtable has no room for it
+    fn drain(&mut self, memtable: &mut MemTable) -> bool;
     fn length(&self) -> usize;
+    /// Latest block buffered for the hashed phone number, tombstones included
+    fn search(&self, key: Digest) -> Option<Block>;
+    /// Latest block buffered for the hashed phone number at or before `seq`
+    fn search_at(&self, key: Digest, seq: u64) -> Option<Block>;
 }
 
 impl BlockRingBuffer {
     pub fn new() -> Self {
+        BlockRingBuffer::with_capacity(RING_BUFFER_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
         BlockRingBuffer {
-            cumulative_hash: [0; 16],
-            bitmap: [0; 13],
-            blocks: [None; 100],
+            bitmap: vec![0; capacity.div_ceil(8)],
+            blocks: (0..capacity).map(|_| None).collect(),
             head: AlignedPosition { data: None, padding: PADDING },
             tail: AlignedPosition { data: None, padding: PADDING },
             size: 0,
-            capacity: 100,
+            capacity,
+            sequence: 0,
         }
     }
+
+    /// Buffered blocks in insertion order, head -> tail.
+    pub fn iter(&self) -> impl Iterator<Item = &Block> {
+        let tail = self.tail.data;
+        std::iter::successors(self.head.data, move |&i| {
+            if Some(i) == tail {
+                None
+            } else {
+                self.blocks[i].as_ref().unwrap().next
+            }
+        })
+        .map(|i| self.blocks[i].as_ref().unwrap())
+    }
 }
 impl BlockRingBufferOps for BlockRingBuffer {
     fn add(&mut self, phone_number: [u8; 10]) -> bool {
-        let new_block = Block::new(phone_number, false);
-        self._add(phone_number, new_block);
-        true
+        self.push(Block::new(phone_number, false))
     }
 
     fn delete(&mut self, phone_number: [u8; 10]) -> bool {
-        let new_block = Block::new(phone_number, true);
-        self._add(phone_number, new_block);
+        self.push(Block::new(phone_number, true))
+    }
+
+    fn push(&mut self, block: Block) -> bool {
+        self._add(block);
         true
     }
 
@@ -60,46 +93,59 @@ impl BlockRingBufferOps for BlockRingBuffer {
         self.size
     }
 
+    fn search(&self, key: Digest) -> Option<Block> {
+        self.search_at(key, u64::MAX)
+    }
+
+    fn search_at(&self, key: Digest, seq: u64) -> Option<Block> {
+        // a later match shadows an earlier one
+        self.iter().filter(|b| b.data == key && b.seq <= seq).last().cloned()
+    }
+
     fn flush(&mut self, mt: &mut MemTable) -> bool {
-        if self.size <= 1 || self.head.data.unwrap() == self.tail.data.unwrap() {
-            false
-        } else {
-            if self.size == self.capacity {
-                quick_sort(&mut self.blocks);
-                let read_index = self.head.data.unwrap();
-                // flush to memtable
-                for i in read_index..self.size {
-                    let block = self.blocks[i].unwrap();
-                    mt.add(&block.data, block.disabled);
-                }
-                return true;
-            } else {
-                // do not flush until capacity is reached
-                return false;
-            }
+        if self.size < self.capacity {
+            // do not flush until capacity is reached
+            return false;
+        }
+        self.drain(mt)
+    }
+
+    fn drain(&mut self, mt: &mut MemTable) -> bool {
+        if self.size == 0 || mt.size() + self.size > mt.capacity() {
+            return false;
+        }
+        // walk head -> tail so a later write to the same key lands after the earlier one
+        let mut index = self.head.data;
+        while let Some(i) = index {
+            let block = self.blocks[i].take().unwrap();
+            index = if Some(i) == self.tail.data { None } else { block.next };
+            mt.insert(block);
         }
+        self.bitmap.fill(0);
+        self.head = AlignedPosition { data: None, padding: PADDING };
+        self.tail = AlignedPosition { data: None, padding: PADDING };
+        self.size = 0;
+        true
     }
 }
 
 impl BlockRingBuffer {
     /// Internal method to add a new block to the ring buffer.
     /// tail block is updated to point to new block
-    fn _add(&mut self, phone_number: [u8; 10], new_block: Block) {
+    /// and the block is stamped with the next sequence number
+    fn _add(&mut self, mut new_block: Block) {
+        self.sequence += 1;
+        new_block.seq = self.sequence;
         if self.size == 0 {
             self.head = AlignedPosition { data: Some(0), padding: PADDING };
             self.tail = AlignedPosition { data: Some(0), padding: PADDING };
             self.blocks[0] = Some(new_block);
             self.size += 1;
             self.bitmap[0] |= 1;
-            self.cumulative_hash = sha_hash(&phone_number);
         } else {
-            // vectorized SIMD instruction to update cumulative hash
-            let new_cumulative_hash = u128::from_le_bytes(self.cumulative_hash)
-                ^ u128::from_le_bytes(sha_hash(&phone_number));
-            self.cumulative_hash = new_cumulative_hash.to_le_bytes();
             let tail_index = self.tail.data.unwrap();
             // update tail block to point to new block
-            let mut current_tail_block = self.blocks[tail_index].unwrap();
+            let mut current_tail_block = self.blocks[tail_index].take().unwrap();
             // for new block to be added, current_tail_block -> next
             // needs to be updated to point to new block
             // but it needs to be mod capacity to wrap around
@@ -123,7 +169,12 @@ impl BlockRingBuffer {
             if self.size < self.capacity {
                 self.size += 1;
             } else {
-                self.size = self.capacity
+                // oldest block got overwritten, head moves to the next oldest
+                self.size = self.capacity;
+                self.head = AlignedPosition {
+                    data: Some((new_tail_index + 1) % self.capacity),
+                    padding: PADDING,
+                };
             }
         }
     }
@@ -143,10 +194,42 @@ fn test_add_and_flush() {
     assert_eq!(ring_buffer.size, 2);
     assert_eq!(ring_buffer.tail.data.unwrap(), 1);
 
-    // Flush a block
-    assert!(ring_buffer.flush());
-    assert_eq!(ring_buffer.size, 2); // Size remains the same, only head moves
-    assert_eq!(ring_buffer.head.data.unwrap(), 1);
+    // Below capacity nothing is flushed
+    let mut mt = MemTable::new();
+    assert!(!ring_buffer.flush(&mut mt));
+    assert_eq!(ring_buffer.size, 2);
+
+    // Fill up, flush moves every block to the memtable and empties the buffer
+    for i in 2..100 {
+        ring_buffer.add([i as u8; 10]);
+    }
+    assert!(ring_buffer.flush(&mut mt));
+    assert_eq!(mt.size(), 100);
+    // sequence numbers follow insertion order into the memtable
+    assert_eq!(ring_buffer.sequence, 100);
+    assert_eq!(mt.search(sha_hash(&[99; 10]).into()).unwrap().seq, 100);
+    assert_eq!(ring_buffer.size, 0);
+    assert!(ring_buffer.head.data.is_none());
+    assert!(ring_buffer.blocks.iter().all(|b| b.is_none()));
+}
+
+#[test]
+fn test_drain_keeps_insertion_order_after_wrap() {
+    let mut ring_buffer = BlockRingBuffer::new();
+    let phone = [42; 10];
+    for i in 0..105 {
+        ring_buffer.add([i as u8; 10]);
+    }
+    ring_buffer.delete(phone);
+    // overwrites moved head past the oldest blocks
+    assert_eq!(ring_buffer.head.data.unwrap(), 6);
+
+    let mut mt = MemTable::new();
+    assert!(ring_buffer.drain(&mut mt));
+    // the tombstone was written last so it is the last entry for that key
+    let hashed = sha_hash(&phone);
+    let last = mt.blocks.iter().filter(|n| n.data == hashed).last().unwrap();
+    assert!(last.tombstone);
 }
 
 proptest! {
@@ -198,15 +281,23 @@ fn test_overflow() {
 fn test_cumulative_hash() {
     let mut ring_buffer = BlockRingBuffer::new();
     let phone1 = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
-    let phone2 = [11, 12, 13, 14, 15, 16, 17, 18, 19, 20];
-
-    ring_buffer.add(phone1);
-    let hash1 = ring_buffer.cumulative_hash;
 
-    ring_buffer.add(phone2);
-    let hash2 = ring_buffer.cumulative_hash;
+    // blocks are accumulated once, when a drain moves them into the memtable
+    for i in 0..250 {
+        ring_buffer.add([(i % 256) as u8; 10]);
+    }
+    let buffered: MultisetHash = ring_buffer.iter().cloned().collect();
+    let mut mt = MemTable::new();
+    assert!(mt.accumulator.is_empty());
+    assert!(ring_buffer.drain(&mut mt));
+    assert_eq!(mt.accumulator, buffered);
 
-    assert_ne!(hash1, hash2); // Hash should change after addition
+    // re-adding a number is a new write, it does not cancel the first one out
+    ring_buffer.add(phone1);
+    ring_buffer.add(phone1);
+    assert!(ring_buffer.drain(&mut mt));
+    assert_ne!(mt.accumulator, buffered);
+    assert!(!mt.accumulator.is_empty());
 }
 
 #[test]
@@ -0,0 +1,53 @@
+use std::sync::{Arc, Mutex, Weak};
+
+/// Point-in-time view of the engine: reads through it only see blocks with a sequence number
+/// at or below `seq`. While the handle is alive compaction keeps the versions it can see,
+/// dropping it releases them.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub seq: u64,
+    _pin: Arc<u64>,
+}
+
+/// Registry of the snapshots handed out by an engine.
+#[derive(Debug, Default)]
+pub struct Snapshots {
+    live: Mutex<Vec<Weak<u64>>>,
+}
+
+impl Snapshots {
+    pub fn acquire(&self, seq: u64) -> Snapshot {
+        let pin = Arc::new(seq);
+        self.live.lock().unwrap().push(Arc::downgrade(&pin));
+        Snapshot { seq, _pin: pin }
+    }
+
+    /// Sequence numbers of the snapshots still held, ascending, forgetting dropped ones.
+    pub fn live(&self) -> Vec<u64> {
+        let mut live = self.live.lock().unwrap();
+        live.retain(|pin| pin.strong_count() > 0);
+        let mut seqs: Vec<u64> = live.iter().filter_map(|pin| pin.upgrade()).map(|s| *s).collect();
+        seqs.sort_unstable();
+        seqs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dropped_snapshots_are_released() {
+        let snapshots = Snapshots::default();
+        let a = snapshots.acquire(7);
+        let b = snapshots.acquire(3);
+        let c = b.clone();
+        assert_eq!(snapshots.live(), vec![3, 7]);
+        drop(a);
+        drop(b);
+        // the clone still pins sequence 3
+        assert_eq!(snapshots.live(), vec![3]);
+        drop(c);
+        assert!(snapshots.live().is_empty());
+    }
+}
@@ -1,18 +1,33 @@
-use std::io::Error;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
 
+use serde::de::DeserializeOwned;
 use serde::Serialize;
+use twox_hash::XxHash32;
 
-use crate::core::skip_list::SkipList;
-use crate::storage::bloom_filter::BloomFilter;
-use crate::storage::data_block::DataBlock;
-use crate::storage::footer::Footer;
-use crate::storage::index_block::IndexBlock;
+use crate::core::accumulator::MultisetHash;
+use crate::core::block::Block;
+use crate::core::hasher::{Digest, MAX_DIGEST_SIZE};
+use crate::core::skip_list::{SkipList, SkipNode};
+use crate::storage::data_block::{DataBlock, DATA_BLOCK_SIZE};
+use crate::storage::footer::{BlockHandle, Footer};
+use crate::storage::index_block::{IndexBlock, IndexEntry};
+use crate::storage::membership_filter::{FilterKind, MembershipFilter, SegmentFilter};
 use crate::storage::meta_block::MetaBlock;
-use crate::sys::{get_page_size, mmap_opt};
+use crate::sys::mmap_opt;
 
-use super::bloom_filter::BloomFilterOps;
-use super::data_block;
-use super::mem_table::MemTable;
+/// "ONEC" - marks the footer of every segment file
+pub const SSTABLE_MAGIC: u32 = 0x4F4E_4543;
+/// bumped whenever the layout of the blocks or the footer changes, older files are rejected
+pub const SSTABLE_FORMAT_VERSION: u16 = 3;
+/// magic (4) + format version (2) + filter kind (1) + four block handles (8 + 8 + 4, each)
+/// + max_key and min_key (digest bytes + width, each) + checksum (4)
+pub const FOOTER_SIZE: usize = 11 + 4 * BLOCK_HANDLE_SIZE + 2 * (MAX_DIGEST_SIZE + 1);
+/// offset (8) + length (8) + checksum (4)
+pub const BLOCK_HANDLE_SIZE: usize = 20;
+pub const SEGMENT_PREFIX: &str = "sstable-";
+pub const SEGMENT_SUFFIX: &str = ".segment";
 
 ///
 /// SSTableSegment is a segment of SSTable file.
@@ -20,13 +35,13 @@ use super::mem_table::MemTable;
 ///
 /// +----------------------------------------------------+
 // |                   SSTableSegment                   |
+// +----------------------------------------------------+
+// |                     Data Blocks                    |
+// |  (Sorted key-value pairs, stored in sorted order)  |
 // +----------------------+----------------------------+
-// | Bloom Filter        | Index Block                  |
-// | (For fast lookup)   | (Key → Offset mapping)       |
+// | Filter              | Index Block                  |
+// | (For fast lookup)   | (Last key → block handle)    |
 // +----------------------+----------------------------+
-// |                     Data Block                     |
-// |  (Sorted key-value pairs, stored in sorted order)  |
-// +----------------------------------------------------+
 // |                  Metadata Block                    |
 // | (Compression, timestamps, merge info, etc.)        |
 // +----------------------------------------------------+
@@ -45,46 +60,480 @@ use super::mem_table::MemTable;
 // +-----------+-----------+--------------------+
 // ```
 // 	•	Keys are sorted lexicographically (e.g., "apple" < "banana" < "cherry").
-// 	•	The Index Block maps keys to Data Block offsets.
-// 	•	The Bloom Filter helps avoid unnecessary lookups.
+// 	•	The Index Block maps the last key of every Data Block to its handle.
+// 	•	The filter helps avoid unnecessary lookups, the footer records its kind.
+//
+// File format, version 3, all integers little endian:
+// ```ascii
+// +------------------------+--------------+-------------+------------+---------------------+
+// | data block 0 .. data n | filter block | index block | meta block | footer (FOOTER_SIZE) |
+// +------------------------+--------------+-------------+------------+---------------------+
+//
+// footer:
+// +-----------+-------------+-------------+---------------------------------------+
+// | magic u32 | version u16 | filter u8   | filter, index, data and meta handles  |
+// +-----------+-------------+-------------+---------------------------------------+
+// | max_key (len u8 + 32 bytes) | min_key (len u8 + 32 bytes) | checksum u32     |
+// +-----------------------------+-----------------------------+------------------+
+//
+// block handle:
+// +------------+------------+--------------+
+// | offset u64 | length u64 | xxHash32 u32 |
+// +------------+------------+--------------+
+// ```
+// Every block is bincode encoded and located only through its handle, which carries the
+// checksum of its bytes, so a flipped bit names the block it hit. The footer's data handle
+// spans all data blocks, the index holds a handle for each of them. The footer checksum covers
+// the footer itself with the checksum field zeroed. Readers reject any other magic or version.
 
+/// A whole segment decoded into memory, as a flush writes it and its accumulator is checked.
+/// The engine reads live segments through an `SSTableReader` instead.
 pub struct SSTableSegment {
-    pub bloom_filter: BloomFilter,
+    pub path: PathBuf,
+    pub filter: SegmentFilter,
     pub index_block: IndexBlock,
     pub data_block: DataBlock,
     pub meta_block: MetaBlock,
     pub footer: Footer,
 }
 
-trait SSTableSegmentOps {
-    fn create_segment(&mut self) -> std::result::Result<&mut SSTableSegment, Error>;
+pub trait SSTableSegmentOps: Sized {
+    /// Persists the sorted blocks of a full `SkipList` as a new immutable level 0 segment in `dir`,
+    /// with the default filter.
+    fn create(dir: &Path, skip_list: &SkipList) -> std::result::Result<Self, Error>;
+    /// Persists already sorted `entries`, used by compaction to write merged segments.
+    /// `created_at` is the time of the newest data in the segment, it orders segments on open.
+    /// The segment's keys are summarized by a filter of `filter` kind.
+    fn create_from_entries(
+        dir: &Path, entries: Vec<SkipNode>, level: u8, created_at: i64, filter: FilterKind,
+    ) -> std::result::Result<Self, Error>;
+    /// Reads back a segment written by `create`, validating its footer and decoding every
+    /// data block.
+    fn open(path: &Path) -> std::result::Result<Self, Error>;
+    /// Latest entry for `key`, the filter is consulted before the data block.
+    fn search(&self, key: Digest) -> Option<Block>;
+    /// Latest entry for `key` written at or before `seq`.
+    fn search_at(&self, key: Digest, seq: u64) -> Option<Block>;
 }
 
 impl SSTableSegmentOps for SSTableSegment {
-    fn create(&mut self, data_block: SkipList) -> std::result::Result<&mut SSTableSegment, Error> {
-        let segment_path = format!("sstable-{}.segment", chrono::Utc::now().timestamp_millis());
-        let mut mmap_buffer  = mmap_opt(&segment_path)?;
-        let offset = 0;
-        let page_size = get_page_size();
-        // fill 16 KB page with bloom filter, index block, meta block and footer
-        while offset < page_size {
-            // bloom filter
-            let bf = BloomFilter::new();
-            let blocks = data_block.blocks;
-            for i  in 0..blocks.len() {
-                let b = blocks[i];
-                if b.is_some() {
-                    let block = b.unwrap();
-                    bf.add(&block.data);
-                }
+    fn create(dir: &Path, skip_list: &SkipList) -> std::result::Result<SSTableSegment, Error> {
+        let entries = skip_list.iter().cloned().collect();
+        let now = chrono::Utc::now().timestamp_millis();
+        SSTableSegment::create_from_entries(dir, entries, 0, now, FilterKind::default())
+    }
+
+    fn create_from_entries(
+        dir: &Path, entries: Vec<SkipNode>, level: u8, created_at: i64, filter: FilterKind,
+    ) -> std::result::Result<SSTableSegment, Error> {
+        if entries.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "cannot create an empty segment"));
+        }
+        let keys: Vec<Digest> = entries.iter().map(|e| e.data).collect();
+        let filter = SegmentFilter::build(filter, &keys);
+        let mut accumulator = MultisetHash::new();
+        for entry in entries.iter() {
+            accumulator.insert(&Block::from(entry));
+        }
+        let min_key = entries[0].data;
+        let max_key = entries[entries.len() - 1].data;
+        let mut body = Vec::new();
+        let mut index_block = IndexBlock::new();
+        for (start, end) in data_block_ranges(&entries)? {
+            let page = DataBlock { entries: entries[start..end].to_vec() };
+            index_block.entries.push(IndexEntry {
+                last_key: entries[end - 1].data,
+                handle: append_block(&mut body, &encode(&page)?),
+                first_entry: start as u64,
+            });
+        }
+        let data_handle = BlockHandle {
+            offset: 0,
+            length: body.len() as u64,
+            checksum: XxHash32::oneshot(0, &body),
+        };
+        let meta_block = MetaBlock {
+            tombstone: entries.iter().any(|e| e.tombstone),
+            accumulator,
+            level,
+            created_at,
+            entry_count: entries.len() as u64,
+            max_seq: entries.iter().map(|e| e.seq).max().unwrap_or(0),
+        };
+        let data_block = DataBlock { entries };
+
+        let filter_handle = append_block(&mut body, &filter.encode()?);
+        let index_handle = append_block(&mut body, &encode(&index_block)?);
+        let meta_handle = append_block(&mut body, &encode(&meta_block)?);
+        let mut footer = Footer {
+            magic_number: SSTABLE_MAGIC,
+            format_version: SSTABLE_FORMAT_VERSION,
+            filter: filter.id(),
+            filter_handle,
+            index_handle,
+            data_handle,
+            meta_handle,
+            max_key,
+            min_key,
+            checksum: 0,
+        };
+        footer.checksum = footer_checksum(&footer)?;
+        let footer_bytes = encode(&footer)?;
+        debug_assert_eq!(footer_bytes.len(), FOOTER_SIZE);
+
+        let path = next_segment_path(dir);
+        let path_str = path.to_str().ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, format!("invalid segment path {:?}", path))
+        })?;
+        let mut mmap_buffer = mmap_opt(path_str, body.len() + footer_bytes.len())?;
+        mmap_buffer[..body.len()].copy_from_slice(&body);
+        mmap_buffer[body.len()..].copy_from_slice(&footer_bytes);
+        mmap_buffer.flush()?;
+
+        Ok(SSTableSegment {
+            path,
+            filter,
+            index_block,
+            data_block,
+            meta_block,
+            footer,
+        })
+    }
+
+    fn open(path: &Path) -> std::result::Result<SSTableSegment, Error> {
+        let bytes = fs::read(path)?;
+        let footer = read_footer(path, &bytes)?;
+        let body = &bytes[..bytes.len() - FOOTER_SIZE];
+        let index_block: IndexBlock =
+            decode(&mut read_block(path, body, &footer.index_handle, "index")?)?;
+        read_block(path, body, &footer.data_handle, "data")?;
+        let mut entries = Vec::new();
+        for index_entry in index_block.entries.iter() {
+            let page: DataBlock =
+                decode(&mut read_block(path, body, &index_entry.handle, "data")?)?;
+            if index_entry.first_entry != entries.len() as u64
+                || page.entries.last().map(|e| e.data) != Some(index_entry.last_key)
+            {
+                return Err(corrupted(path, "index does not match the data blocks"));
             }
-            let size = size_of_val(&bf);
-            mmap_buffer[offset..offset+size].copy_from_slice(&bf);
-            // index block
-            let ib = IndexBlock::new();
-            offset +=size;
+            entries.extend(page.entries);
         }
-        
-        Ok(self)
+        let segment = SSTableSegment {
+            path: path.to_path_buf(),
+            filter: SegmentFilter::decode(
+                footer.filter,
+                &mut read_block(path, body, &footer.filter_handle, "filter")?,
+            )?,
+            index_block,
+            data_block: DataBlock { entries },
+            meta_block: decode(&mut read_block(path, body, &footer.meta_handle, "meta")?)?,
+            footer,
+        };
+        if !segment.verify_accumulator() {
+            return Err(corrupted(path, "entries do not match the accumulator"));
+        }
+        Ok(segment)
+    }
+
+    fn search(&self, key: Digest) -> Option<Block> {
+        self.search_at(key, u64::MAX)
+    }
+
+    fn search_at(&self, key: Digest, seq: u64) -> Option<Block> {
+        if key < self.footer.min_key || key > self.footer.max_key {
+            return None;
+        }
+        if !self.filter.may_contain(&key) {
+            return None;
+        }
+        let block = self.index_block.find(&key)?;
+        let range = self.index_block.entry_range(block, self.data_block.entries.len());
+        let entries = &self.data_block.entries[range];
+        // versions of a key are stored oldest first
+        let start = entries.partition_point(|e| e.data < key);
+        let end = entries.partition_point(|e| e.data <= key);
+        entries[start..end].iter().rev().find(|e| e.seq <= seq).map(Block::from)
+    }
+}
+
+impl SSTableSegment {
+    /// Recomputes the multiset hash of the entries and compares it with the persisted one.
+    pub fn verify_accumulator(&self) -> bool {
+        let entries = self.data_block.entries.iter().map(Block::from);
+        entries.collect::<MultisetHash>() == self.meta_block.accumulator
+    }
+}
+
+/// Segments are named `sstable-<millis>.segment`, bumping the millis on collision
+/// so two flushes within the same millisecond never overwrite each other.
+fn next_segment_path(dir: &Path) -> PathBuf {
+    let mut millis = chrono::Utc::now().timestamp_millis();
+    loop {
+        let path = dir.join(format!("{}{}{}", SEGMENT_PREFIX, millis, SEGMENT_SUFFIX));
+        if !path.exists() {
+            return path;
+        }
+        millis += 1;
+    }
+}
+
+/// Footer at the end of the segment file `bytes`, once its magic, checksum and version check out.
+pub(crate) fn read_footer(path: &Path, bytes: &[u8]) -> std::result::Result<Footer, Error> {
+    if bytes.len() < FOOTER_SIZE {
+        return Err(corrupted(path, "file is shorter than the footer"));
+    }
+    let footer: Footer = decode(&mut &bytes[bytes.len() - FOOTER_SIZE..])?;
+    if footer.magic_number != SSTABLE_MAGIC {
+        return Err(corrupted(path, "bad magic number"));
+    }
+    if footer.checksum != footer_checksum(&footer)? {
+        return Err(corrupted(path, "footer checksum mismatch"));
+    }
+    if footer.format_version != SSTABLE_FORMAT_VERSION {
+        return Err(corrupted(
+            path,
+            &format!("unsupported format version {}", footer.format_version),
+        ));
+    }
+    Ok(footer)
+}
+
+/// Splits sorted `entries` into data blocks of about `DATA_BLOCK_SIZE` encoded bytes.
+/// A block only ends where the key changes, so every version of a key lands in one block.
+fn data_block_ranges(entries: &[SkipNode]) -> std::result::Result<Vec<(usize, usize)>, Error> {
+    let mut ranges = Vec::new();
+    let (mut start, mut size) = (0, 0);
+    for (i, entry) in entries.iter().enumerate() {
+        if size >= DATA_BLOCK_SIZE && entry.data != entries[i - 1].data {
+            ranges.push((start, i));
+            (start, size) = (i, 0);
+        }
+        size += bincode::serialized_size(entry)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))? as usize;
+    }
+    ranges.push((start, entries.len()));
+    Ok(ranges)
+}
+
+/// Appends `bytes` as the next block of `body` and returns its handle.
+fn append_block(body: &mut Vec<u8>, bytes: &[u8]) -> BlockHandle {
+    let handle = BlockHandle {
+        offset: body.len() as u64,
+        length: bytes.len() as u64,
+        checksum: XxHash32::oneshot(0, bytes),
+    };
+    body.extend_from_slice(bytes);
+    handle
+}
+
+/// Bytes of the block behind `handle`, once they are in bounds and match its checksum.
+pub(crate) fn read_block<'a>(
+    path: &Path, body: &'a [u8], handle: &BlockHandle, name: &str,
+) -> std::result::Result<&'a [u8], Error> {
+    let range = handle.range();
+    if range.end > body.len() as u64 {
+        return Err(corrupted(path, &format!("{} block lies past the end of the file", name)));
+    }
+    let bytes = &body[range.start as usize..range.end as usize];
+    if XxHash32::oneshot(0, bytes) != handle.checksum {
+        return Err(corrupted(path, &format!("{} block checksum mismatch", name)));
+    }
+    Ok(bytes)
+}
+
+fn footer_checksum(footer: &Footer) -> std::result::Result<u32, Error> {
+    let zeroed = Footer { checksum: 0, ..footer.clone() };
+    Ok(XxHash32::oneshot(0, &encode(&zeroed)?))
+}
+
+fn encode<T: Serialize>(value: &T) -> std::result::Result<Vec<u8>, Error> {
+    bincode::serialize(value).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+pub(crate) fn decode<T: DeserializeOwned>(reader: &mut &[u8]) -> std::result::Result<T, Error> {
+    bincode::deserialize_from(reader).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+pub(crate) fn corrupted(path: &Path, reason: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("corrupted segment {:?}: {}", path, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::skip_list::SkipListOps;
+    use crate::storage::test_dir;
+
+    #[test]
+    fn test_create_and_open_segment() {
+        let dir = test_dir("segment-roundtrip");
+        let mut skip_list = SkipList::init();
+        for i in (0..50u8).rev() {
+            assert!(skip_list.add([i; 16].into(), i as u64, i % 10 == 0));
+        }
+        let segment = SSTableSegment::create(&dir, &skip_list).unwrap();
+        assert_eq!(segment.footer.min_key, [0; 16]);
+        assert_eq!(segment.footer.max_key, [49; 16]);
+        assert!(segment.meta_block.tombstone);
+
+        let reopened = SSTableSegment::open(&segment.path).unwrap();
+        let keys: Vec<_> = reopened.data_block.entries.iter().map(|e| e.data).collect();
+        let expected: Vec<_> = (0..50u8).map(|i| [i; 16]).collect();
+        assert_eq!(keys, expected);
+        assert!(reopened.data_block.entries[10].tombstone);
+        assert!(!reopened.search([3; 16].into()).unwrap().disabled);
+        assert!(reopened.search([20; 16].into()).unwrap().disabled);
+        assert!(reopened.search([50; 16].into()).is_none());
+        assert_eq!(reopened.filter, segment.filter);
+        assert_eq!(reopened.footer, segment.footer);
+
+        // blocks are laid out back to back in front of the footer
+        let footer = &reopened.footer;
+        assert_eq!(footer.format_version, SSTABLE_FORMAT_VERSION);
+        assert_eq!(footer.data_handle.offset, 0);
+        assert_eq!(footer.filter_handle.offset, footer.data_handle.range().end);
+        assert_eq!(footer.index_handle.offset, footer.filter_handle.range().end);
+        assert_eq!(footer.meta_handle.offset, footer.index_handle.range().end);
+        assert_eq!(reopened.index_block, segment.index_block);
+        let file_len = fs::metadata(&segment.path).unwrap().len();
+        assert_eq!(footer.meta_handle.range().end, file_len - FOOTER_SIZE as u64);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_large_segment_is_split_into_indexed_blocks() {
+        let dir = test_dir("segment-index");
+        let mut entries = Vec::new();
+        for i in 0..3000u32 {
+            let mut key = [0u8; 16];
+            key[..4].copy_from_slice(&i.to_be_bytes());
+            // every tenth key has three versions
+            for seq in 0..if i % 10 == 0 { 3 } else { 1 } {
+                entries.push(SkipNode {
+                    tombstone: false,
+                    data: key.into(),
+                    seq: seq * 10_000 + i as u64,
+                    payload: None,
+                });
+            }
+        }
+        let segment =
+            SSTableSegment::create_from_entries(&dir, entries, 1, 0, FilterKind::default())
+                .unwrap();
+        let reopened = SSTableSegment::open(&segment.path).unwrap();
+        let index = &reopened.index_block;
+        assert!(index.entries.len() > 10);
+        assert_eq!(index.entries, segment.index_block.entries);
+        let total = reopened.data_block.entries.len();
+        assert_eq!(total, 3600);
+        for (block, entry) in index.entries.iter().enumerate() {
+            let range = index.entry_range(block, total);
+            // about a page each, and a key never continues in the next block
+            assert!(entry.handle.length as usize <= DATA_BLOCK_SIZE + 200);
+            assert_eq!(reopened.data_block.entries[range.end - 1].data, entry.last_key);
+            if range.end < total {
+                assert_ne!(reopened.data_block.entries[range.end].data, entry.last_key);
+            }
+        }
+        for i in (0..3000u32).step_by(7) {
+            let mut key = [0u8; 16];
+            key[..4].copy_from_slice(&i.to_be_bytes());
+            let latest = if i % 10 == 0 { 20_000 } else { 0 } + i as u64;
+            assert_eq!(reopened.search(key.into()).unwrap().seq, latest);
+            assert_eq!(reopened.search_at(key.into(), i as u64).unwrap().seq, i as u64);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_footer_records_filter_kind() {
+        let dir = test_dir("segment-filter-kind");
+        let entries: Vec<SkipNode> = (0..100u8)
+            .map(|i| SkipNode {
+                tombstone: false,
+                data: [i; 16].into(),
+                seq: i as u64,
+                payload: None,
+            })
+            .collect();
+        for kind in
+            [FilterKind::Xor, FilterKind::Cuckoo, FilterKind::BlockedBloom { bits_per_key: 12 }]
+        {
+            let segment =
+                SSTableSegment::create_from_entries(&dir, entries.clone(), 1, 0, kind).unwrap();
+            assert_eq!(segment.footer.filter, kind.id());
+            let reopened = SSTableSegment::open(&segment.path).unwrap();
+            assert_eq!(reopened.filter, segment.filter);
+            assert!((0..100u8).all(|i| reopened.search([i; 16].into()).is_some()));
+            assert!(reopened.search([200; 16].into()).is_none());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_rejects_corrupted_segment() {
+        let dir = test_dir("segment-corrupt");
+        let mut skip_list = SkipList::init();
+        skip_list.add([7; 16].into(), 0, false);
+        let segment = SSTableSegment::create(&dir, &skip_list).unwrap();
+
+        let footer = &segment.footer;
+        let original = fs::read(&segment.path).unwrap();
+        let open_flipped = |at: usize| {
+            let mut bytes = original.clone();
+            bytes[at] ^= 0xFF;
+            fs::write(&segment.path, &bytes).unwrap();
+            let err = SSTableSegment::open(&segment.path).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            err.to_string()
+        };
+        // a flipped byte in any block is pinned on that block
+        for (handle, name) in [
+            (footer.filter_handle, "filter"),
+            (footer.index_handle, "index"),
+            (footer.data_handle, "data"),
+            (footer.meta_handle, "meta"),
+        ] {
+            let middle = (handle.offset + handle.length / 2) as usize;
+            assert!(open_flipped(middle).contains(&format!("{} block checksum", name)));
+        }
+        let footer_start = original.len() - FOOTER_SIZE;
+        assert!(open_flipped(footer_start).contains("bad magic number"));
+        // a handle pointing elsewhere is caught before any block is read
+        assert!(open_flipped(footer_start + 7).contains("footer checksum"));
+
+        fs::write(&segment.path, &original[1..]).unwrap();
+        assert!(SSTableSegment::open(&segment.path).is_err());
+        fs::write(&segment.path, &original[..FOOTER_SIZE - 1]).unwrap();
+        assert!(SSTableSegment::open(&segment.path).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_rejects_unknown_format_version() {
+        let dir = test_dir("segment-version");
+        let mut skip_list = SkipList::init();
+        skip_list.add([7; 16].into(), 0, false);
+        let segment = SSTableSegment::create(&dir, &skip_list).unwrap();
+
+        let mut footer = segment.footer.clone();
+        footer.format_version = SSTABLE_FORMAT_VERSION + 1;
+        footer.checksum = footer_checksum(&footer).unwrap();
+        let mut bytes = fs::read(&segment.path).unwrap();
+        let footer_start = bytes.len() - FOOTER_SIZE;
+        bytes[footer_start..].copy_from_slice(&encode(&footer).unwrap());
+        fs::write(&segment.path, &bytes).unwrap();
+        let err = SSTableSegment::open(&segment.path).err().unwrap();
+        let expected = format!("unsupported format version {}", SSTABLE_FORMAT_VERSION + 1);
+        assert!(err.to_string().contains(&expected));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_create_rejects_empty_skip_list() {
+        let dir = test_dir("segment-empty");
+        let skip_list = SkipList::init();
+        assert!(SSTableSegment::create(&dir, &skip_list).is_err());
+        fs::remove_dir_all(dir).unwrap();
     }
 }
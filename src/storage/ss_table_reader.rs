@@ -0,0 +1,359 @@
+use std::cmp::Ordering;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+
+use memmap2::Mmap;
+
+use crate::core::block::Block;
+use crate::core::hasher::{Digest, MAX_DIGEST_SIZE};
+use crate::core::skip_list::SkipNode;
+use crate::storage::footer::Footer;
+use crate::storage::index_block::IndexBlock;
+use crate::storage::membership_filter::{MembershipFilter, SegmentFilter};
+use crate::storage::meta_block::MetaBlock;
+use crate::storage::ss_table::{corrupted, decode, read_block, read_footer, FOOTER_SIZE};
+use crate::sys::{advise, mmap_read, AccessPattern};
+
+/// encoded bytes of an entry in front of its payload:
+/// tombstone (1) + digest bytes and width (MAX_DIGEST_SIZE + 1) + seq (8)
+const ENTRY_HEADER_SIZE: usize = MAX_DIGEST_SIZE + 10;
+
+/// Read only view of a segment file mapped whole into memory.
+/// Opening decodes only the footer, filter, index and meta blocks, data blocks stay in the
+/// mapping and are read in place when a lookup or scan reaches them, so a point lookup
+/// touches the one page of its data block. Keys are borrowed straight from the mapping.
+/// Opened for point lookups, call `advise` with `AccessPattern::Sequential` before a scan.
+pub struct SSTableReader {
+    pub path: PathBuf,
+    pub footer: Footer,
+    pub filter: SegmentFilter,
+    pub index_block: IndexBlock,
+    pub meta_block: MetaBlock,
+    mmap: Mmap,
+    /// whether the mapping was last advised sequential, repeating an advice skips the syscall
+    sequential: AtomicBool,
+}
+
+/// One entry of a data block, viewed in place.
+#[derive(Debug, Clone, Copy)]
+pub struct EntryRef<'a> {
+    /// digest of the key, a slice rather than `[u8; 16]` since digests are as wide as the
+    /// hasher made them, up to `MAX_DIGEST_SIZE`
+    pub key: &'a [u8],
+    pub tombstone: bool,
+    pub seq: u64,
+    /// the whole encoded entry, payload included
+    bytes: &'a [u8],
+}
+
+/// Entries of one data block in key order, versions of a key oldest first.
+#[derive(Clone)]
+pub struct BlockEntries<'a> {
+    rest: &'a [u8],
+    remaining: usize,
+}
+
+pub trait SSTableReaderOps: Sized {
+    /// Maps the segment at `path` and validates its footer and the blocks it decodes.
+    fn open(path: &Path) -> std::result::Result<Self, Error>;
+    /// Tells the kernel how the data blocks are about to be read, unless it was told already.
+    fn advise(&self, pattern: AccessPattern) -> std::result::Result<(), Error>;
+    /// Pattern the mapping was last advised with.
+    fn access_pattern(&self) -> AccessPattern;
+    fn block_count(&self) -> usize;
+    /// Entries of data block `block`, once its checksum matches.
+    /// Fails with `InvalidInput` when `block` is not below `block_count`.
+    fn block(&self, block: usize) -> std::result::Result<BlockEntries<'_>, Error>;
+    /// Every entry in key order, decoded from all data blocks, for readers of the whole segment
+    /// such as compaction.
+    fn entries(&self) -> std::result::Result<Vec<SkipNode>, Error>;
+    /// Latest entry for `key`, reading at most one data block.
+    fn search(&self, key: Digest) -> std::result::Result<Option<Block>, Error>;
+    /// Latest entry for `key` written at or before `seq`.
+    fn search_at(&self, key: Digest, seq: u64) -> std::result::Result<Option<Block>, Error>;
+}
+
+impl SSTableReaderOps for SSTableReader {
+    fn open(path: &Path) -> std::result::Result<SSTableReader, Error> {
+        // an empty file cannot be mapped
+        if (fs::metadata(path)?.len() as usize) < FOOTER_SIZE {
+            return Err(corrupted(path, "file is shorter than the footer"));
+        }
+        let mmap = mmap_read(path)?;
+        advise(&mmap, AccessPattern::Random)?;
+        let footer = read_footer(path, &mmap)?;
+        let body = &mmap[..mmap.len() - FOOTER_SIZE];
+        let index_block: IndexBlock =
+            decode(&mut read_block(path, body, &footer.index_handle, "index")?)?;
+        let data_end = footer.data_handle.range().end;
+        if index_block.entries.iter().any(|e| e.handle.range().end > data_end) {
+            return Err(corrupted(path, "index points past the data blocks"));
+        }
+        Ok(SSTableReader {
+            path: path.to_path_buf(),
+            filter: SegmentFilter::decode(
+                footer.filter,
+                &mut read_block(path, body, &footer.filter_handle, "filter")?,
+            )?,
+            index_block,
+            meta_block: decode(&mut read_block(path, body, &footer.meta_handle, "meta")?)?,
+            footer,
+            mmap,
+            sequential: AtomicBool::new(false),
+        })
+    }
+
+    fn advise(&self, pattern: AccessPattern) -> std::result::Result<(), Error> {
+        if self.access_pattern() == pattern {
+            return Ok(());
+        }
+        advise(&self.mmap, pattern)?;
+        self.sequential.store(pattern == AccessPattern::Sequential, AtomicOrdering::Relaxed);
+        Ok(())
+    }
+
+    fn access_pattern(&self) -> AccessPattern {
+        if self.sequential.load(AtomicOrdering::Relaxed) {
+            AccessPattern::Sequential
+        } else {
+            AccessPattern::Random
+        }
+    }
+
+    fn block_count(&self) -> usize {
+        self.index_block.entries.len()
+    }
+
+    fn block(&self, block: usize) -> std::result::Result<BlockEntries<'_>, Error> {
+        let Some(entry) = self.index_block.entries.get(block) else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{:?} has no data block {}", self.path, block),
+            ));
+        };
+        let handle = &entry.handle;
+        let bytes = read_block(&self.path, &self.mmap, handle, "data")?;
+        // a data block is a `DataBlock`: entry count (8), then the entries back to back
+        let count = bytes.get(..8).map(|c| u64::from_le_bytes(c.try_into().unwrap()));
+        let entries = BlockEntries {
+            rest: bytes.get(8..).unwrap_or_default(),
+            remaining: count.unwrap_or(u64::MAX) as usize,
+        };
+        // checked once up front, so iterating can not run into a malformed entry
+        let mut walk = entries.clone();
+        while walk.remaining > 0 {
+            let entry = EntryRef::parse(walk.rest)
+                .ok_or_else(|| corrupted(&self.path, "malformed data block"))?;
+            walk.rest = &walk.rest[entry.bytes.len()..];
+            walk.remaining -= 1;
+        }
+        if !walk.rest.is_empty() {
+            return Err(corrupted(&self.path, "malformed data block"));
+        }
+        Ok(entries)
+    }
+
+    fn entries(&self) -> std::result::Result<Vec<SkipNode>, Error> {
+        let mut entries = Vec::new();
+        for block in 0..self.block_count() {
+            for entry in self.block(block)? {
+                entries.push(entry.to_node()?);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn search(&self, key: Digest) -> std::result::Result<Option<Block>, Error> {
+        self.search_at(key, u64::MAX)
+    }
+
+    fn search_at(&self, key: Digest, seq: u64) -> std::result::Result<Option<Block>, Error> {
+        if key < self.footer.min_key || key > self.footer.max_key {
+            return Ok(None);
+        }
+        if !self.filter.may_contain(&key) {
+            return Ok(None);
+        }
+        let Some(block) = self.index_block.find(&key) else {
+            return Ok(None);
+        };
+        let mut found = None;
+        for entry in self.block(block)? {
+            match entry.key.cmp(key.as_bytes()) {
+                Ordering::Less => continue,
+                Ordering::Greater => break,
+                // versions are oldest first, the last one visible at `seq` wins
+                Ordering::Equal if entry.seq <= seq => found = Some(entry),
+                Ordering::Equal => {},
+            }
+        }
+        found.map(|entry| entry.to_block()).transpose()
+    }
+}
+
+impl<'a> EntryRef<'a> {
+    /// Views the bincode encoded `SkipNode` at the front of `bytes`, None when it is cut short.
+    fn parse(bytes: &'a [u8]) -> Option<EntryRef<'a>> {
+        let header = bytes.get(..ENTRY_HEADER_SIZE)?;
+        let width = header[1 + MAX_DIGEST_SIZE] as usize;
+        // anything bincode would not decode, so a parsed entry always decodes
+        if header[0] > 1 || width > MAX_DIGEST_SIZE {
+            return None;
+        }
+        let seq = u64::from_le_bytes(header[ENTRY_HEADER_SIZE - 8..].try_into().ok()?);
+        // payload: None tag, or Some tag followed by the length prefixed key and value
+        let mut end = ENTRY_HEADER_SIZE + 1;
+        match bytes.get(ENTRY_HEADER_SIZE)? {
+            0 => {},
+            1 => {
+                for _ in 0..2 {
+                    let len = u64::from_le_bytes(bytes.get(end..end + 8)?.try_into().ok()?);
+                    end = end.checked_add(8)?.checked_add(usize::try_from(len).ok()?)?;
+                }
+            },
+            _ => return None,
+        }
+        Some(EntryRef {
+            key: &header[1..1 + width],
+            tombstone: header[0] != 0,
+            seq,
+            bytes: bytes.get(..end)?,
+        })
+    }
+
+    /// The key as a 16 byte digest, None when the hasher made it another width.
+    pub fn key_array(&self) -> Option<&'a [u8; 16]> {
+        self.key.try_into().ok()
+    }
+
+    /// Decodes the whole entry, payload included.
+    pub fn to_node(&self) -> std::result::Result<SkipNode, Error> {
+        decode(&mut &self.bytes[..])
+    }
+
+    pub fn to_block(&self) -> std::result::Result<Block, Error> {
+        self.to_node().map(|node| Block::from(&node))
+    }
+}
+
+impl<'a> Iterator for BlockEntries<'a> {
+    type Item = EntryRef<'a>;
+
+    fn next(&mut self) -> Option<EntryRef<'a>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let entry = EntryRef::parse(self.rest)?;
+        self.rest = &self.rest[entry.bytes.len()..];
+        self.remaining -= 1;
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::block::Payload;
+    use crate::storage::membership_filter::FilterKind;
+    use crate::storage::ss_table::{SSTableSegment, SSTableSegmentOps};
+    use crate::storage::test_dir;
+
+    fn key(i: u32) -> [u8; 16] {
+        let mut key = [0u8; 16];
+        key[..4].copy_from_slice(&i.to_be_bytes());
+        key
+    }
+
+    fn segment(dir: &Path) -> SSTableSegment {
+        let entries = (0..2000u32)
+            .map(|i| SkipNode {
+                tombstone: i % 9 == 0,
+                data: key(i).into(),
+                seq: i as u64,
+                // payloads of varying length between fixed size headers
+                payload: (i % 3 == 0).then(|| {
+                    Box::new(Payload {
+                        key: vec![1; i as usize % 7],
+                        value: vec![2; i as usize % 50],
+                    })
+                }),
+            })
+            .collect();
+        SSTableSegment::create_from_entries(dir, entries, 0, 0, FilterKind::default()).unwrap()
+    }
+
+    #[test]
+    fn test_scan_borrows_keys_from_the_mapping() {
+        let dir = test_dir("reader-scan");
+        let segment = segment(&dir);
+        let reader = SSTableReader::open(&segment.path).unwrap();
+        assert_eq!(reader.footer, segment.footer);
+        assert!(reader.block_count() > 1);
+
+        reader.advise(AccessPattern::Sequential).unwrap();
+        let range = reader.mmap.as_ptr_range();
+        let mut scanned = Vec::new();
+        for block in 0..reader.block_count() {
+            for entry in reader.block(block).unwrap() {
+                assert!(range.contains(&entry.key.as_ptr()));
+                assert_eq!(entry.key_array().map(|k| &k[..]), Some(entry.key));
+                scanned.push((entry.key, entry.tombstone, entry.seq, entry.to_node().unwrap()));
+            }
+        }
+        assert_eq!(scanned.len(), segment.data_block.entries.len());
+        let wide = SkipNode {
+            tombstone: false,
+            data: [7; 32].into(),
+            seq: 1,
+            payload: None,
+        };
+        let wide = bincode::serialize(&wide).unwrap();
+        assert!(EntryRef::parse(&wide).unwrap().key_array().is_none());
+        let past_the_end = reader.block(reader.block_count()).err().unwrap();
+        assert_eq!(past_the_end.kind(), ErrorKind::InvalidInput);
+        for ((key, tombstone, seq, node), expected) in
+            scanned.iter().zip(&segment.data_block.entries)
+        {
+            assert_eq!(*key, expected.data.as_bytes());
+            assert_eq!((*tombstone, *seq), (expected.tombstone, expected.seq));
+            assert_eq!(node.payload, expected.payload);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_point_lookups_match_the_segment() {
+        let dir = test_dir("reader-search");
+        let segment = segment(&dir);
+        let reader = SSTableReader::open(&segment.path).unwrap();
+        for i in (0..2000).step_by(13) {
+            let found = reader.search(key(i).into()).unwrap().unwrap();
+            let expected = segment.search(key(i).into()).unwrap();
+            assert_eq!((found.seq, found.disabled), (expected.seq, expected.disabled));
+            assert_eq!(found.payload, expected.payload);
+            if i > 0 {
+                assert!(reader.search_at(key(i).into(), i as u64 - 1).unwrap().is_none());
+            }
+        }
+        assert!(reader.search(key(5000).into()).unwrap().is_none());
+
+        // a damaged data block fails the lookups that reach it, the rest keep working
+        let handle = reader.index_block.entries[0].handle;
+        let mut bytes = fs::read(&segment.path).unwrap();
+        bytes[handle.offset as usize + 20] ^= 0xFF;
+        fs::write(&segment.path, &bytes).unwrap();
+        let reader = SSTableReader::open(&segment.path).unwrap();
+        assert!(reader.search(key(0).into()).is_err());
+        assert!(reader.search(key(1999).into()).unwrap().is_some());
+
+        let footer_start = bytes.len() - FOOTER_SIZE;
+        bytes[footer_start + 8] ^= 0xFF;
+        fs::write(&segment.path, &bytes).unwrap();
+        assert!(SSTableReader::open(&segment.path).is_err());
+        fs::write(&segment.path, b"").unwrap();
+        assert!(SSTableReader::open(&segment.path).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use twox_hash::XxHash32;
+
+use crate::core::block::Block;
+
+pub const WAL_FILE: &str = "wal.log";
+/// length (4) + checksum (4)
+const RECORD_HEADER_SIZE: usize = 8;
+
+/// Append-only log of every `Block` written to the ring buffer.
+/// Each record is laid out as:
+/// +------------+--------------+---------------------+
+/// | len: u32   | xxh32: u32   | bincode(Block)      |
+/// +------------+--------------+---------------------+
+/// Records are appended before the ring buffer is mutated so a crash loses nothing that was
+/// acknowledged. Once the memtable reaches an `SSTableSegment` the log is rewritten to hold only
+/// the blocks that are still buffered in memory.
+pub struct WriteAheadLog {
+    pub path: PathBuf,
+    file: File,
+}
+
+pub trait WriteAheadLogOps: Sized {
+    /// Opens or creates `wal.log` in `dir`.
+    fn open(dir: &Path) -> std::result::Result<Self, Error>;
+    /// Appends one record, it reaches the OS before returning and survives a process crash.
+    fn append(&mut self, block: &Block) -> std::result::Result<(), Error>;
+    /// Forces appended records to disk.
+    fn sync(&mut self) -> std::result::Result<(), Error>;
+    /// Reads every intact record in write order. A torn or corrupted tail left by a crash
+    /// is cut off so later appends are not hidden behind it.
+    fn replay(&mut self) -> std::result::Result<Vec<Block>, Error>;
+    /// Atomically replaces the log with `blocks`, an empty iterator truncates it.
+    fn rewrite<'a>(
+        &mut self, blocks: impl Iterator<Item = &'a Block>,
+    ) -> std::result::Result<(), Error>;
+}
+
+impl WriteAheadLogOps for WriteAheadLog {
+    fn open(dir: &Path) -> std::result::Result<WriteAheadLog, Error> {
+        let path = dir.join(WAL_FILE);
+        let file = open_append(&path)?;
+        Ok(WriteAheadLog { path, file })
+    }
+
+    fn append(&mut self, block: &Block) -> std::result::Result<(), Error> {
+        self.file.write_all(&encode_record(block)?)
+    }
+
+    fn sync(&mut self) -> std::result::Result<(), Error> {
+        self.file.sync_data()
+    }
+
+    fn replay(&mut self) -> std::result::Result<Vec<Block>, Error> {
+        let bytes = fs::read(&self.path)?;
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        while let Some((block, len)) = decode_record(&bytes[offset..]) {
+            blocks.push(block);
+            offset += len;
+        }
+        if offset < bytes.len() {
+            self.file.set_len(offset as u64)?;
+            self.file.sync_data()?;
+        }
+        Ok(blocks)
+    }
+
+    fn rewrite<'a>(
+        &mut self, blocks: impl Iterator<Item = &'a Block>,
+    ) -> std::result::Result<(), Error> {
+        let mut bytes = Vec::new();
+        for block in blocks {
+            bytes.extend(encode_record(block)?);
+        }
+        replace_file(&self.path, &bytes)?;
+        self.file = open_append(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Writes `bytes` next to `path` and renames it over, readers see either the old or new file.
+pub(crate) fn replace_file(path: &Path, bytes: &[u8]) -> std::result::Result<(), Error> {
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(bytes)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    if let Some(dir) = path.parent() {
+        // persist the rename itself
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn open_append(path: &Path) -> std::result::Result<File, Error> {
+    OpenOptions::new().read(true).append(true).create(true).open(path)
+}
+
+/// Frames `value` as `len | xxh32 | bincode(value)`, shared with the manifest log.
+pub(crate) fn encode_record<T: Serialize>(value: &T) -> std::result::Result<Vec<u8>, Error> {
+    let payload = bincode::serialize(value).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&XxHash32::oneshot(0, &payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+/// Decodes the record at the start of `bytes`, returning it with its encoded length.
+/// None when the record is incomplete or fails its checksum.
+pub(crate) fn decode_record<T: DeserializeOwned>(bytes: &[u8]) -> Option<(T, usize)> {
+    if bytes.len() < RECORD_HEADER_SIZE {
+        return None;
+    }
+    let len = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    let payload = bytes.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len)?;
+    if XxHash32::oneshot(0, payload) != checksum {
+        return None;
+    }
+    let value = bincode::deserialize(payload).ok()?;
+    Some((value, RECORD_HEADER_SIZE + len))
+}
+
+/// True when `bytes` start with a record cut short, as left by a crash during its append,
+/// rather than a whole record that fails its checksum.
+pub(crate) fn is_incomplete_record(bytes: &[u8]) -> bool {
+    if bytes.len() < RECORD_HEADER_SIZE {
+        return true;
+    }
+    let len = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+    bytes.len() < RECORD_HEADER_SIZE + len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_dir;
+
+    #[test]
+    fn test_append_and_replay() {
+        let dir = test_dir("wal-replay");
+        let mut wal = WriteAheadLog::open(&dir).unwrap();
+        for i in 0..10u8 {
+            wal.append(&Block::new([i; 10], i % 3 == 0)).unwrap();
+        }
+        wal.sync().unwrap();
+
+        let mut reopened = WriteAheadLog::open(&dir).unwrap();
+        let blocks = reopened.replay().unwrap();
+        assert_eq!(blocks.len(), 10);
+        for (i, block) in blocks.iter().enumerate() {
+            assert_eq!(block.data, Block::new([i as u8; 10], false).data);
+            assert_eq!(block.disabled, i % 3 == 0);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_cuts_torn_tail() {
+        let dir = test_dir("wal-torn");
+        let mut wal = WriteAheadLog::open(&dir).unwrap();
+        wal.append(&Block::new([1; 10], false)).unwrap();
+        wal.append(&Block::new([2; 10], false)).unwrap();
+        // simulate a crash half way through the second record
+        let len = fs::metadata(&wal.path).unwrap().len();
+        wal.file.set_len(len - 5).unwrap();
+
+        let mut reopened = WriteAheadLog::open(&dir).unwrap();
+        assert_eq!(reopened.replay().unwrap().len(), 1);
+        // new records are readable after the cut
+        reopened.append(&Block::new([3; 10], false)).unwrap();
+        assert_eq!(reopened.replay().unwrap().len(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_stops_at_corrupted_record() {
+        let dir = test_dir("wal-corrupt");
+        let mut wal = WriteAheadLog::open(&dir).unwrap();
+        wal.append(&Block::new([1; 10], false)).unwrap();
+        wal.append(&Block::new([2; 10], false)).unwrap();
+        let mut bytes = fs::read(&wal.path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        fs::write(&wal.path, &bytes).unwrap();
+
+        assert_eq!(wal.replay().unwrap().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite_replaces_log() {
+        let dir = test_dir("wal-rewrite");
+        let mut wal = WriteAheadLog::open(&dir).unwrap();
+        for i in 0..5u8 {
+            wal.append(&Block::new([i; 10], false)).unwrap();
+        }
+        let kept = [Block::new([9; 10], true)];
+        wal.rewrite(kept.iter()).unwrap();
+        wal.append(&Block::new([10; 10], false)).unwrap();
+        let blocks = wal.replay().unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks[0].disabled);
+
+        wal.rewrite(std::iter::empty()).unwrap();
+        assert_eq!(fs::metadata(&wal.path).unwrap().len(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+use serde::{Deserialize, Serialize};
+use twox_hash::XxHash64;
+
+use crate::core::hasher::Digest;
+use crate::storage::bloom_filter::BLOOM_SEED;
+
+/// slots per key, the 1.23 of the xor filter paper leaves peeling room for three hashes
+const LOAD_FACTOR: f64 = 1.23;
+/// extra slots so small key sets still peel
+const EXTRA_SLOTS: usize = 32;
+
+/// Xor filter with 8 bit fingerprints: a key is present when the fingerprints in its three
+/// slots, one in each third of the table, xor to its own fingerprint.
+/// About 9.8 bits per key for 0.4% false positives, cheaper than a bloom filter at the same
+/// rate, but it is built once from the full key set and takes no keys afterwards,
+/// which is all an immutable segment needs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct XorFilter {
+    pub fingerprints: Vec<u8>,
+    /// slots per third of `fingerprints`
+    pub block_length: usize,
+    /// seed the construction settled on, retried until the key set peeled
+    pub seed: u64,
+}
+
+impl XorFilter {
+    /// Filter holding exactly `keys`, duplicates are dropped.
+    pub fn new(keys: &[Digest]) -> Self {
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        keys.dedup();
+        let capacity = (EXTRA_SLOTS + (LOAD_FACTOR * keys.len() as f64).ceil() as usize) / 3 * 3;
+        let mut filter = XorFilter {
+            fingerprints: vec![0; capacity],
+            block_length: capacity / 3,
+            seed: BLOOM_SEED,
+        };
+        loop {
+            if let Some(peeled) = filter.peel(&keys) {
+                // assign in reverse peeling order, each slot is the last one its key still owns
+                for (slot, hash) in peeled.into_iter().rev() {
+                    filter.fingerprints[slot] = 0;
+                    let [a, b, c] = filter.slots(hash);
+                    filter.fingerprints[slot] = fingerprint(hash)
+                        ^ filter.fingerprints[a]
+                        ^ filter.fingerprints[b]
+                        ^ filter.fingerprints[c];
+                }
+                return filter;
+            }
+            filter.seed = filter.seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        }
+    }
+
+    /// false means `hashed` is not in the set, true means it probably is
+    pub fn may_contain(&self, hashed: &Digest) -> bool {
+        let hash = self.hash(hashed);
+        let [a, b, c] = self.slots(hash);
+        fingerprint(hash) == self.fingerprints[a] ^ self.fingerprints[b] ^ self.fingerprints[c]
+    }
+
+    /// Repeatedly removes a key that is alone in one of its slots. Returns that slot and the
+    /// key hash for every key, None when the hashes of `keys` left a cycle under this seed.
+    fn peel(&self, keys: &[Digest]) -> Option<Vec<(usize, u64)>> {
+        // xor of the hashes and number of keys mapped to every slot
+        let mut sets = vec![(0u64, 0u32); self.fingerprints.len()];
+        for key in keys {
+            let hash = self.hash(key);
+            for slot in self.slots(hash) {
+                sets[slot].0 ^= hash;
+                sets[slot].1 += 1;
+            }
+        }
+        let mut alone: Vec<usize> = (0..sets.len()).filter(|i| sets[*i].1 == 1).collect();
+        let mut peeled = Vec::with_capacity(keys.len());
+        while let Some(slot) = alone.pop() {
+            if sets[slot].1 != 1 {
+                continue;
+            }
+            let hash = sets[slot].0;
+            peeled.push((slot, hash));
+            for other in self.slots(hash) {
+                sets[other].0 ^= hash;
+                sets[other].1 -= 1;
+                if sets[other].1 == 1 {
+                    alone.push(other);
+                }
+            }
+        }
+        (peeled.len() == keys.len()).then_some(peeled)
+    }
+
+    fn hash(&self, hashed: &Digest) -> u64 {
+        XxHash64::oneshot(self.seed, hashed.as_bytes())
+    }
+
+    /// One slot per third, each picked by a different 32 bit window of `hash`.
+    fn slots(&self, hash: u64) -> [usize; 3] {
+        let reduce =
+            |window: u64| ((window as u32 as u64 * self.block_length as u64) >> 32) as usize;
+        [
+            reduce(hash),
+            self.block_length + reduce(hash.rotate_left(21)),
+            2 * self.block_length + reduce(hash.rotate_left(42)),
+        ]
+    }
+}
+
+fn fingerprint(hash: u64) -> u8 {
+    (hash ^ (hash >> 32)) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::hasher::{BlockHasher, TruncatedSha256Hasher};
+
+    fn key(i: u32) -> Digest {
+        TruncatedSha256Hasher.digest(&i.to_be_bytes())
+    }
+
+    #[test]
+    fn test_built_from_keys_with_low_false_positive_rate() {
+        let mut keys: Vec<Digest> = (0..1000).map(key).collect();
+        // versions of one key share a digest
+        keys.extend((0..100).map(key));
+        let filter = XorFilter::new(&keys);
+        assert_eq!(filter.fingerprints.len(), 1260);
+        // no false negatives
+        assert!(keys.iter().all(|k| filter.may_contain(k)));
+
+        let probes = 100_000;
+        let false_positives =
+            (1000..1000 + probes).filter(|i| filter.may_contain(&key(*i))).count();
+        let measured = false_positives as f64 / probes as f64;
+        assert!(measured < 0.006, "{} false positives", measured);
+
+        let bytes = bincode::serialize(&filter).unwrap();
+        assert_eq!(bincode::deserialize::<XorFilter>(&bytes).unwrap(), filter);
+        assert!(XorFilter::new(&[]).fingerprints.len() >= 3);
+    }
+}
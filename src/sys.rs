@@ -1,6 +1,6 @@
 use libc::_SC_PAGESIZE;
-use libc::{madvise, MADV_SEQUENTIAL};
-use memmap2::MmapMut;
+use libc::{madvise, MADV_RANDOM, MADV_SEQUENTIAL};
+use memmap2::{Mmap, MmapMut};
 use std::fs::OpenOptions;
 use std::path::Path;
 
@@ -9,15 +9,13 @@ use libc::mlock;
 use libc::munlock;
 use libc::sysconf;
 use memmap2::MmapOptions;
-use memoffset::offset_of;
 use std::io::Result;
 
 /// TODO: This needs to be a proceedural macro to avoid duplication
 #[cfg(any(target_os = "ios", target_os = "macos", target_os = "android", target_os = "linux"))]
 pub fn blocks_ptr(blocks: &SkipList) -> *const u8 {
-    let base_ptr = blocks as *const SkipList as *const u8;
-    let offset = offset_of!(SkipList, blocks); // Offset of the `blocks` field
-    unsafe { base_ptr.add(offset) }
+    // the blocks live on the heap, behind the `blocks` field
+    blocks.blocks.as_ptr() as *const u8
 }
 #[cfg(any(target_os = "ios", target_os = "macos", target_os = "android", target_os = "linux"))]
 pub fn pin_memory(ptr: *const u8, size: usize) -> std::result::Result<(), String> {
@@ -44,14 +42,42 @@ pub fn get_page_size() -> usize {
 }
 
 ///
-/// Creates new segment file and memory maps it
+/// Creates new segment file of `len` bytes and memory maps it
 ///  Memory map a file with 16 KB pages
 /// 16 KB pages are used to optimize for sequential access
-pub fn mmap_opt(file_path: &str) -> Result<MmapMut> {
+pub fn mmap_opt(file_path: &str, len: usize) -> Result<MmapMut> {
     let file = OpenOptions::new().read(true).write(true).create(true).open(Path::new(file_path))?;
-    let page_size = get_page_size();
-    let mut mmap = unsafe { MmapOptions::new().len(page_size).map_mut(&file)? };
-    unsafe { madvise(mmap.as_mut_ptr() as *mut libc::c_void, page_size, MADV_SEQUENTIAL) };
+    // size the file up front, touching pages past the end of file raises SIGBUS
+    file.set_len(len as u64)?;
+    let mut mmap = unsafe { MmapOptions::new().len(len).map_mut(&file)? };
+    unsafe { madvise(mmap.as_mut_ptr() as *mut libc::c_void, len, MADV_SEQUENTIAL) };
     return Ok(mmap);
 }
 
+/// How a mapping is about to be read, see `advise`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessPattern {
+    /// point lookups touching a page here and there, read ahead only wastes memory
+    Random,
+    /// scans and compactions reading front to back, read ahead aggressively
+    Sequential,
+}
+
+/// Memory maps the whole file at `file_path` read only.
+pub fn mmap_read(file_path: &Path) -> Result<Mmap> {
+    let file = OpenOptions::new().read(true).open(file_path)?;
+    unsafe { MmapOptions::new().map(&file) }
+}
+
+/// Tells the kernel how `mmap` is about to be read.
+pub fn advise(mmap: &Mmap, pattern: AccessPattern) -> Result<()> {
+    let advice = match pattern {
+        AccessPattern::Random => MADV_RANDOM,
+        AccessPattern::Sequential => MADV_SEQUENTIAL,
+    };
+    let result = unsafe { madvise(mmap.as_ptr() as *mut libc::c_void, mmap.len(), advice) };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}